@@ -1,55 +1,311 @@
 use dirs::config_dir;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
     path::{Path, PathBuf},
     str::FromStr,
 };
-use toml_edit::{table, Document, Item, Value};
+use toml_edit::{table, Document, Item, Table, Value};
 
-use crate::PipeswitchError;
+use crate::{types::PortKind, PipeswitchError};
 
 const DEFAULT_CONFIG_NAME: &str = "pipeswitch.conf";
 const DEFAULT_CONFIG: &str = include_str!("default.toml");
+const DROPIN_EXTENSION: &str = "conf";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub general: General,
     pub log: Logging,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub osc: Osc,
     #[serde(rename = "link")]
     pub links: HashMap<String, LinkConfig>,
+    /// Named sets of rules, of which only the active one is linked on top of
+    /// the rules outside of profiles
+    #[serde(rename = "profile", default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, Profile>,
+    /// Every config merged in through `include`, directly or not, filled in by
+    /// [`Config::load_from`]
+    #[serde(skip)]
+    pub included: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(rename = "link", default)]
+    pub links: HashMap<String, LinkConfig>,
+}
+
+/// A config named by `include`, which may only contain links and include
+/// more configs in turn
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Included {
+    #[serde(default)]
+    general: IncludedGeneral,
+    #[serde(rename = "link", default)]
+    links: HashMap<String, LinkConfig>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct IncludedGeneral {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+}
+
+/// A config in the drop-in directory, which may only contain links
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct DropIn {
+    #[serde(rename = "link", default)]
+    links: HashMap<String, LinkConfig>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct General {
     /// keep links that dont exist in the config anymore
     pub linger_links: bool,
     /// inotify listen config and reload when it changes
     pub hotreload_config: bool,
+    /// default for matching names case-sensitively, can be overridden per target
+    #[serde(default)]
+    pub case_sensitive: bool,
+    /// recreate links of rules that were removed by something else
+    #[serde(default)]
+    pub auto_reconnect: bool,
+    /// destroy managed links when pipeswitchd exits, unless links linger
+    #[serde(default)]
+    pub unlink_on_exit: bool,
+    /// never destroy links that were not made for the rule destroying them,
+    /// such as links made by hand
+    #[serde(default = "return_true")]
+    pub only_manage_own_links: bool,
+    /// refuse links that would let audio flow back into the node it came from
+    #[serde(default)]
+    pub prevent_cycles: bool,
+    /// refuse to link nodes with a different number of channels, instead of
+    /// only warning about it
+    #[serde(default)]
+    pub strict_channels: bool,
+    /// default for special_empty_ports of links that do not set it
+    #[serde(default = "return_true")]
+    pub default_special_empty_ports: bool,
+    /// default for how names are matched, can be overridden per target
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    /// only the highest priority rule matching a port gets to link it
+    #[serde(default)]
+    pub stop_on_first_match: bool,
+    /// serve a control interface on the D-Bus session bus
+    #[serde(default)]
+    pub dbus_interface: bool,
+    /// listen for JSON commands on this unix socket
+    pub control_socket: Option<PathBuf>,
+    /// name of the PipeWire instance to manage instead of the default one
+    pub remote: Option<String>,
+    /// profile active when pipeswitchd starts
+    pub profile: Option<String>,
+    /// configs whose links are merged in, relative to this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Logging {
     pub level: log::Level,
+    /// how log lines are written to stderr
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human-readable lines
+    #[default]
+    Text,
+    /// a JSON object per line, with fields such as the rule and link id
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Metrics {
+    /// serve prometheus metrics over http
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            enabled: false,
+            port: 9187,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Osc {
+    /// listen for OSC messages over udp
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for Osc {
+    fn default() -> Self {
+        Osc {
+            enabled: false,
+            port: 9000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct LinkConfig {
     #[serde(alias = "in")]
-    pub sink: NodeOrTarget,
+    pub sink: Sinks,
     #[serde(alias = "out")]
     pub source: NodeOrTarget,
-    /// if false, empty port fields on both sides are never treated specially channel-wise
-    #[serde(default = "return_true")]
-    pub special_empty_ports: bool,
+    /// if false, empty port fields on both sides are never treated specially channel-wise.
+    /// Defaults to default_special_empty_ports of the general settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub special_empty_ports: Option<bool>,
+    /// if true, a mono source port is linked to both the left and right sink ports
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub duplicate_mono: bool,
+    /// if true, other links from the source ports are destroyed before linking
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub exclusive: bool,
+    /// if true, monitor ports of sinks are matched like any other port
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub include_monitor: bool,
+    /// if true, the source stream is moved to the sink by setting its
+    /// `target.object`, instead of linking their ports
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub move_stream: bool,
+    /// rules with higher priority get to claim new ports first
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub priority: i32,
+    /// wait until no new ports have matched for this long before linking
+    pub link_delay_ms: Option<u64>,
+    /// which source channels are linked to which sink channels, instead of
+    /// linking only the same channels
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channel_map: Vec<ChannelMapping>,
+    /// extra properties of the links made, passed to PipeWire as they are,
+    /// ie. `{ "link.passive" = true }`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub link_properties: BTreeMap<String, PropertyValue>,
+    /// name given to the links made, where `{1}`, `{2}` and so on are the
+    /// capture groups of the source's node and port patterns
+    pub link_name_template: Option<String>,
+    /// which of the ports matching the other side a new port is linked to
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub pairing: Pairing,
+    /// whether every source is linked to every sink, or each source node only
+    /// to one of the sinks in turn
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub distribute: Distribute,
+}
+
+/// Value of a PipeWire property, which PipeWire itself keeps as a string
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+}
+
+impl fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyValue::Bool(value) => write!(f, "{value}"),
+            PropertyValue::Integer(value) => write!(f, "{value}"),
+            PropertyValue::String(value) => f.write_str(value),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelMapping {
+    /// channel of the source, ie. "FL"
+    pub from: String,
+    /// channel of the sink
+    pub to: String,
 }
 
 const fn return_true() -> bool {
     true
 }
 
+/// Settings left at their defaults are not written out when saving
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// One sink, or a list of sinks that the source is linked to all at once
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Sinks {
+    Many(Vec<NodeOrTarget>),
+    One(NodeOrTarget),
+}
+
+impl Sinks {
+    pub fn targets(&self) -> &[NodeOrTarget] {
+        match self {
+            Sinks::Many(targets) => targets,
+            Sinks::One(target) => std::slice::from_ref(target),
+        }
+    }
+}
+
+impl From<NodeOrTarget> for Sinks {
+    fn from(target: NodeOrTarget) -> Self {
+        Sinks::One(target)
+    }
+}
+
+/// One pattern, or a list of patterns of which any may match
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Patterns {
+    Many(Vec<String>),
+    One(String),
+}
+
+impl Patterns {
+    pub fn patterns(&self) -> &[String] {
+        match self {
+            Patterns::Many(patterns) => patterns,
+            Patterns::One(pattern) => std::slice::from_ref(pattern),
+        }
+    }
+
+    /// The pattern, if there is only one
+    pub fn single(&self) -> Option<&String> {
+        match self.patterns() {
+            [pattern] => Some(pattern),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for Patterns {
+    fn from(pattern: String) -> Self {
+        Patterns::One(pattern)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum NodeOrTarget {
@@ -59,9 +315,87 @@ pub enum NodeOrTarget {
 
 #[derive(Serialize, Default, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Target {
-    pub client: Option<String>,
-    pub node: Option<String>,
-    pub port: Option<String>,
+    /// client, node and port may also be lists, matching if any of them does
+    pub client: Option<Patterns>,
+    pub node: Option<Patterns>,
+    pub port: Option<Patterns>,
+    /// ie. `Audio/Sink` or `Stream/Output/Audio`
+    pub media_class: Option<String>,
+    /// `media.role` of the stream, ie. `Music` or `Communication`
+    pub role: Option<String>,
+    /// `media.name` of the stream, ie. the title of the track or browser tab
+    pub media_name: Option<String>,
+    /// `node.nick`
+    pub nick: Option<String>,
+    /// `node.description`, often more readable than the node name
+    pub description: Option<String>,
+    /// `application.process.binary` of the client
+    pub binary: Option<String>,
+    /// process id of the client
+    pub pid: Option<u32>,
+    /// `object.path` of the port, which stays the same across restarts
+    pub path: Option<String>,
+    /// channel of the port as PipeWire names it, ie. `FL` or `MONO`
+    pub channel: Option<String>,
+    /// what the port carries, ie. `midi`, so that a node with both audio and
+    /// MIDI ports can be targeted for only one of them
+    pub port_kind: Option<PortKind>,
+    /// exclude clients matching this, even if `client` matches
+    pub not_client: Option<String>,
+    /// exclude nodes matching this, even if `node` matches
+    pub not_node: Option<String>,
+    /// exclude ports matching this, even if `port` matches
+    pub not_port: Option<String>,
+    /// which field of the port `port` and `not_port` match, its name by default
+    pub port_field: Option<PortField>,
+    pub case_sensitive: Option<bool>,
+    pub match_mode: Option<MatchMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// names are regular expressions
+    #[default]
+    Regex,
+    /// names are shell globs, where `*` matches anything and `?` any character
+    Glob,
+    /// names are compared as they are, case included, without any special characters
+    Exact,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pairing {
+    /// every matching port on the other side that the port should link to
+    #[default]
+    All,
+    /// only the ports of the node that most recently matched, on both sides,
+    /// so that a new source is not linked to every matching sink at once
+    ByNode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Distribute {
+    /// every source is linked to every sink
+    #[default]
+    All,
+    /// each new source node is linked to the next sink of the list, and keeps
+    /// that sink for as long as it exists
+    RoundRobin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortField {
+    /// `port.name`, ie. `playback_FL`
+    #[default]
+    Name,
+    /// `port.alias`, ie. `Speakers:playback_FL`
+    Alias,
+    /// `object.path`, ie. `alsa:pcm:1:front:1:playback:0`
+    Path,
 }
 
 impl Config {
@@ -73,12 +407,134 @@ impl Config {
         Config::from_string(DEFAULT_CONFIG)
     }
 
+    /// Loads the config along with the links of the configs it includes. The
+    /// returned document is still only the config itself.
     pub fn load_from(path: &Path) -> Result<Option<(Config, Document)>, PipeswitchError> {
         if !path.try_exists()? {
-            Ok(None)
-        } else {
-            Ok(Some(Config::from_string(&fs::read_to_string(path)?)?))
+            return Ok(None);
+        }
+        let (mut config, document) = Config::from_string(&fs::read_to_string(path)?)?;
+        let include = config.general.include.clone();
+        let mut stack = vec![fs::canonicalize(path)?];
+        config.merge_includes(path, &include, &mut stack)?;
+        Ok(Some((config, document)))
+    }
+
+    /// Merges in the links of the given configs, resolved relative to the one
+    /// including them, and of the configs they include in turn. `stack` holds
+    /// the configs being included, to tell when one ends up including itself.
+    /// A config included more than once is only merged the first time.
+    fn merge_includes(
+        &mut self,
+        from: &Path,
+        include: &[PathBuf],
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), PipeswitchError> {
+        let dir = from.parent().unwrap_or_else(|| Path::new(""));
+        for path in include.iter().map(|include| dir.join(include)) {
+            let canonical = fs::canonicalize(&path)
+                .map_err(|e| PipeswitchError::IncludeNotRead(path.clone(), e))?;
+            if stack.contains(&canonical) {
+                return Err(PipeswitchError::CircularInclude(path));
+            }
+            if self.included.contains(&canonical) {
+                continue;
+            }
+            let text = fs::read_to_string(&canonical)
+                .map_err(|e| PipeswitchError::IncludeNotRead(path.clone(), e))?;
+            let included: Included = toml_edit::de::from_str(&text)
+                .map_err(|e| PipeswitchError::IncludedConfig(path.clone(), e))?;
+            for (name, link) in included.links {
+                if self.links.contains_key(&name) {
+                    return Err(PipeswitchError::DuplicateRule(name, path));
+                }
+                self.links.insert(name, link);
+            }
+            self.included.push(canonical.clone());
+            stack.push(canonical);
+            self.merge_includes(&path, &included.general.include, stack)?;
+            stack.pop();
+        }
+        Ok(())
+    }
+
+    /// Directory next to the config whose `*.conf` files add more links,
+    /// ie. `pipeswitch.conf.d`
+    pub fn dropin_dir(path: &Path) -> PathBuf {
+        let mut dir = path.as_os_str().to_owned();
+        dir.push(".d");
+        PathBuf::from(dir)
+    }
+
+    /// Like [`Config::load_from`], but also merges in the links of every
+    /// drop-in config. The returned document is still only the main config.
+    pub fn load_with_dropins(path: &Path) -> Result<Option<(Config, Document)>, PipeswitchError> {
+        let (mut config, document) = match Config::load_from(path)? {
+            Some(loaded) => loaded,
+            None => return Ok(None),
+        };
+        let dir = Config::dropin_dir(path);
+        if !dir.try_exists()? {
+            return Ok(Some((config, document)));
+        }
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == DROPIN_EXTENSION)
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            let dropin: DropIn = match toml_edit::de::from_str(&fs::read_to_string(&path)?) {
+                Ok(dropin) => dropin,
+                Err(e) => return Err(PipeswitchError::DropInConfig(path, e)),
+            };
+            for (name, link) in dropin.links {
+                if config.links.contains_key(&name) {
+                    return Err(PipeswitchError::DuplicateRule(name, path));
+                }
+                config.links.insert(name, link);
+            }
+        }
+        Ok(Some((config, document)))
+    }
+
+    /// Merges the links of the profile in with the links outside of profiles,
+    /// which its rules may not share a name with
+    pub fn activate_profile(&mut self, name: &str) -> Result<(), PipeswitchError> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| PipeswitchError::NoSuchProfile(name.to_owned()))?;
+        if let Some(rule_name) = profile.links.keys().find(|n| self.links.contains_key(*n)) {
+            return Err(PipeswitchError::ProfileRuleExists(
+                rule_name.clone(),
+                name.to_owned(),
+            ));
+        }
+        self.links.extend(profile.links.clone());
+        Ok(())
+    }
+
+    /// Adds a rule, which is written after the rules that were already in the
+    /// document, in the order of their names
+    pub fn add_rule(&mut self, name: &str, link: LinkConfig) -> Result<(), PipeswitchError> {
+        if self.links.contains_key(name) {
+            return Err(PipeswitchError::RuleExists(name.to_owned()));
         }
+        self.links.insert(name.to_owned(), link);
+        Ok(())
+    }
+
+    /// Removes a rule, whose comments are left out the next time it is written
+    pub fn remove_rule(&mut self, name: &str) -> Result<LinkConfig, PipeswitchError> {
+        self.links
+            .remove(name)
+            .ok_or_else(|| PipeswitchError::NoSuchRule(name.to_owned()))
     }
 
     pub fn write_to(&self, path: &Path, doc: Option<&Document>) -> Result<(), PipeswitchError> {
@@ -87,6 +543,15 @@ impl Config {
     }
 
     pub fn to_string(&self, old_document: Option<&Document>) -> Result<String, PipeswitchError> {
+        Ok(self.to_document(old_document)?.to_string())
+    }
+
+    /// The config as a document, with the comments, formatting and rule order
+    /// of the old document where it has the same keys
+    pub fn to_document(
+        &self,
+        old_document: Option<&Document>,
+    ) -> Result<Document, PipeswitchError> {
         let mut document = toml_edit::ser::to_document(&self)?;
         // General
         let general_item = Item::Table(
@@ -106,31 +571,129 @@ impl Config {
                     "can't convert 'log' into a table",
                 ))?,
         );
+        // Metrics
+        let metrics_item = Item::Table(
+            document
+                .remove("metrics")
+                .and_then(|v| v.into_table().ok())
+                .ok_or(PipeswitchError::ConfigMalformed(
+                    "can't convert 'metrics' into a table",
+                ))?,
+        );
+        // Osc
+        let osc_item = Item::Table(
+            document
+                .remove("osc")
+                .and_then(|v| v.into_table().ok())
+                .ok_or(PipeswitchError::ConfigMalformed(
+                    "can't convert 'osc' into a table",
+                ))?,
+        );
         // Link
+        let old_links = old_document.and_then(|doc| doc.get("link"));
         let mut link_item = table();
         let tableref = link_item.as_table_mut().unwrap();
         tableref.set_implicit(true);
-        for (internal_string, val) in document
+        let mut links: Vec<_> = document
             .remove("link")
             .and_then(|v| v.into_table().ok())
             .ok_or(PipeswitchError::ConfigMalformed(
                 "can't convert 'link' into a table",
             ))?
-        {
-            let table_item = Item::Table(val.into_table().map_err(|_| {
+            .into_iter()
+            .collect();
+        // Rules already in the old document keep their place, new ones follow
+        let old_position = |name: &str| {
+            old_links
+                .and_then(Item::as_table_like)
+                .and_then(|links| links.iter().position(|(key, _)| key == name))
+                .unwrap_or(usize::MAX)
+        };
+        links.sort_by_cached_key(|(name, _)| (old_position(name), name.clone()));
+        for (internal_string, val) in links {
+            let mut link_table = val.into_table().map_err(|_| {
                 PipeswitchError::ConfigMalformed("can't convert link item into a table")
-            })?);
+            })?;
+            let old_link = old_links.and_then(|links| links.get(internal_string.as_str()));
+            // The settings of the rule keep the order they were written in
+            if let Some(old_link) = old_link.and_then(Item::as_table_like) {
+                let old_position = |key: &str| {
+                    old_link
+                        .iter()
+                        .position(|(old_key, _)| old_key == key)
+                        .unwrap_or(usize::MAX)
+                };
+                let mut settings: Vec<_> = link_table.into_iter().collect();
+                settings.sort_by_key(|(key, _)| old_position(key));
+                link_table = Table::new();
+                for (key, setting) in settings {
+                    link_table.insert(&key, setting);
+                }
+            }
+            // Links written as `name = { ... }` stay that way
+            let inline = old_link.map_or(false, Item::is_inline_table);
+            let table_item = if inline {
+                Item::Value(Value::InlineTable(link_table.into_inline_table()))
+            } else {
+                Item::Table(link_table)
+            };
             tableref.insert(&internal_string, table_item);
         }
+        // Profile, sorted by name, with their rules written as tables too
+        let mut profile_item = table();
+        let profiles_ref = profile_item.as_table_mut().unwrap();
+        profiles_ref.set_implicit(true);
+        if let Some(profiles) = document.remove("profile") {
+            let mut profiles: Vec<_> = profiles
+                .into_table()
+                .map_err(|_| {
+                    PipeswitchError::ConfigMalformed("can't convert 'profile' into a table")
+                })?
+                .into_iter()
+                .collect();
+            profiles.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (profile_name, profile) in profiles {
+                let links = profile
+                    .into_table()
+                    .ok()
+                    .and_then(|mut profile| profile.remove("link"))
+                    .and_then(|links| links.into_table().ok())
+                    .ok_or(PipeswitchError::ConfigMalformed(
+                        "can't convert profile links into a table",
+                    ))?;
+                let mut links: Vec<_> = links.into_iter().collect();
+                links.sort_by(|(a, _), (b, _)| a.cmp(b));
+                let mut links_item = table();
+                let links_ref = links_item.as_table_mut().unwrap();
+                links_ref.set_implicit(true);
+                for (rule_name, link) in links {
+                    let link_table = link.into_table().map_err(|_| {
+                        PipeswitchError::ConfigMalformed("can't convert link item into a table")
+                    })?;
+                    links_ref.insert(&rule_name, Item::Table(link_table));
+                }
+                let mut profile_table = table();
+                let profile_ref = profile_table.as_table_mut().unwrap();
+                profile_ref.set_implicit(true);
+                profile_ref.insert("link", links_item);
+                profiles_ref.insert(&profile_name, profile_table);
+            }
+        }
+        let has_profiles = !profiles_ref.is_empty();
         // Insert them all
         document.insert("general", general_item);
         document.insert("log", log_item);
+        document.insert("metrics", metrics_item);
+        document.insert("osc", osc_item);
         document.insert("link", link_item);
+        if has_profiles {
+            document.insert("profile", profile_item);
+        }
         // Clone decor and return
         if let Some(old_document) = old_document {
             clone_decor(&mut document, old_document);
         }
-        Ok(document.to_string())
+        Ok(document)
     }
 
     pub fn from_string(input: &str) -> Result<(Self, Document), PipeswitchError> {
@@ -139,6 +702,56 @@ impl Config {
     }
 }
 
+/// Puts together a config with the settings and comments of the default one,
+/// but only the rules given to it
+#[derive(Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+    /// The default config, for its comments
+    document: Document,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Result<Self, PipeswitchError> {
+        let (mut config, document) = Config::default_conf()?;
+        config.links.clear();
+        Ok(ConfigBuilder { config, document })
+    }
+
+    pub fn general(mut self, general: General) -> Self {
+        self.config.general = general;
+        self
+    }
+
+    pub fn log(mut self, log: Logging) -> Self {
+        self.config.log = log;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    pub fn osc(mut self, osc: Osc) -> Self {
+        self.config.osc = osc;
+        self
+    }
+
+    /// Adds a rule, replacing any earlier rule of the same name
+    pub fn rule(mut self, name: &str, link: LinkConfig) -> Self {
+        self.config.links.insert(name.to_owned(), link);
+        self
+    }
+
+    /// The config along with a document of it, which [`Config::to_string`]
+    /// and [`Config::write_to`] keep the comments of
+    pub fn build(self) -> Result<(Config, Document), PipeswitchError> {
+        let document = self.config.to_document(Some(&self.document))?;
+        Ok((self.config, document))
+    }
+}
+
 pub fn clone_decor(to: &mut Document, from: &Document) {
     for (key, item) in to.iter_mut() {
         clone_item_decor(item, from.get(&key))
@@ -171,17 +784,23 @@ pub fn clone_item_decor(to: &mut Item, from: Option<&Item>) {
                     }
                 }
             }
-            // (Value(toml_edit::Value::InlineTable(to_table)), Table(from_table)) => {
-            //     for (to, from) in to_tables.iter_mut().zip(from_tables) {
-            //         *to.decor_mut() = from.decor().clone();
-            //         for (mut key, to_item) in to.iter_mut() {
-            //             if let Some((from_key, from_item)) = from.get_key_value(&key) {
-            //                 *key.decor_mut() = from_key.decor().clone();
-            //                 clone_decor(to_item, Some(from_item));
-            //             }
-            //         }
-            //     }
-            // }
+            // A table written inline where a table was before, or the other way
+            // around. Only the values carry over, the decor of the tables
+            // themselves does not fit the other form.
+            (Value(toml_edit::Value::InlineTable(to_table)), Table(from_table)) => {
+                for (key, to_value) in to_table.iter_mut() {
+                    if let Some(Value(from_value)) = from_table.get(&key) {
+                        clone_value_decor(to_value, from_value);
+                    }
+                }
+            }
+            (Table(to_table), Value(toml_edit::Value::InlineTable(from_table))) => {
+                for (key, to_item) in to_table.iter_mut() {
+                    if let (Value(to_value), Some(from_value)) = (to_item, from_table.get(&key)) {
+                        clone_value_decor(to_value, from_value);
+                    }
+                }
+            }
             (_, _) => {}
         }
     }
@@ -222,3 +841,51 @@ pub fn clone_value_decor(to: &mut Value, from: &Value) {
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_rules_survive_a_round_trip() {
+        let (settings, _) = DEFAULT_CONFIG.split_once("# Default link").unwrap();
+        let links = r#"[link]
+# the mic into the speakers
+monitor = { source = "mic", sink = { node = "speakers" }, exclusive = true }  # while recording
+
+[link.table]
+source = "a"
+# only the left channel
+sink = { node = "b", channel = "FL" }
+"#;
+        let (config, document) = Config::from_string(&format!("{settings}{links}")).unwrap();
+        let saved = config.to_string(Some(&document)).unwrap();
+        assert!(saved.ends_with(links), "{saved}");
+        assert_eq!(Config::from_string(&saved).unwrap().0.links, config.links);
+    }
+
+    #[test]
+    fn profile_rules_may_not_shadow_others() {
+        let (settings, _) = DEFAULT_CONFIG.split_once("# Default link").unwrap();
+        let links = r#"[link.music]
+source = "player"
+sink = "speakers"
+
+[profile.quiet.link.quiet]
+source = "player"
+sink = "headphones"
+
+[profile.loud.link.music]
+source = "player"
+sink = "amp"
+"#;
+        let (config, _) = Config::from_string(&format!("{settings}{links}")).unwrap();
+        let mut quiet = config.clone();
+        quiet.activate_profile("quiet").unwrap();
+        assert!(quiet.links.contains_key("music") && quiet.links.contains_key("quiet"));
+        let loud = config.clone().activate_profile("loud");
+        assert!(matches!(loud, Err(PipeswitchError::ProfileRuleExists(..))));
+        let missing = config.clone().activate_profile("none");
+        assert!(matches!(missing, Err(PipeswitchError::NoSuchProfile(..))));
+    }
+}
@@ -1,18 +1,24 @@
 pub use log;
 use pipewire::channel::Sender as PipewireSender;
 pub use pipewire::types::ObjectType;
+#[cfg(any(test, feature = "test-util"))]
+pub use pw::StateBuilder;
 use pw::{
-    mainloop::{mainloop, MainloopAction, MainloopEvents},
-    types::{Link, Object, Port},
+    mainloop::{mainloop, MainloopAction, MainloopEvents, RequestId},
+    types::{Client, Link, Node, Object, Port},
+    StateChanged,
 };
-pub use pw::{types, PipewireError, PipewireState};
+pub use pw::{types, PipewireError, PipewireState, StateSnapshot};
 use std::{
-    marker::PhantomData,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
     sync::{
+        atomic::{AtomicU64, Ordering},
         mpsc::{self},
-        Arc, Mutex, MutexGuard,
+        Arc, Mutex, RwLock, RwLockReadGuard,
     },
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 pub use toml_edit;
@@ -20,6 +26,17 @@ pub use toml_edit;
 pub mod config;
 mod pw;
 
+/// How long [`Pipeswitch::create_link`] and [`Pipeswitch::create_null_sink`]
+/// wait for PipeWire before giving up
+pub const DEFAULT_LINK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Type name of the factory that creates links
+pub const LINK_FACTORY: &str = "PipeWire:Interface:Link";
+
+/// How long a waiter holds on to the mainloop answers at a time, before letting
+/// other waiters check whether their answer has arrived
+const WAIT_SLICE: Duration = Duration::from_millis(10);
+
 #[derive(Error, Debug)]
 pub enum PipeswitchError {
     #[error("error reading or writing to disk: {0}")]
@@ -40,6 +57,28 @@ pub enum PipeswitchError {
     DoubleInputPort(Box<Port>, Box<Port>),
     #[error("given ports are both output: {0:?}, {1:?}")]
     DoubleOutputPort(Box<Port>, Box<Port>),
+    #[error("timed out waiting for PipeWire to create link")]
+    LinkTimeout,
+    #[error("PipeWire did not create node '{0}'")]
+    NodeNotCreated(String),
+    #[error("error parsing drop-in config {}: {1}", .0.display())]
+    DropInConfig(PathBuf, toml_edit::de::Error),
+    #[error("rule [{0}] is defined again in {}", .1.display())]
+    DuplicateRule(String, PathBuf),
+    #[error("error reading included config {}: {1}", .0.display())]
+    IncludeNotRead(PathBuf, std::io::Error),
+    #[error("error parsing included config {}: {1}", .0.display())]
+    IncludedConfig(PathBuf, toml_edit::de::Error),
+    #[error("included config {} is part of an include cycle", .0.display())]
+    CircularInclude(PathBuf),
+    #[error("rule [{0}] already exists")]
+    RuleExists(String),
+    #[error("no rule named [{0}]")]
+    NoSuchRule(String),
+    #[error("no profile named [{0}]")]
+    NoSuchProfile(String),
+    #[error("rule [{0}] of profile [{1}] is also defined outside of it")]
+    ProfileRuleExists(String, String),
     #[error("unable to re-form config: {0}")]
     ConfigMalformed(&'static str),
     #[cfg(debug_assertions)]
@@ -51,120 +90,613 @@ pub enum PipeswitchError {
 pub enum PipeswitchMessage {
     NewObject(Object),
     ObjectRemoved(Object),
+    /// The properties of an existing node changed in place, ie. when a device
+    /// is reconfigured. Holds the object as it was and the updated object.
+    ObjectChanged(Object, Object),
+    /// The default sink or source was changed, see [`PipewireState::default_node_name`]
+    DefaultChanged(types::DefaultNode),
+    /// How many links [`Pipeswitch::create_links`] created, and how long it
+    /// took from asking for them until PipeWire had made them, including the
+    /// wait for the link factory
+    LinksCreated(usize, Duration),
+    /// PipeWire went away, ie. it was restarted. The state has been cleared and
+    /// the connection is retried until [`PipeswitchMessage::Reconnected`].
+    Disconnected,
+    /// Connected to PipeWire again, with the existing objects already sent as
+    /// new ones
+    Reconnected,
     Error(pw::PipewireError),
 }
 
+/// A link to create with [`Pipeswitch::create_links`], between an output and
+/// an input port
+#[derive(Debug, Clone)]
+pub struct NewLink {
+    output: Port,
+    input: Port,
+    rule_name: String,
+    properties: HashMap<String, String>,
+}
+
+impl NewLink {
+    /// The ports may be given in either order, but one of them has to be an
+    /// output and the other an input
+    pub fn new(port1: Port, port2: Port, rule_name: String) -> Result<Self, PipeswitchError> {
+        use types::Direction::*;
+        match (&port1.direction, &port2.direction) {
+            (Input, Input) => Err(PipeswitchError::DoubleInputPort(
+                Box::new(port1),
+                Box::new(port2),
+            )),
+            (Output, Output) => Err(PipeswitchError::DoubleOutputPort(
+                Box::new(port1),
+                Box::new(port2),
+            )),
+            (Input, Output) => Ok(NewLink {
+                output: port2,
+                input: port1,
+                rule_name,
+                properties: HashMap::new(),
+            }),
+            (Output, Input) => Ok(NewLink {
+                output: port1,
+                input: port2,
+                rule_name,
+                properties: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Extra properties of the link, ie. `link.passive`. The ports,
+    /// `object.linger` and the rule name are always set by Pipeswitch itself.
+    pub fn with_properties(mut self, properties: HashMap<String, String>) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    pub fn output(&self) -> &Port {
+        &self.output
+    }
+
+    pub fn input(&self) -> &Port {
+        &self.input
+    }
+
+    /// Every property the link is created with. The ports, `object.linger`
+    /// and the rule name are what make the link, the extra properties never
+    /// replace them.
+    pub(crate) fn into_properties(self) -> Vec<(String, String)> {
+        use pipewire::keys::{
+            LINK_INPUT_NODE, LINK_INPUT_PORT, LINK_OUTPUT_NODE, LINK_OUTPUT_PORT,
+        };
+        let mut properties: Vec<(String, String)> = [
+            (*LINK_OUTPUT_NODE, self.output.node_id.to_string()),
+            (*LINK_OUTPUT_PORT, self.output.id.to_string()),
+            (*LINK_INPUT_NODE, self.input.node_id.to_string()),
+            (*LINK_INPUT_PORT, self.input.id.to_string()),
+            ("object.linger", "1".to_owned()),
+            (types::KEY_RULE_NAME, self.rule_name),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect();
+        for (key, value) in self.properties {
+            if !properties.iter().any(|(set, _)| *set == key) {
+                properties.push((key, value));
+            }
+        }
+        properties
+    }
+}
+
 pub struct Pipeswitch {
-    pipewire_state: Arc<Mutex<PipewireState>>,
-    sender: PipewireSender<MainloopAction>,
-    mainloop_receiver: mpsc::Receiver<MainloopEvents>,
+    pipewire_state: Arc<RwLock<PipewireState>>,
+    /// Signalled by the mainloop whenever it has updated `pipewire_state`
+    state_changed: Arc<StateChanged>,
+    sender: Mutex<PipewireSender<(RequestId, MainloopAction)>>,
+    mainloop_receiver: Mutex<mpsc::Receiver<(RequestId, MainloopEvents)>>,
+    next_request: AtomicU64,
+    /// Answers received by a waiter that were meant for another one
+    unclaimed_events: Mutex<HashMap<RequestId, MainloopEvents>>,
+    /// Requests nobody waits for anymore, whose answers can be thrown away
+    abandoned_requests: Mutex<HashSet<RequestId>>,
+    /// Messages kept for [`Pipeswitch::try_recv`], if created with
+    /// [`Pipeswitch::new_polled`]
+    message_receiver: Option<Mutex<mpsc::Receiver<PipeswitchMessage>>>,
     join_handle: Option<JoinHandle<()>>,
-    nosync_phantom_data: PhantomData<std::cell::Cell<()>>,
 }
 
 impl Pipeswitch {
-    pub fn new(sender: Option<mpsc::Sender<PipeswitchMessage>>) -> Result<Self, PipeswitchError> {
-        let pipewire_state = Arc::new(Mutex::new(PipewireState::default()));
+    /// Connects to the PipeWire instance of the given remote name, ie. the
+    /// name of its socket, or to the default one like `PIPEWIRE_REMOTE` decides
+    pub fn new(
+        sender: Option<mpsc::Sender<PipeswitchMessage>>,
+        remote: Option<&str>,
+    ) -> Result<Self, PipeswitchError> {
+        Pipeswitch::new_filtered(sender, None, remote)
+    }
+
+    /// Like [`Pipeswitch::new`], but keeps the messages to itself, to be taken
+    /// with [`Pipeswitch::try_recv`] or [`Pipeswitch::recv_timeout`] from an
+    /// event loop of the caller's
+    pub fn new_polled(remote: Option<&str>) -> Result<Self, PipeswitchError> {
+        let (sender, receiver) = mpsc::channel();
+        let mut pipeswitch = Pipeswitch::new_filtered(Some(sender), None, remote)?;
+        pipeswitch.message_receiver = Some(Mutex::new(receiver));
+        Ok(pipeswitch)
+    }
+
+    /// Like [`Pipeswitch::new`], but only sends added and removed objects of
+    /// the given types to `sender`. Errors are always sent.
+    pub fn new_filtered(
+        sender: Option<mpsc::Sender<PipeswitchMessage>>,
+        filter: Option<&[ObjectType]>,
+        remote: Option<&str>,
+    ) -> Result<Self, PipeswitchError> {
+        let filter = filter.map(|types| types.to_vec());
+        let remote = remote.map(str::to_owned);
+        let pipewire_state = Arc::new(RwLock::new(PipewireState::default()));
 
         let (ps_sender, ps_receiver) = mpsc::channel();
-        let (pw_sender, pw_receiver) = pipewire::channel::channel::<MainloopAction>();
+        let (pw_sender, pw_receiver) = pipewire::channel::channel();
+
+        let state_changed = Arc::new(StateChanged::default());
 
         let state_clone = pipewire_state.clone();
+        let changed_clone = state_changed.clone();
 
         let join_handle = std::thread::spawn(move || {
-            mainloop(sender, ps_sender, pw_receiver, state_clone)
-                .map_err(|_| {
-                    PipeswitchError::CriticalThreadFailure("Background thread died unexpectedly")
-                })
-                .unwrap();
+            mainloop(
+                sender,
+                filter,
+                remote,
+                ps_sender,
+                pw_receiver,
+                state_clone,
+                changed_clone,
+            )
+            .map_err(|_| {
+                PipeswitchError::CriticalThreadFailure("Background thread died unexpectedly")
+            })
+            .unwrap();
         });
 
         Ok(Pipeswitch {
             pipewire_state,
-            sender: pw_sender,
+            state_changed,
+            sender: Mutex::new(pw_sender),
             join_handle: Some(join_handle),
-            mainloop_receiver: ps_receiver,
-            nosync_phantom_data: PhantomData::default(),
+            mainloop_receiver: Mutex::new(ps_receiver),
+            next_request: AtomicU64::new(0),
+            unclaimed_events: Mutex::default(),
+            abandoned_requests: Mutex::default(),
+            message_receiver: None,
         })
     }
 
-    pub fn lock_current_state(&self) -> MutexGuard<PipewireState> {
-        self.pipewire_state.lock().unwrap()
+    /// A Pipeswitch over a graph built by hand, ie. with [`StateBuilder`],
+    /// without connecting to PipeWire. The state can be read as usual, while
+    /// anything that has to ask PipeWire fails right away.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn offline(state: PipewireState) -> Self {
+        let (pw_sender, _) = pipewire::channel::channel();
+        let (_, ps_receiver) = mpsc::channel();
+        Pipeswitch {
+            pipewire_state: Arc::new(RwLock::new(state)),
+            state_changed: Arc::default(),
+            sender: Mutex::new(pw_sender),
+            join_handle: None,
+            mainloop_receiver: Mutex::new(ps_receiver),
+            next_request: AtomicU64::new(0),
+            unclaimed_events: Mutex::default(),
+            abandoned_requests: Mutex::default(),
+            message_receiver: None,
+        }
     }
 
+    /// Swaps the graph of an offline Pipeswitch, as if PipeWire had changed
+    /// it. Nothing is told about the objects that came or went.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn replace_state(&self, state: PipewireState) {
+        *self.pipewire_state.write().unwrap() = state;
+    }
+
+    /// The next message if one has arrived, without waiting. Always `None`
+    /// unless created with [`Pipeswitch::new_polled`].
+    pub fn try_recv(&self) -> Option<PipeswitchMessage> {
+        self.message_receiver
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .try_recv()
+            .ok()
+    }
+
+    /// The next message, waiting up to `timeout` for one to arrive. Returns
+    /// `None` right away unless created with [`Pipeswitch::new_polled`].
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<PipeswitchMessage> {
+        self.message_receiver
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .recv_timeout(timeout)
+            .ok()
+    }
+
+    /// Sends the action to the mainloop, returning the id its answer will have
+    fn request(
+        &self,
+        action: MainloopAction,
+        error: &'static str,
+    ) -> Result<RequestId, PipeswitchError> {
+        let request = self.next_request.fetch_add(1, Ordering::Relaxed);
+        self.sender
+            .lock()
+            .unwrap()
+            .send((request, action))
+            .map_err(|_| PipeswitchError::CriticalThreadFailure(error))?;
+        Ok(request)
+    }
+
+    /// Waits for the answer to the given request, leaving answers to other
+    /// requests for their own waiters
+    fn wait_for(
+        &self,
+        request: RequestId,
+        deadline: Option<Instant>,
+    ) -> Result<MainloopEvents, mpsc::RecvTimeoutError> {
+        loop {
+            let receiver = self.mainloop_receiver.lock().unwrap();
+            // Checked only while holding the receiver, so that nothing can be
+            // stashed between the check and receiving
+            if let Some(event) = self.unclaimed_events.lock().unwrap().remove(&request) {
+                return Ok(event);
+            }
+            let slice = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        self.abandoned_requests.lock().unwrap().insert(request);
+                        return Err(mpsc::RecvTimeoutError::Timeout);
+                    }
+                    remaining.min(WAIT_SLICE)
+                }
+                None => WAIT_SLICE,
+            };
+            match receiver.recv_timeout(slice) {
+                Ok((id, event)) if id == request => return Ok(event),
+                Ok((id, event)) => {
+                    if !self.abandoned_requests.lock().unwrap().remove(&id) {
+                        self.unclaimed_events.lock().unwrap().insert(id, event);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read access to the current state. Any number of readers may hold it at
+    /// once, but the mainloop can not update the state meanwhile, so it should
+    /// not be held for long, nor while waiting on PipeWire.
+    pub fn lock_current_state(&self) -> RwLockReadGuard<PipewireState> {
+        self.pipewire_state.read().unwrap()
+    }
+
+    /// Snapshot of all nodes currently known
+    pub fn nodes(&self) -> Vec<Node> {
+        self.lock_current_state().nodes.values().cloned().collect()
+    }
+
+    /// Snapshot of all clients currently known
+    pub fn clients(&self) -> Vec<Client> {
+        self.lock_current_state()
+            .clients
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Snapshot of all ports currently known
+    pub fn ports(&self) -> Vec<Port> {
+        self.lock_current_state().ports.values().cloned().collect()
+    }
+
+    /// Snapshot of all links currently known
+    pub fn links(&self) -> Vec<Link> {
+        self.lock_current_state().links.values().cloned().collect()
+    }
+
+    /// The first node with the given `node.name`
+    pub fn node_by_name(&self, name: &str) -> Option<Node> {
+        self.lock_current_state()
+            .nodes
+            .values()
+            .find(|node| node.node_name == name)
+            .cloned()
+    }
+
+    /// Snapshot of every link from or to the port
+    pub fn links_for_port(&self, port_id: u32) -> Vec<Link> {
+        self.lock_current_state()
+            .links_for_port(port_id)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Ids of the ports currently linked to the port
+    pub fn connected_ports(&self, port_id: u32) -> Vec<u32> {
+        self.lock_current_state().connected_ports(port_id)
+    }
+
+    /// All links created by Pipeswitch, ie. that have a rule name
+    pub fn managed_links(&self) -> Vec<Link> {
+        self.lock_current_state()
+            .links
+            .values()
+            .filter(|link| link.rule_name.is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// All links created by Pipeswitch for the given rule
+    pub fn links_for_rule(&self, name: &str) -> Vec<Link> {
+        self.lock_current_state()
+            .links
+            .values()
+            .filter(|link| link.rule_name.as_deref() == Some(name))
+            .cloned()
+            .collect()
+    }
+
+    /// Links the two ports, or returns the link between them if there
+    /// already is one
     pub fn create_link(
         &self,
         port1: Port,
         port2: Port,
         rule_name: String,
     ) -> Result<Option<Link>, PipeswitchError> {
-        use types::Direction::*;
-        // Check for double inputs and double outputs
-        match (&port1.direction, &port2.direction) {
-            (Input, Input) => {
-                return Err(PipeswitchError::DoubleInputPort(
-                    Box::new(port1),
-                    Box::new(port2),
-                ))
+        self.create_link_timeout(port1, port2, rule_name, DEFAULT_LINK_TIMEOUT)
+    }
+
+    /// Like [`Pipeswitch::create_link`], but gives up after `timeout`, which
+    /// includes waiting for the link factory to be discovered
+    pub fn create_link_timeout(
+        &self,
+        port1: Port,
+        port2: Port,
+        rule_name: String,
+        timeout: Duration,
+    ) -> Result<Option<Link>, PipeswitchError> {
+        self.create_link_with_properties(port1, port2, rule_name, HashMap::new(), timeout)
+    }
+
+    /// Like [`Pipeswitch::create_link_timeout`], but also gives the link the
+    /// given properties, ie. `link.passive`. The ports, `object.linger` and the
+    /// rule name are always set by Pipeswitch itself.
+    pub fn create_link_with_properties(
+        &self,
+        port1: Port,
+        port2: Port,
+        rule_name: String,
+        properties: HashMap<String, String>,
+        timeout: Duration,
+    ) -> Result<Option<Link>, PipeswitchError> {
+        let link = NewLink::new(port1, port2, rule_name)?.with_properties(properties);
+        Ok(self.create_links(vec![link], timeout)?.pop().flatten())
+    }
+
+    /// Creates all of the links at once, waiting for PipeWire only once
+    /// instead of for each link. Gives the links in the same order, None for
+    /// those that could not be created. Like [`Pipeswitch::create_link`],
+    /// existing links between the same ports are returned instead.
+    pub fn create_links(
+        &self,
+        links: Vec<NewLink>,
+        timeout: Duration,
+    ) -> Result<Vec<Option<Link>>, PipeswitchError> {
+        let requested = Instant::now();
+        let deadline = requested + timeout;
+        let mut results = vec![None; links.len()];
+        // Indices of the links that are created, and what they are created from
+        let mut created = Vec::new();
+        let mut to_create = Vec::new();
+        let lock = self.pipewire_state.read().unwrap();
+        for (index, link) in links.into_iter().enumerate() {
+            // Linking the same ports twice only makes a duplicate link
+            match lock.links.values().find(|existing| {
+                existing.output_port == link.output.id && existing.input_port == link.input.id
+            }) {
+                Some(existing) => results[index] = Some(existing.clone()),
+                None => {
+                    created.push(index);
+                    to_create.push(link);
+                }
+            }
+        }
+        drop(lock);
+        if to_create.is_empty() {
+            return Ok(results);
+        }
+        let factory_name = self.link_factory_name(deadline)?;
+
+        let request = self.request(
+            MainloopAction::CreateLinks(factory_name, to_create, requested),
+            "Failed to send create links",
+        )?;
+
+        match self.wait_for(request, Some(deadline)) {
+            Ok(MainloopEvents::LinksCreated(links)) => {
+                let lock = self.pipewire_state.read().unwrap();
+                for (index, mut link) in created.into_iter().zip(links) {
+                    if let Some(link) = &mut link {
+                        lock.fill_port_aliases(link);
+                    }
+                    results[index] = link;
+                }
+                Ok(results)
+            }
+            Ok(_) => Ok(results),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let _ = self.request(MainloopAction::AbortCreate(request), "");
+                Err(PipeswitchError::LinkTimeout)
             }
-            (Output, Output) => {
-                return Err(PipeswitchError::DoubleOutputPort(
-                    Box::new(port1),
-                    Box::new(port2),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(PipeswitchError::CriticalThreadFailure(
+                    "Background thread stopped while creating links",
                 ))
             }
-            _ => {}
-        };
-        // Flip ports if necessary
-        let (input, output) = if let Input = &port1.direction {
-            (port1, port2)
-        } else {
-            (port2, port1)
-        };
+        }
+    }
 
-        let lock = self.pipewire_state.lock().unwrap();
-        let factory_name = lock
-            .factories
-            .get("PipeWire:Interface:Link")
-            .ok_or(PipeswitchError::NoLinkFactory)?
-            .name
-            .clone();
-        drop(lock);
+    /// Name of the link factory. Right after startup it may not have been
+    /// registered yet, so this waits for it until the deadline.
+    fn link_factory_name(&self, deadline: Instant) -> Result<String, PipeswitchError> {
+        let mut deferred = false;
+        self.state_changed
+            .wait_until(&self.pipewire_state, deadline, |state| {
+                let factory = state.factories.get(LINK_FACTORY);
+                let name = factory.map(|factory| factory.name.clone());
+                if name.is_none() && !deferred {
+                    log::debug!("link deferred until the link factory has been discovered");
+                    deferred = true;
+                }
+                name
+            })
+            .ok_or(PipeswitchError::NoLinkFactory)
+    }
 
-        self.sender
-            .send(MainloopAction::CreateLink(
-                factory_name,
-                output,
-                input,
-                rule_name,
-            ))
-            .map_err(|_| PipeswitchError::CriticalThreadFailure("Failed to send create link"))
-            .unwrap();
+    /// Whether links can be created right away, without waiting for the link
+    /// factory to be discovered first
+    pub fn link_factory_known(&self) -> bool {
+        let state = self.lock_current_state();
+        state.factories.contains_key(LINK_FACTORY)
+    }
 
-        Ok(loop {
-            if let Ok(MainloopEvents::LinkCreated(link)) = self.mainloop_receiver.recv() {
-                break link;
+    pub fn destroy_link(&self, link: Link) -> Result<bool, PipeswitchError> {
+        let request = self.request(
+            MainloopAction::DestroyLink(link),
+            "Failed to send destroy link",
+        )?;
+
+        match self.wait_for(request, None) {
+            Ok(MainloopEvents::LinkDestroyed(success)) => Ok(success),
+            Ok(_) => Ok(false),
+            Err(_) => Err(PipeswitchError::CriticalThreadFailure(
+                "Background thread stopped while destroying link",
+            )),
+        }
+    }
+
+    /// Like [`Pipeswitch::destroy_link`], for a link known by its id only.
+    /// Links that are not in the current state are not destroyed.
+    pub fn destroy_link_by_id(&self, id: u32) -> Result<bool, PipeswitchError> {
+        // The state is not kept locked while the link is destroyed, since the
+        // mainloop needs it to remove the link
+        let link = self.lock_current_state().links.get(&id).cloned();
+        match link {
+            Some(link) => self.destroy_link(link),
+            None => Ok(false),
+        }
+    }
+
+    /// Asks the session manager to move the stream node to the node with the
+    /// given name, by setting its `target.object` in the default metadata.
+    /// Without a name, the session manager picks the target again.
+    pub fn set_stream_target(
+        &self,
+        node_id: u32,
+        target: Option<&str>,
+    ) -> Result<bool, PipeswitchError> {
+        let action = MainloopAction::SetMetadata(
+            node_id,
+            "target.object".to_owned(),
+            target.map(str::to_owned),
+        );
+        let request = self.request(action, "Failed to send set metadata")?;
+
+        match self.wait_for(request, None) {
+            Ok(MainloopEvents::MetadataSet(set)) => Ok(set),
+            Ok(_) => Ok(false),
+            Err(_) => Err(PipeswitchError::CriticalThreadFailure(
+                "Background thread stopped while setting metadata",
+            )),
+        }
+    }
+
+    /// Creates a virtual sink which lingers after Pipeswitch exits, and
+    /// returns it once it has appeared in the current state
+    pub fn create_null_sink(&self, name: &str, channels: u32) -> Result<Node, PipeswitchError> {
+        let request = self.request(
+            MainloopAction::CreateNullSink(name.to_owned(), channels),
+            "Failed to send create node",
+        )?;
+
+        let deadline = Instant::now() + DEFAULT_LINK_TIMEOUT;
+        let node_id = match self.wait_for(request, Some(deadline)) {
+            Ok(MainloopEvents::NodeCreated(node_id)) => node_id,
+            Ok(_) => None,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let _ = self.request(MainloopAction::AbortCreate(request), "");
+                None
             }
-        })
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(PipeswitchError::CriticalThreadFailure(
+                    "Background thread stopped while creating node",
+                ))
+            }
+        }
+        .ok_or_else(|| PipeswitchError::NodeNotCreated(name.to_owned()))?;
+
+        // The node global may arrive slightly after the proxy is bound
+        self.state_changed
+            .wait_until(&self.pipewire_state, deadline, |state| {
+                state.nodes.get(&node_id).cloned()
+            })
+            .ok_or_else(|| PipeswitchError::NodeNotCreated(name.to_owned()))
     }
 
-    pub fn destroy_link(&self, link: Link) -> Result<bool, PipeswitchError> {
-        self.sender
-            .send(MainloopAction::DestroyLink(link))
-            .map_err(|_| PipeswitchError::CriticalThreadFailure("Failed to send destroy link"))
-            .unwrap();
+    /// Destroys every link going in or out of the given port, returns how many
+    /// were destroyed
+    pub fn disconnect_port(&self, port_id: u32) -> Result<usize, PipeswitchError> {
+        let links: Vec<Link> = self
+            .lock_current_state()
+            .links
+            .values()
+            .filter(|link| link.input_port == port_id || link.output_port == port_id)
+            .cloned()
+            .collect();
 
-        Ok(loop {
-            if let Ok(MainloopEvents::LinkDestroyed(success)) = self.mainloop_receiver.recv() {
-                break success;
+        let mut destroyed = 0;
+        for link in links {
+            if self.destroy_link(link)? {
+                destroyed += 1;
             }
-        })
+        }
+        Ok(destroyed)
+    }
+
+    /// Blocks until PipeWire has answered every request sent before this one.
+    /// Right after [`Pipeswitch::new`] this means all existing globals have
+    /// been received, though infos of links bound meanwhile may need another
+    /// roundtrip.
+    pub fn roundtrip(&self) -> Result<(), PipeswitchError> {
+        let request = self.request(MainloopAction::Roundtrip, "Failed to send roundtrip")?;
+
+        match self.wait_for(request, None) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(PipeswitchError::CriticalThreadFailure(
+                "Background thread stopped during roundtrip",
+            )),
+        }
     }
 }
 
 impl Drop for Pipeswitch {
     fn drop(&mut self) {
-        let _ = self.sender.send(MainloopAction::Terminate);
+        let _ = self.request(MainloopAction::Terminate, "");
         if let Some(handle) = self.join_handle.take() {
             handle
                 .join()
@@ -175,3 +707,90 @@ impl Drop for Pipeswitch {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pipewire::keys::{LINK_INPUT_PORT, LINK_OUTPUT_PORT};
+
+    use super::*;
+    use types::{Channel, Direction, KEY_RULE_NAME};
+
+    /// A source with two ports and a sink with two ports, linked by rule `a`,
+    /// rule `b` and by hand
+    fn linked_graph() -> Pipeswitch {
+        let rule_link = |output: &'static str, input: &'static str, rule: &'static str| {
+            [
+                (*LINK_OUTPUT_PORT, output),
+                (*LINK_INPUT_PORT, input),
+                (KEY_RULE_NAME, rule),
+            ]
+        };
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(2, 1, "source")
+            .port(3, 2, "out_FL", Direction::Output, Channel::Left)
+            .port(4, 2, "out_FR", Direction::Output, Channel::Right)
+            .node(5, 1, "sink")
+            .port(6, 5, "in_FL", Direction::Input, Channel::Left)
+            .port(7, 5, "in_FR", Direction::Input, Channel::Right)
+            .object(ObjectType::Link, 8, &rule_link("3", "6", "a"))
+            .object(ObjectType::Link, 9, &rule_link("4", "7", "b"))
+            .link(10, 3, 7)
+            .build()
+            .unwrap();
+        Pipeswitch::offline(state)
+    }
+
+    fn ids(links: Vec<Link>) -> Vec<u32> {
+        let mut ids: Vec<u32> = links.iter().map(|link| link.id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn managed_links_have_a_rule() {
+        let pipeswitch = linked_graph();
+        assert_eq!(ids(pipeswitch.managed_links()), [8, 9]);
+        assert_eq!(ids(pipeswitch.links()), [8, 9, 10]);
+    }
+
+    #[test]
+    fn links_for_rule_only_gives_its_own() {
+        let pipeswitch = linked_graph();
+        assert_eq!(ids(pipeswitch.links_for_rule("a")), [8]);
+        assert_eq!(ids(pipeswitch.links_for_rule("b")), [9]);
+        assert!(pipeswitch.links_for_rule("c").is_empty());
+    }
+
+    #[test]
+    fn linking_linked_ports_gives_the_existing_link() {
+        let pipeswitch = linked_graph();
+        let ports = pipeswitch.lock_current_state().ports.clone();
+        for _ in 0..2 {
+            // Offline, so asking PipeWire for a new link would fail
+            let link = pipeswitch
+                .create_link(ports[&3].clone(), ports[&6].clone(), "a".to_owned())
+                .unwrap();
+            assert_eq!(link.map(|link| link.id), Some(8));
+        }
+        assert_eq!(ids(pipeswitch.links()), [8, 9, 10]);
+    }
+
+    #[test]
+    fn extra_link_properties_never_replace_the_ports() {
+        let pipeswitch = linked_graph();
+        let ports = pipeswitch.lock_current_state().ports.clone();
+        let extra = [("link.passive", "true"), (*LINK_OUTPUT_PORT, "7")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        let link = NewLink::new(ports[&6].clone(), ports[&3].clone(), "a".to_owned()).unwrap();
+        let properties = link.with_properties(extra).into_properties();
+        let properties: HashMap<String, String> = properties.into_iter().collect();
+        assert_eq!(properties.len(), 7);
+        assert_eq!(properties["link.passive"], "true");
+        assert_eq!(properties[*LINK_OUTPUT_PORT], "3");
+        assert_eq!(properties[*LINK_INPUT_PORT], "6");
+        assert_eq!(properties[KEY_RULE_NAME], "a");
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use pipewire::{keys::*, types::ObjectType};
+
+use super::{
+    parse_serial,
+    types::{Channel, Client, Direction, Factory, Link, Node, Object, Port},
+    PipewireError, PipewireMessage, PipewireState,
+};
+
+/// Builds a [`PipewireState`] out of objects given by hand, ie. to test rules
+/// without a running PipeWire. Properties that PipeWire always sets are filled
+/// in, the rest can be given with [`StateBuilder::object`], ie.
+/// `StateBuilder::new().client(1, "Firefox").node(2, 1, "firefox").build()`
+#[derive(Debug, Default)]
+pub struct StateBuilder {
+    objects: Vec<(ObjectType, u32, HashMap<String, String>)>,
+}
+
+impl StateBuilder {
+    pub fn new() -> Self {
+        StateBuilder::default()
+    }
+
+    pub fn client(self, id: u32, application_name: &str) -> Self {
+        self.object(ObjectType::Client, id, &[(*APP_NAME, application_name)])
+    }
+
+    pub fn node(self, id: u32, client_id: u32, node_name: &str) -> Self {
+        let client_id = client_id.to_string();
+        self.object(
+            ObjectType::Node,
+            id,
+            &[(*CLIENT_ID, client_id.as_str()), (*NODE_NAME, node_name)],
+        )
+    }
+
+    /// A port whose alias is its name
+    pub fn port(
+        self,
+        id: u32,
+        node_id: u32,
+        name: &str,
+        direction: Direction,
+        channel: Channel,
+    ) -> Self {
+        let node_id = node_id.to_string();
+        let direction = direction.to_string();
+        let channel = channel.name();
+        self.object(
+            ObjectType::Port,
+            id,
+            &[
+                (*NODE_ID, node_id.as_str()),
+                (*PORT_NAME, name),
+                (*PORT_ALIAS, name),
+                (*PORT_DIRECTION, direction.as_str()),
+                (*AUDIO_CHANNEL, channel.as_str()),
+            ],
+        )
+    }
+
+    /// A link between two ports, which have to be added before it
+    pub fn link(self, id: u32, output_port: u32, input_port: u32) -> Self {
+        let output_port = output_port.to_string();
+        let input_port = input_port.to_string();
+        self.object(
+            ObjectType::Link,
+            id,
+            &[
+                (*LINK_OUTPUT_PORT, output_port.as_str()),
+                (*LINK_INPUT_PORT, input_port.as_str()),
+            ],
+        )
+    }
+
+    /// Any object with the given properties, which replace the defaults
+    pub fn object(mut self, object_type: ObjectType, id: u32, props: &[(&str, &str)]) -> Self {
+        let props = props
+            .iter()
+            .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+            .collect();
+        self.objects.push((object_type, id, props));
+        self
+    }
+
+    /// Gives the object added last more properties, ie. the `object.path` of
+    /// a port, replacing any it already has
+    pub fn with(mut self, props: &[(&str, &str)]) -> Self {
+        if let Some((_, _, given)) = self.objects.last_mut() {
+            let props = props
+                .iter()
+                .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()));
+            given.extend(props);
+        }
+        self
+    }
+
+    /// Adds the objects in the order they were given. Fails on the first one
+    /// that is missing a property PipeWire would always have set.
+    pub fn build(self) -> Result<PipewireState, PipewireError> {
+        let mut state = PipewireState::default();
+        for (object_type, id, given) in self.objects {
+            let mut props: HashMap<String, String> = defaults(&object_type, &given, &state)
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect();
+            props.extend(given);
+            let object = match object_type {
+                ObjectType::Client => Object::Client(Client::from_props(id, &props)?),
+                ObjectType::Node => Object::Node(Node::from_props(id, &props)?),
+                ObjectType::Port => Object::Port(Port::from_props(id, &props)?),
+                ObjectType::Link => Object::Link(Link::from_props(id, &props)?),
+                ObjectType::Factory => Object::Factory(Factory::from_props(id, &props)?),
+                // Not kept in the state
+                _ => continue,
+            };
+            let serial = parse_serial(props.get("object.serial").map(String::as_str));
+            state.process_message(PipewireMessage::NewGlobal(id, object_type, object, serial));
+        }
+        Ok(state)
+    }
+}
+
+/// The properties PipeWire always sets on objects of the type, as far as they
+/// are not about the object itself
+fn defaults(
+    object_type: &ObjectType,
+    given: &HashMap<String, String>,
+    state: &PipewireState,
+) -> Vec<(&'static str, String)> {
+    let zero = || "0".to_owned();
+    match object_type {
+        ObjectType::Client => vec![
+            (*MODULE_ID, zero()),
+            (*PROTOCOL, "protocol-native".to_owned()),
+            (*SEC_PID, zero()),
+            (*SEC_UID, zero()),
+            (*SEC_GID, zero()),
+            (*SEC_LABEL, "unconfined".to_owned()),
+        ],
+        ObjectType::Port => vec![(*PORT_ID, zero())],
+        ObjectType::Link => {
+            // The nodes of the linked ports, if they are known
+            let node_of = |key: &str| {
+                let port_id: u32 = given.get(key)?.parse().ok()?;
+                Some(state.ports.get(&port_id)?.node_id.to_string())
+            };
+            let mut props = vec![(*FACTORY_ID, zero())];
+            props.extend(node_of(*LINK_OUTPUT_PORT).map(|node| (*LINK_OUTPUT_NODE, node)));
+            props.extend(node_of(*LINK_INPUT_PORT).map(|node| (*LINK_INPUT_NODE, node)));
+            props
+        }
+        ObjectType::Factory => vec![(*MODULE_ID, zero())],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Direction::{Input, Output};
+
+    #[test]
+    fn links_know_the_nodes_and_aliases_of_their_ports() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(2, 1, "source")
+            .port(3, 2, "capture_FL", Output, Channel::Left)
+            .node(4, 1, "sink")
+            .port(5, 4, "playback_FL", Input, Channel::Left)
+            .link(6, 3, 5)
+            .build()
+            .unwrap();
+        assert_eq!(state.nodes[&2].client_id, 1);
+        assert_eq!(state.ports_by_node(4)[0].id, 5);
+        let link = &state.links[&6];
+        assert_eq!((link.output_node, link.input_node), (2, 4));
+        assert_eq!(link.output_alias.as_deref(), Some("capture_FL"));
+        assert_eq!(link.input_alias.as_deref(), Some("playback_FL"));
+    }
+
+    #[test]
+    fn objects_missing_properties_fail() {
+        let node = StateBuilder::new()
+            .client(1, "app")
+            .object(ObjectType::Node, 2, &[(*CLIENT_ID, "1")])
+            .build();
+        assert!(matches!(node, Err(PipewireError::PropNotFound(2, ..))));
+        // The port of the link is not known, so neither is its node
+        let link = StateBuilder::new().link(6, 3, 5).build();
+        assert!(link.is_err());
+    }
+}
@@ -1,11 +1,14 @@
-use super::PipewireMessage;
+use super::{PipewireMessage, StateChanged};
 use crate::{
-    types::{self, Object},
-    PipeswitchMessage, PipewireError, PipewireState,
+    types::{self, DefaultNode, Object},
+    NewLink, PipeswitchMessage, PipewireError, PipewireState,
 };
 use pipewire::{
     channel::Receiver as PipewireReceiver,
     link::{self as pwlink},
+    metadata::{Metadata, MetadataListener},
+    node::{self as pwnode},
+    prelude::*,
     proxy::ProxyT,
     registry::{GlobalObject, Registry},
     spa::{AsyncSeq, ForeignDict},
@@ -13,97 +16,303 @@ use pipewire::{
     Context, Core, MainLoop, PW_ID_CORE,
 };
 use std::{
+    cell::Cell,
     collections::HashMap,
     sync::{
         mpsc::{self, Sender},
-        Arc, Mutex,
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
     },
+    time::{Duration, Instant},
 };
 
+/// How long to wait before connecting again after losing PipeWire, doubled
+/// after every attempt up to [`MAX_RECONNECT_DELAY`]
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The error PipeWire reports on the core once its socket has closed
+const EPIPE: i32 = 32;
+
+/// Identifies which action an event is the answer to
+pub type RequestId = u64;
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum MainloopAction {
     Terminate,
-    CreateLink(String, types::Port, types::Port, String),
+    /// Factory name and the links to create, all waited for with a single
+    /// roundtrip, and when they were asked for
+    CreateLinks(String, Vec<NewLink>, Instant),
+    CreateNullSink(String, u32),
+    /// Stop waiting for the given create request
+    AbortCreate(RequestId),
     DestroyLink(types::Link),
+    /// Subject, key and value of a property of the `default` metadata, which
+    /// is cleared without a value
+    SetMetadata(u32, String, Option<String>),
+    Roundtrip,
 }
 
 #[derive(Debug)]
 pub enum MainloopEvents {
-    LinkCreated(Option<types::Link>),
+    /// In the order the links were asked for, None for those that failed
+    LinksCreated(Vec<Option<types::Link>>),
+    NodeCreated(Option<u32>),
     LinkDestroyed(bool),
+    MetadataSet(bool),
+    RoundtripDone,
 }
 
 enum Roundtrip {
-    CreateLink(AsyncSeq, u32),
+    /// Proxy ids of the links, None for those that could not be created, and
+    /// when they were asked for
+    CreateLinks(AsyncSeq, Vec<Option<u32>>, Instant),
+    CreateNode(AsyncSeq, u32),
     DestroyLink(AsyncSeq),
+    SetMetadata(AsyncSeq),
+    Sync(AsyncSeq),
+}
+
+impl Roundtrip {
+    fn seq(&self) -> AsyncSeq {
+        match self {
+            Roundtrip::CreateLinks(seq, ..)
+            | Roundtrip::CreateNode(seq, _)
+            | Roundtrip::DestroyLink(seq)
+            | Roundtrip::SetMetadata(seq)
+            | Roundtrip::Sync(seq) => *seq,
+        }
+    }
+
+    /// The answer to the request when the roundtrip never completes
+    fn failed(&self) -> MainloopEvents {
+        match self {
+            Roundtrip::CreateLinks(_, proxy_ids, _) => {
+                MainloopEvents::LinksCreated(vec![None; proxy_ids.len()])
+            }
+            Roundtrip::CreateNode(..) => MainloopEvents::NodeCreated(None),
+            Roundtrip::DestroyLink(_) => MainloopEvents::LinkDestroyed(false),
+            Roundtrip::SetMetadata(_) => MainloopEvents::MetadataSet(false),
+            Roundtrip::Sync(_) => MainloopEvents::RoundtripDone,
+        }
+    }
 }
 
 type ShareableMainloopData = Arc<Mutex<MainloopData>>;
 
 struct LinkProxy {
     _proxy: pwlink::Link,
+    /// Global id of the link, once PipeWire has announced it
+    id: Option<u32>,
     link: Option<types::Link>,
     listener: Option<pwlink::LinkListener>,
+    /// Hears the state of a link bound from the registry for as long as it
+    /// exists, unlike `listener` which is only there while it is created
+    _state_listener: Option<pwlink::LinkListener>,
+}
+
+struct NodeProxy {
+    _proxy: pwnode::Node,
+    id: Option<u32>,
+    listener: Option<pwnode::NodeListener>,
 }
 
 struct MainloopData {
     mainloop: MainLoop,
-    core: Core,
-    pending_seq: Option<Roundtrip>,
+    /// The current connection, missing while waiting to reconnect
+    core: Option<Core>,
+    registry: Option<Arc<Registry>>,
+    /// Set once Pipeswitch asked the loop to stop, instead of it stopping
+    /// because the connection was lost
+    terminated: bool,
+    /// The roundtrip after reconnecting, done once the globals have arrived
+    reconnect_seq: Option<AsyncSeq>,
+    /// Roundtrips still waited for, and the requests they answer
+    pending: Vec<(RequestId, Roundtrip)>,
     links: HashMap<u32, LinkProxy>,
-    event_sender: Sender<MainloopEvents>,
+    nodes: HashMap<u32, NodeProxy>,
+    /// Every node in the graph by global id, listened to for property changes
+    watched_nodes: HashMap<u32, (pwnode::Node, pwnode::NodeListener)>,
+    event_sender: Sender<(RequestId, MainloopEvents)>,
     message_sender: Option<Sender<PipeswitchMessage>>,
+    /// Only these object types are sent to `message_sender`, if given
+    message_filter: Option<Vec<ObjectType>>,
+    /// The `default` metadata, which holds the default sink and source
+    metadata: Option<(Metadata, MetadataListener)>,
+    state_changed: Arc<StateChanged>,
 }
 
 impl MainloopData {
     fn from(
         mainloop: MainLoop,
-        core: Core,
-        event_sender: Sender<MainloopEvents>,
+        event_sender: Sender<(RequestId, MainloopEvents)>,
         message_sender: Option<Sender<PipeswitchMessage>>,
+        message_filter: Option<Vec<ObjectType>>,
+        state_changed: Arc<StateChanged>,
     ) -> Self {
         MainloopData {
             mainloop,
-            core,
+            core: None,
+            registry: None,
+            terminated: false,
+            reconnect_seq: None,
             event_sender,
             message_sender,
-            pending_seq: None,
+            message_filter,
+            metadata: None,
+            state_changed,
+            pending: Vec::new(),
             links: HashMap::default(),
+            nodes: HashMap::default(),
+            watched_nodes: HashMap::default(),
+        }
+    }
+
+    /// Lets the main thread know an action failed, instead of panicking the
+    /// background thread
+    fn report_error(&self, error: PipewireError) {
+        match &self.message_sender {
+            Some(sender) => {
+                let _ = sender.send(PipeswitchMessage::Error(error));
+            }
+            None => log::error!("{error}"),
+        }
+    }
+
+    /// Answers the given request. Nobody may be waiting anymore, so failing
+    /// to send is fine.
+    fn send_event(&self, request: RequestId, event: MainloopEvents) {
+        let _ = self.event_sender.send((request, event));
+    }
+
+    fn core(&self) -> Result<&Core, PipewireError> {
+        self.core.as_ref().ok_or(PipewireError::Disconnected)
+    }
+
+    /// Starts a roundtrip, or reports the error and sends `failed` to whoever
+    /// is waiting for the action to finish
+    fn sync(
+        &self,
+        request: RequestId,
+        action: &'static str,
+        failed: MainloopEvents,
+    ) -> Option<AsyncSeq> {
+        let result = self.core().and_then(|core| {
+            core.sync(0)
+                .map_err(|e| PipewireError::ActionFailed(action, e))
+        });
+        match result {
+            Ok(seq) => Some(seq),
+            Err(e) => {
+                self.report_error(e);
+                self.send_event(request, failed);
+                None
+            }
+        }
+    }
+
+    /// Drops everything bound through the connection, which has to happen
+    /// before the connection itself is dropped, and fails the requests still
+    /// waiting for it
+    fn disconnect(&mut self) {
+        for (request, roundtrip) in std::mem::take(&mut self.pending) {
+            self.send_event(request, roundtrip.failed());
         }
+        self.links.clear();
+        self.nodes.clear();
+        self.watched_nodes.clear();
+        self.metadata = None;
+        self.reconnect_seq = None;
+        self.registry = None;
+        self.core = None;
     }
 }
 
+/// The mutex is only poisoned if the background thread panicked while holding
+/// it, in which case the data is still the best there is
+fn lock(data: &ShareableMainloopData) -> MutexGuard<MainloopData> {
+    data.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 pub fn mainloop(
     sender: Option<Sender<PipeswitchMessage>>,
-    ps_sender: mpsc::Sender<MainloopEvents>,
-    receiver: PipewireReceiver<MainloopAction>,
-    state: Arc<Mutex<PipewireState>>,
+    filter: Option<Vec<ObjectType>>,
+    remote: Option<String>,
+    ps_sender: mpsc::Sender<(RequestId, MainloopEvents)>,
+    receiver: PipewireReceiver<(RequestId, MainloopAction)>,
+    state: Arc<RwLock<PipewireState>>,
+    state_changed: Arc<StateChanged>,
 ) -> Result<(), PipewireError> {
     let mainloop = MainLoop::new()?;
-    let context = Context::new(&mainloop)?;
-    let core = context.connect(None)?;
-    let registry = Arc::new(core.get_registry()?);
 
     let data = Arc::new(Mutex::new(MainloopData::from(
         mainloop.clone(),
-        core.clone(),
         ps_sender,
         sender,
+        filter,
+        state_changed,
     )));
 
     let _rec = receiver.attach(&mainloop, {
         let data = data.clone();
-        let registry = registry.clone();
         // Called when Pipeswitch sends an event
-        move |action| handle_action(action, &data, &registry)
+        move |(request, action)| handle_action(request, action, &data)
     });
+
+    // Not reaching PipeWire at all is an error, losing it later is not
+    let remote = remote.as_deref();
+    run_connection(&mainloop, &data, &state, remote, false)?;
+    let mut delay = MIN_RECONNECT_DELAY;
+    while !lock(&data).terminated {
+        wait(&mainloop, delay);
+        if lock(&data).terminated {
+            break;
+        }
+        let started = Instant::now();
+        if let Err(e) = run_connection(&mainloop, &data, &state, remote, true) {
+            log::debug!("failed to reconnect to PipeWire: {e}");
+        }
+        // Only a connection that stayed up for a while starts the backoff over,
+        // so that a PipeWire crashing right after starting is not hammered
+        delay = if started.elapsed() > MAX_RECONNECT_DELAY {
+            MIN_RECONNECT_DELAY
+        } else {
+            (delay * 2).min(MAX_RECONNECT_DELAY)
+        };
+    }
+
+    Ok(())
+}
+
+/// Connects to PipeWire and runs the loop until it is terminated or the
+/// connection is lost
+fn run_connection(
+    mainloop: &MainLoop,
+    data: &ShareableMainloopData,
+    state: &Arc<RwLock<PipewireState>>,
+    remote: Option<&str>,
+    reconnect: bool,
+) -> Result<(), PipewireError> {
+    let context = Context::new(mainloop)?;
+    let properties = remote.map(|remote| {
+        pipewire::properties! {
+            *pipewire::keys::REMOTE_NAME => remote
+        }
+    });
+    let core = context.connect(properties)?;
+    let registry = Arc::new(core.get_registry()?);
+
     let _listener_core = core
         .add_listener_local()
         .done({
             let data = data.clone();
+            let state = state.clone();
             // Called when Core is done with roundtrip
-            move |id, seq| handle_done(id, seq, &data)
+            move |id, seq| handle_done(id, seq, &data, &state)
+        })
+        .error({
+            let data = data.clone();
+            move |id, _seq, res, message| handle_core_error(id, res, message, &data)
         })
         .register();
     let _listener = registry
@@ -115,119 +324,347 @@ pub fn mainloop(
             move |global| handle_new_global(global, &data, &registry, &state)
         })
         .global_remove({
+            let state = state.clone();
+            let data = data.clone();
             move |global_id| {
+                let mut data_lock = lock(&data);
+                data_lock.watched_nodes.remove(&global_id);
+                // Links removed by something else take their proxies with them
+                data_lock
+                    .links
+                    .retain(|_, proxy| proxy.id != Some(global_id));
+                drop(data_lock);
                 process_message(PipewireMessage::GlobalRemoved(global_id), &data, &state)
             }
         })
         .register();
 
+    let mut data_lock = lock(data);
+    if reconnect {
+        // Answered after the registry has sent every existing global
+        data_lock.reconnect_seq = Some(core.sync(0)?);
+    }
+    data_lock.core = Some(core.clone());
+    data_lock.registry = Some(registry.clone());
+    drop(data_lock);
+
     mainloop.run();
 
+    let mut data_lock = lock(data);
+    data_lock.disconnect();
+    let terminated = data_lock.terminated;
+    drop(data_lock);
+    if !terminated {
+        process_message(PipewireMessage::Disconnected, data, state);
+    }
     Ok(())
 }
 
+/// Runs the loop for the given time, so that actions are still answered, or
+/// until it is terminated
+fn wait(mainloop: &MainLoop, delay: Duration) {
+    let timer = mainloop.add_timer({
+        let mainloop = mainloop.clone();
+        move |_| mainloop.quit()
+    });
+    let _ = timer.update_timer(Some(delay), None);
+    mainloop.run();
+}
+
 /// Called when an action is called from the Pipeswitch-struct
-fn handle_action(action: MainloopAction, data: &ShareableMainloopData, registry: &Registry) {
+fn handle_action(request: RequestId, action: MainloopAction, data: &ShareableMainloopData) {
     match action {
-        MainloopAction::Terminate => data.lock().unwrap().mainloop.quit(),
-        MainloopAction::CreateLink(factory_name, output, input, rule_name) => {
+        MainloopAction::Terminate => {
+            let mut data_lock = lock(data);
+            data_lock.terminated = true;
+            data_lock.mainloop.quit();
+        }
+        MainloopAction::CreateLinks(factory_name, links, requested) => {
+            let mut data_lock = lock(data);
+            let mut proxy_ids = Vec::with_capacity(links.len());
+            for link in links {
+                match create_link_proxy(&mut data_lock, data, &factory_name, link) {
+                    Ok(proxy_id) => proxy_ids.push(Some(proxy_id)),
+                    Err(e) => {
+                        data_lock.report_error(e);
+                        proxy_ids.push(None);
+                    }
+                }
+            }
+            let failed = MainloopEvents::LinksCreated(vec![None; proxy_ids.len()]);
+            match data_lock.sync(request, "create links", failed) {
+                Some(seq) => {
+                    let roundtrip = Roundtrip::CreateLinks(seq, proxy_ids, requested);
+                    data_lock.pending.push((request, roundtrip));
+                }
+                None => {
+                    for proxy_id in proxy_ids.into_iter().flatten() {
+                        data_lock.links.remove(&proxy_id);
+                    }
+                }
+            }
+        }
+        MainloopAction::CreateNullSink(name, channels) => {
             let props = pipewire::properties! {
-                *pipewire::keys::LINK_OUTPUT_NODE => output.node_id.to_string(),
-                *pipewire::keys::LINK_OUTPUT_PORT => output.id.to_string(),
-                *pipewire::keys::LINK_INPUT_NODE => input.node_id.to_string(),
-                *pipewire::keys::LINK_INPUT_PORT => input.id.to_string(),
-                "object.linger" => "1",
-                types::KEY_RULE_NAME => rule_name
+                *pipewire::keys::FACTORY_NAME => "support.null-audio-sink",
+                *pipewire::keys::NODE_NAME => name.clone(),
+                *pipewire::keys::NODE_DESCRIPTION => name,
+                *pipewire::keys::MEDIA_CLASS => "Audio/Sink",
+                *pipewire::keys::AUDIO_CHANNELS => channels.to_string(),
+                "audio.position" => channel_positions(channels),
+                "object.linger" => "1"
+            };
+            let mut data_lock = lock(data);
+            let proxy = match data_lock.core().and_then(|core| {
+                core.create_object::<pwnode::Node, _>("adapter", &props)
+                    .map_err(|e| PipewireError::ActionFailed("create null sink", e))
+            }) {
+                Ok(proxy) => proxy,
+                Err(e) => {
+                    data_lock.report_error(e);
+                    data_lock.send_event(request, MainloopEvents::NodeCreated(None));
+                    return;
+                }
             };
-            let mut data_lock = data.lock().unwrap();
-            let proxy = data_lock
-                .core
-                .create_object::<pipewire::link::Link, _>(&factory_name, &props)
-                .unwrap();
             let proxy_id = proxy.upcast_ref().id();
-
-            if let Some(info) = data_lock.links.get(&proxy_id).and_then(|l| l.link.clone()) {
-                data_lock
-                    .event_sender
-                    .send(MainloopEvents::LinkCreated(Some(info)))
-                    .unwrap();
-            } else {
-                let listener = proxy
-                    .add_listener_local()
-                    .info({
-                        let data = data.clone();
-                        move |info| {
-                            if let Some(link_proxy) = data.lock().unwrap().links.get_mut(&proxy_id)
-                            {
-                                link_proxy.link =
-                                    Some(types::Link::from_link_info(info, proxy_id).unwrap())
-                            }
+            let listener = proxy
+                .add_listener_local()
+                .info({
+                    let data = data.clone();
+                    move |info| {
+                        if let Some(node_proxy) = lock(&data).nodes.get_mut(&proxy_id) {
+                            node_proxy.id = Some(info.id());
                         }
-                    })
-                    .register();
-                data_lock.links.insert(
-                    proxy_id,
-                    LinkProxy {
-                        _proxy: proxy,
-                        link: None,
-                        listener: Some(listener),
-                    },
-                );
-                data_lock.pending_seq = Some(Roundtrip::CreateLink(
-                    data_lock.core.sync(0).expect("sync failed"),
-                    proxy_id,
-                ));
+                    }
+                })
+                .register();
+            data_lock.nodes.insert(
+                proxy_id,
+                NodeProxy {
+                    _proxy: proxy,
+                    id: None,
+                    listener: Some(listener),
+                },
+            );
+            match data_lock.sync(
+                request,
+                "create null sink",
+                MainloopEvents::NodeCreated(None),
+            ) {
+                Some(seq) => {
+                    let roundtrip = Roundtrip::CreateNode(seq, proxy_id);
+                    data_lock.pending.push((request, roundtrip));
+                }
+                None => drop(data_lock.nodes.remove(&proxy_id)),
+            }
+        }
+        MainloopAction::AbortCreate(aborted) => {
+            let mut data_lock = lock(data);
+            let index = data_lock.pending.iter().position(|(r, _)| *r == aborted);
+            let roundtrip = match index.map(|index| data_lock.pending.remove(index).1) {
+                Some(roundtrip) => roundtrip,
+                // Already answered, the answer is thrown away by the waiter
+                None => return,
+            };
+            match &roundtrip {
+                Roundtrip::CreateLinks(_, proxy_ids, _) => {
+                    for proxy_id in proxy_ids.iter().flatten() {
+                        data_lock.links.remove(proxy_id);
+                    }
+                }
+                Roundtrip::CreateNode(_, proxy_id) => drop(data_lock.nodes.remove(proxy_id)),
+                _ => {}
             }
+            // The waiter has given up on the request and waits for this
+            // answer to forget it
+            data_lock.send_event(aborted, roundtrip.failed());
         }
         MainloopAction::DestroyLink(link) => {
-            let mut data_lock = data.lock().unwrap();
+            let mut data_lock = lock(data);
             if let Some(proxy) = data_lock.links.remove(&link.proxy_id) {
                 if proxy.link.is_some() || proxy.listener.is_some() {
-                    data_lock
-                        .event_sender
-                        .send(MainloopEvents::LinkDestroyed(false))
-                        .unwrap();
+                    data_lock.send_event(request, MainloopEvents::LinkDestroyed(false));
                     data_lock.links.insert(link.proxy_id, proxy);
-                } else {
+                } else if let Some(registry) = data_lock.registry.clone() {
                     registry.destroy_global(link.id);
-                    data_lock.pending_seq = Some(Roundtrip::DestroyLink(
-                        data_lock.core.sync(0).expect("sync failed"),
-                    ));
+                    let failed = MainloopEvents::LinkDestroyed(false);
+                    if let Some(seq) = data_lock.sync(request, "destroy link", failed) {
+                        data_lock
+                            .pending
+                            .push((request, Roundtrip::DestroyLink(seq)));
+                    }
+                } else {
+                    data_lock.report_error(PipewireError::Disconnected);
+                    data_lock.send_event(request, MainloopEvents::LinkDestroyed(false));
                 }
+            } else {
+                data_lock.send_event(request, MainloopEvents::LinkDestroyed(false));
+            }
+        }
+        MainloopAction::SetMetadata(subject, key, value) => {
+            let mut data_lock = lock(data);
+            match &data_lock.metadata {
+                Some((metadata, _)) => {
+                    metadata.set_property(subject, &key, None, value.as_deref());
+                }
+                None => {
+                    data_lock.report_error(PipewireError::NoDefaultMetadata);
+                    data_lock.send_event(request, MainloopEvents::MetadataSet(false));
+                    return;
+                }
+            }
+            let failed = MainloopEvents::MetadataSet(false);
+            if let Some(seq) = data_lock.sync(request, "set metadata", failed) {
+                data_lock
+                    .pending
+                    .push((request, Roundtrip::SetMetadata(seq)));
+            }
+        }
+        MainloopAction::Roundtrip => {
+            let mut data_lock = lock(data);
+            if let Some(seq) = data_lock.sync(request, "roundtrip", MainloopEvents::RoundtripDone) {
+                data_lock.pending.push((request, Roundtrip::Sync(seq)));
             }
         }
     }
 }
 
-/// Called when a round trip is complete from the Core
-fn handle_done(id: u32, seq: AsyncSeq, data: &ShareableMainloopData) {
-    let mut data_lock = data.lock().unwrap();
-    if id == PW_ID_CORE {
-        match data_lock.pending_seq {
-            Some(Roundtrip::CreateLink(s, id)) => {
-                if s == seq {
-                    if let Some(proxy) = data_lock.links.get_mut(&id) {
-                        let _listener = proxy.listener.take();
-                        let link = proxy.link.take();
-                        data_lock
-                            .event_sender
-                            .send(MainloopEvents::LinkCreated(link))
-                            .unwrap();
-                        data_lock.pending_seq = None;
+/// Asks the factory for the link and keeps its proxy, whose info arrives
+/// before the next roundtrip is done. Returns the id of the proxy.
+fn create_link_proxy(
+    data_lock: &mut MainloopData,
+    data: &ShareableMainloopData,
+    factory_name: &str,
+    link: NewLink,
+) -> Result<u32, PipewireError> {
+    let mut props = pipewire::Properties::new();
+    for (key, value) in link.into_properties() {
+        props.insert(key, value);
+    }
+    let proxy = data_lock.core().and_then(|core| {
+        core.create_object::<pipewire::link::Link, _>(factory_name, &props)
+            .map_err(|e| PipewireError::ActionFailed("create link", e))
+    })?;
+    let proxy_id = proxy.upcast_ref().id();
+    // Already known, its info is used as it is
+    if data_lock
+        .links
+        .get(&proxy_id)
+        .map_or(false, |l| l.link.is_some())
+    {
+        return Ok(proxy_id);
+    }
+    let listener = proxy
+        .add_listener_local()
+        .info({
+            let data = data.clone();
+            move |info| {
+                let mut data_lock = lock(&data);
+                match types::Link::from_link_info(info, proxy_id) {
+                    Ok(link) => {
+                        if let Some(link_proxy) = data_lock.links.get_mut(&proxy_id) {
+                            link_proxy.id = Some(link.id);
+                            link_proxy.link = Some(link)
+                        }
                     }
+                    Err(e) => data_lock.report_error(e),
                 }
             }
-            Some(Roundtrip::DestroyLink(s)) => {
-                if s == seq {
-                    data_lock
-                        .event_sender
-                        .send(MainloopEvents::LinkDestroyed(true))
-                        .unwrap();
-                    data_lock.pending_seq = None;
-                }
+        })
+        .register();
+    data_lock.links.insert(
+        proxy_id,
+        LinkProxy {
+            _proxy: proxy,
+            id: None,
+            link: None,
+            listener: Some(listener),
+            _state_listener: None,
+        },
+    );
+    Ok(proxy_id)
+}
+
+/// Called when a round trip is complete from the Core
+fn handle_done(
+    id: u32,
+    seq: AsyncSeq,
+    data: &ShareableMainloopData,
+    state: &Arc<RwLock<PipewireState>>,
+) {
+    let mut data_lock = lock(data);
+    if id != PW_ID_CORE {
+        return;
+    }
+    if data_lock.reconnect_seq == Some(seq) {
+        data_lock.reconnect_seq = None;
+        drop(data_lock);
+        process_message(PipewireMessage::Reconnected, data, state);
+        return;
+    }
+    let index = match data_lock.pending.iter().position(|(_, r)| r.seq() == seq) {
+        Some(index) => index,
+        None => return,
+    };
+    let (request, roundtrip) = data_lock.pending.remove(index);
+    let event = match roundtrip {
+        Roundtrip::CreateLinks(_, proxy_ids, requested) => {
+            let links: Vec<Option<types::Link>> = proxy_ids
+                .into_iter()
+                .map(|id| {
+                    let proxy = data_lock.links.get_mut(&id?)?;
+                    let _listener = proxy.listener.take();
+                    proxy.link.take()
+                })
+                .collect();
+            if let Some(sender) = &data_lock.message_sender {
+                let created = links.iter().flatten().count();
+                let _ = sender.send(PipeswitchMessage::LinksCreated(
+                    created,
+                    requested.elapsed(),
+                ));
             }
-            None => {}
+            MainloopEvents::LinksCreated(links)
+        }
+        Roundtrip::CreateNode(_, id) => {
+            MainloopEvents::NodeCreated(data_lock.nodes.get_mut(&id).and_then(|proxy| {
+                let _listener = proxy.listener.take();
+                proxy.id
+            }))
         }
+        Roundtrip::DestroyLink(_) => MainloopEvents::LinkDestroyed(true),
+        Roundtrip::SetMetadata(_) => MainloopEvents::MetadataSet(true),
+        Roundtrip::Sync(_) => MainloopEvents::RoundtripDone,
+    };
+    data_lock.send_event(request, event);
+}
+
+/// Called when PipeWire reports an error. Stops the loop if the connection
+/// itself is gone, so that it can be made again.
+fn handle_core_error(id: u32, res: i32, message: &str, data: &ShareableMainloopData) {
+    if id == PW_ID_CORE && res == -EPIPE {
+        log::debug!("connection to PipeWire lost: {message}");
+        lock(data).mainloop.quit();
+    }
+}
+
+/// The node name in the value of a default node, ie. `{ "name": "alsa_output..." }`
+fn default_node_name(value: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(value).ok()?;
+    value.get("name")?.as_str().map(str::to_owned)
+}
+
+/// Speaker positions for a sink with the given amount of channels
+fn channel_positions(channels: u32) -> String {
+    match channels {
+        1 => "MONO".to_owned(),
+        2 => "FL,FR".to_owned(),
+        6 => "FL,FR,FC,LFE,RL,RR".to_owned(),
+        8 => "FL,FR,FC,LFE,RL,RR,SL,SR".to_owned(),
+        _ => (0..channels)
+            .map(|n| format!("AUX{n}"))
+            .collect::<Vec<_>>()
+            .join(","),
     }
 }
 
@@ -235,71 +672,199 @@ fn handle_new_global(
     global: &GlobalObject<ForeignDict>,
     data: &ShareableMainloopData,
     registry: &Registry,
-    state: &Arc<Mutex<PipewireState>>,
+    state: &Arc<RwLock<PipewireState>>,
 ) {
     match global.type_ {
         ObjectType::Link => {
-            let proxy: pipewire::link::Link = registry.bind(global).unwrap();
+            let proxy: pipewire::link::Link = match registry.bind(global) {
+                Ok(proxy) => proxy,
+                Err(e) => {
+                    lock(data).report_error(PipewireError::ActionFailed("bind link", e));
+                    return;
+                }
+            };
             let proxy_id = proxy.upcast_ref().id();
+            // The first info announces the link, the later ones its state
+            let announced = Cell::new(false);
             let listener = proxy
                 .add_listener_local()
                 .info({
                     let data = data.clone();
                     let state = state.clone();
                     move |info| {
-                        process_message(
-                            PipewireMessage::NewGlobal(
-                                info.id(),
-                                ObjectType::Link,
-                                Object::Link(types::Link::from_link_info(info, proxy_id).unwrap()),
-                            ),
-                            &data,
-                            &state,
-                        );
-                        let mut data_lock = data.lock().unwrap();
-                        if let Some(proxy) = data_lock.links.get_mut(&proxy_id) {
-                            proxy.listener.take();
-                        }
+                        let link = match types::Link::from_link_info(info, proxy_id) {
+                            Ok(link) => Object::Link(link),
+                            Err(e) => {
+                                lock(&data).report_error(e);
+                                return;
+                            }
+                        };
+                        let message = if announced.replace(true) {
+                            if !info.change_mask().contains(pwlink::LinkChangeMask::STATE) {
+                                return;
+                            }
+                            PipewireMessage::ObjectChanged(link)
+                        } else {
+                            let serial = info.props().and_then(|props| props.get("object.serial"));
+                            let serial = super::parse_serial(serial);
+                            PipewireMessage::NewGlobal(info.id(), ObjectType::Link, link, serial)
+                        };
+                        process_message(message, &data, &state);
                     }
                 })
                 .register();
-            let mut data_lock = data.lock().unwrap();
-            data_lock.links.insert(
+            lock(data).links.insert(
                 proxy_id,
                 LinkProxy {
                     _proxy: proxy,
+                    id: Some(global.id),
                     link: None,
-                    listener: Some(listener),
+                    listener: None,
+                    _state_listener: Some(listener),
                 },
             );
         }
-        _ => match Object::from_global(global) {
-            Ok(Some(obj)) => {
-                process_message(
-                    PipewireMessage::NewGlobal(global.id, global.type_.clone(), obj),
-                    data,
-                    state,
-                );
+        ObjectType::Metadata => {
+            let name = global
+                .props
+                .as_ref()
+                .and_then(|props| props.get("metadata.name"));
+            if name != Some("default") {
+                return;
             }
-            Err(e) => {
-                let data_lock = data.lock().unwrap();
-                if let Some(sender) = &data_lock.message_sender {
-                    sender.send(PipeswitchMessage::Error(e)).unwrap();
+            let proxy: Metadata = match registry.bind(global) {
+                Ok(proxy) => proxy,
+                Err(e) => {
+                    lock(data).report_error(PipewireError::ActionFailed("bind metadata", e));
+                    return;
                 }
-            }
-            _ => {}
-        },
+            };
+            let listener = proxy
+                .add_listener_local()
+                .property({
+                    let data = data.clone();
+                    let state = state.clone();
+                    move |_subject, key, _type, value| {
+                        for kind in [DefaultNode::Sink, DefaultNode::Source] {
+                            // No key means that every property was cleared
+                            if key.is_none() || key == Some(kind.metadata_key()) {
+                                let name = value.and_then(default_node_name);
+                                let message = PipewireMessage::DefaultChanged(kind, name);
+                                process_message(message, &data, &state);
+                            }
+                        }
+                        0
+                    }
+                })
+                .register();
+            lock(data).metadata = Some((proxy, listener));
+        }
+        ObjectType::Node => {
+            process_global(global, data, state);
+            watch_node(global, data, state, registry);
+        }
+        _ => process_global(global, data, state),
     }
 }
 
+fn process_global(
+    global: &GlobalObject<ForeignDict>,
+    data: &ShareableMainloopData,
+    state: &Arc<RwLock<PipewireState>>,
+) {
+    match Object::from_global(global) {
+        Ok(Some(obj)) => {
+            let serial = global
+                .props
+                .as_ref()
+                .and_then(|props| props.get("object.serial"));
+            let serial = super::parse_serial(serial);
+            process_message(
+                PipewireMessage::NewGlobal(global.id, global.type_.clone(), obj, serial),
+                data,
+                state,
+            );
+        }
+        Err(e) => lock(data).report_error(e),
+        _ => {}
+    }
+}
+
+/// Binds the node to hear when its properties change after it appeared
+fn watch_node(
+    global: &GlobalObject<ForeignDict>,
+    data: &ShareableMainloopData,
+    state: &Arc<RwLock<PipewireState>>,
+    registry: &Registry,
+) {
+    let proxy: pwnode::Node = match registry.bind(global) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            lock(data).report_error(PipewireError::ActionFailed("bind node", e));
+            return;
+        }
+    };
+    let listener = proxy
+        .add_listener_local()
+        .info({
+            let data = data.clone();
+            let state = state.clone();
+            move |info| {
+                if !info.change_mask().contains(pwnode::NodeChangeMask::PROPS) {
+                    return;
+                }
+                // The first info holds the same properties as the global, and
+                // is ignored as unchanged
+                match types::Node::from_node_info(info) {
+                    Ok(node) => process_message(
+                        PipewireMessage::ObjectChanged(Object::Node(node)),
+                        &data,
+                        &state,
+                    ),
+                    Err(e) if e.is_system_object() => {}
+                    Err(e) => lock(&data).report_error(e),
+                }
+            }
+        })
+        .register();
+    lock(data)
+        .watched_nodes
+        .insert(global.id, (proxy, listener));
+}
+
 fn process_message(
     message: PipewireMessage,
     data: &ShareableMainloopData,
-    state: &Arc<Mutex<PipewireState>>,
+    state: &Arc<RwLock<PipewireState>>,
 ) {
-    let data_lock = data.lock().unwrap();
-    let result = state.lock().unwrap().process_message(message);
-    if let (Some(sender), Some(result)) = (&data_lock.message_sender, result) {
-        sender.send(result).unwrap();
+    let data_lock = lock(data);
+    // The only place the state is written to, everything else only reads it
+    let results = state
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .process_message(message);
+    data_lock.state_changed.notify();
+    let sender = match &data_lock.message_sender {
+        Some(sender) => sender,
+        None => return,
+    };
+    for result in results {
+        let object = match &result {
+            PipeswitchMessage::NewObject(object) => Some(object),
+            PipeswitchMessage::ObjectRemoved(object) => Some(object),
+            PipeswitchMessage::ObjectChanged(_, object) => Some(object),
+            PipeswitchMessage::DefaultChanged(_)
+            | PipeswitchMessage::LinksCreated(..)
+            | PipeswitchMessage::Disconnected
+            | PipeswitchMessage::Reconnected
+            | PipeswitchMessage::Error(_) => None,
+        };
+        if let (Some(filter), Some(object)) = (&data_lock.message_filter, object) {
+            if !filter.contains(&object.object_type()) {
+                continue;
+            }
+        }
+        // The receiver is gone when the Pipeswitch is being dropped
+        let _ = sender.send(result);
     }
 }
@@ -1,14 +1,29 @@
 use pipewire::types::ObjectType;
-use std::{collections::HashMap, num::ParseIntError, str::ParseBoolError};
+use serde::{Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::ParseIntError,
+    str::ParseBoolError,
+    sync::{Condvar, Mutex, RwLock},
+    time::Instant,
+};
 use thiserror::Error;
 
+#[cfg(any(test, feature = "test-util"))]
+mod builder;
 pub(crate) mod mainloop;
+mod pw_dump;
 pub mod types;
-use types::VERSION;
+use types::MIN_VERSION;
 
 use crate::PipeswitchMessage;
 
-use self::types::{Client, Factory, Link, Node, Object, Port};
+#[cfg(any(test, feature = "test-util"))]
+pub use builder::StateBuilder;
+
+use self::types::{
+    Channel, Client, DefaultNode, Direction, Factory, Link, Node, Object, Port, PortKind,
+};
 
 #[derive(Error, Debug)]
 pub enum PipewireError {
@@ -18,28 +33,57 @@ pub enum PipewireError {
     ParseBoolError(#[from] ParseBoolError),
     #[error("property '{3}' not found in object {0} of type {1}: {2:?}")]
     PropNotFound(u32, ObjectType, HashMap<String, String>, &'static str),
-    #[error("object version invalid, expected {VERSION}, got {0}")]
+    #[error("object version invalid, expected at least {MIN_VERSION}, got {0}")]
     InvalidVersion(u32),
     #[error("globalobject does not have properties: {1} ({0}) {2:?}")]
     MissingProps(u32, ObjectType, HashMap<String, String>),
     #[error("direction not valid: {0}")]
     InvalidDirection(String),
-    #[error("direction not valid: {0}")]
+    #[error("channel not valid: {0}")]
     InvalidChannel(String),
     #[error("error with core pipewire interface: {0}")]
     PipewireInterfaceError(#[from] pipewire::Error),
+    #[error("failed to {0}: {1}")]
+    ActionFailed(&'static str, pipewire::Error),
+    #[error("invalid pw-dump: {0}")]
+    InvalidDump(String),
     #[error("tried to delete a global object that was not yet registered: {0}")]
     GlobalObjectNotRegistered(u32),
+    #[error("not connected to PipeWire")]
+    Disconnected,
+    #[error("the default metadata has not been found")]
+    NoDefaultMetadata,
     #[cfg(debug_assertions)]
     #[error("unknown error")]
     Unknown,
 }
 
+impl PipewireError {
+    /// Whether the error is about an object PipeWire makes for itself, ie. the
+    /// `Dummy-Driver` node, lacking properties it never has. Such objects are
+    /// never matched and can be skipped, unlike ones that are malformed.
+    pub fn is_system_object(&self) -> bool {
+        match self {
+            PipewireError::PropNotFound(_, _, props, _) => types::is_system_object(props),
+            _ => false,
+        }
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 enum PipewireMessage {
-    NewGlobal(u32, ObjectType, Object),
+    /// Along with the `object.serial`, which unlike the id is never reused
+    NewGlobal(u32, ObjectType, Object, Option<u64>),
     GlobalRemoved(u32),
+    /// The properties of an existing object were updated
+    ObjectChanged(Object),
+    /// Name of the new default node, if any
+    DefaultChanged(DefaultNode, Option<String>),
+    /// The connection to PipeWire was lost, and every object with it
+    Disconnected,
+    /// Connected to PipeWire again, and its globals have been received
+    Reconnected,
 }
 
 #[derive(Debug, Default)]
@@ -50,53 +94,501 @@ pub struct PipewireState {
     pub links: HashMap<u32, Link>,
     pub clients: HashMap<u32, Client>,
     pub factories: HashMap<String, Factory>,
+    /// Node names of the current default sink and source
+    pub default_sink: Option<String>,
+    pub default_source: Option<String>,
+    /// Port ids of each node id, kept in sync with `ports`
+    node_ports: HashMap<u32, HashSet<u32>>,
+    /// `object.serial` of each id in `object_types` that has one
+    serials: HashMap<u32, u64>,
 }
 
 impl PipewireState {
-    fn process_message(&mut self, message: PipewireMessage) -> Option<PipeswitchMessage> {
+    /// Applies the message to the state, giving what changed for the users of
+    /// the state to hear about, in the order it happened
+    fn process_message(&mut self, message: PipewireMessage) -> Vec<PipeswitchMessage> {
+        let mut messages = Vec::new();
         match message {
-            PipewireMessage::NewGlobal(id, obj_type, object) => {
+            PipewireMessage::NewGlobal(id, obj_type, mut object, serial) => {
+                let stale = self
+                    .object_types
+                    .get(&id)
+                    .filter(|old| **old != obj_type)
+                    .cloned();
+                if let Some(old_type) = stale {
+                    // PipeWire reused the id, and its removal never arrived
+                    let old_serial = describe_serial(self.serials.get(&id).copied());
+                    let new_serial = describe_serial(serial);
+                    log::warn!(
+                        "id {id} reused for a {obj_type} ({new_serial}) while a {old_type} \
+                        ({old_serial}) still had it, forgetting the stale {old_type}"
+                    );
+                    // The stale object is gone for the users of the state as well
+                    if let Some(old) = self.remove_object(id, old_type) {
+                        messages.push(PipeswitchMessage::ObjectRemoved(old));
+                    }
+                }
                 self.object_types.insert(id, obj_type);
+                match serial {
+                    Some(serial) => drop(self.serials.insert(id, serial)),
+                    None => drop(self.serials.remove(&id)),
+                }
+                if let Object::Link(link) = &mut object {
+                    self.fill_port_aliases(link);
+                }
                 match object.clone() {
-                    Object::Port(port) => drop(self.ports.insert(id, port)),
-                    Object::Node(node) => drop(self.nodes.insert(node.id, node)),
+                    Object::Port(port) => {
+                        let node_id = port.node_id;
+                        if let Some(old) = self.ports.insert(id, port) {
+                            remove_from_node_index(&mut self.node_ports, &old);
+                        }
+                        self.node_ports.entry(node_id).or_default().insert(id);
+                    }
+                    Object::Node(node) => drop(self.nodes.insert(id, node)),
                     Object::Link(link) => {
-                        drop(self.links.insert(link.id, link));
+                        drop(self.links.insert(id, link));
                     }
-                    Object::Client(client) => drop(self.clients.insert(client.id, client)),
+                    Object::Client(client) => drop(self.clients.insert(id, client)),
                     Object::Factory(factory) => {
                         drop(self.factories.insert(factory.type_name.clone(), factory))
                     }
                 }
-                Some(PipeswitchMessage::NewObject(object))
+                messages.push(PipeswitchMessage::NewObject(object));
             }
             PipewireMessage::GlobalRemoved(id) => {
-                if let Some(obj_type) = self.object_types.get(&id) {
-                    match obj_type {
-                        ObjectType::Port => self.ports.remove(&id).map(Object::Port),
-                        ObjectType::Node => self.nodes.remove(&id).map(Object::Node),
-                        ObjectType::Link => self.links.remove(&id).map(Object::Link),
-                        ObjectType::Client => self.clients.remove(&id).map(Object::Client),
-                        _ => None,
+                if let Some(obj_type) = self.object_types.remove(&id) {
+                    self.serials.remove(&id);
+                    messages.push(match self.remove_object(id, obj_type) {
+                        Some(obj) => PipeswitchMessage::ObjectRemoved(obj),
+                        None => {
+                            PipeswitchMessage::Error(PipewireError::GlobalObjectNotRegistered(id))
+                        }
+                    });
+                }
+            }
+            PipewireMessage::ObjectChanged(object) => match object {
+                Object::Node(node) => match self.nodes.get_mut(&node.id) {
+                    Some(old) if *old != node => {
+                        let old = Object::Node(std::mem::replace(old, node.clone()));
+                        messages.push(PipeswitchMessage::ObjectChanged(old, Object::Node(node)));
                     }
-                    .map(|obj| Some(PipeswitchMessage::ObjectRemoved(obj)))
-                    .unwrap_or(Some(PipeswitchMessage::Error(
-                        PipewireError::GlobalObjectNotRegistered(id),
-                    )))
+                    _ => {}
+                },
+                // Only the state of a link changes
+                Object::Link(link) => match self.links.get_mut(&link.id) {
+                    Some(old) if old.state != link.state => {
+                        let previous = Object::Link(old.clone());
+                        old.state = link.state;
+                        let new = Object::Link(old.clone());
+                        messages.push(PipeswitchMessage::ObjectChanged(previous, new));
+                    }
+                    _ => {}
+                },
+                // Only nodes and links are watched for changes
+                _ => {}
+            },
+            PipewireMessage::DefaultChanged(kind, name) => {
+                let default = match kind {
+                    DefaultNode::Sink => &mut self.default_sink,
+                    DefaultNode::Source => &mut self.default_source,
+                };
+                if *default != name {
+                    *default = name;
+                    messages.push(PipeswitchMessage::DefaultChanged(kind));
+                }
+            }
+            PipewireMessage::Disconnected => {
+                *self = PipewireState::default();
+                messages.push(PipeswitchMessage::Disconnected);
+            }
+            PipewireMessage::Reconnected => messages.push(PipeswitchMessage::Reconnected),
+        }
+        messages
+    }
+
+    /// Takes the object out of the map of its type, keeping the node index in
+    /// sync
+    fn remove_object(&mut self, id: u32, obj_type: ObjectType) -> Option<Object> {
+        match obj_type {
+            ObjectType::Port => self.ports.remove(&id).map(|port| {
+                remove_from_node_index(&mut self.node_ports, &port);
+                Object::Port(port)
+            }),
+            ObjectType::Node => self.nodes.remove(&id).map(Object::Node),
+            ObjectType::Link => self.links.remove(&id).map(Object::Link),
+            ObjectType::Client => self.clients.remove(&id).map(Object::Client),
+            // Factories are kept by their type name, not by id
+            ObjectType::Factory => {
+                let type_name = self
+                    .factories
+                    .values()
+                    .find(|factory| factory.id == id)
+                    .map(|factory| factory.type_name.clone())?;
+                self.factories.remove(&type_name).map(Object::Factory)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sets the port aliases of the link, for the ports that are known
+    pub fn fill_port_aliases(&self, link: &mut Link) {
+        let alias = |id| self.ports.get(&id).map(|port: &Port| port.alias.clone());
+        link.output_alias = alias(link.output_port);
+        link.input_alias = alias(link.input_port);
+    }
+
+    /// Every link from or to the port
+    pub fn links_for_port(&self, port_id: u32) -> Vec<&Link> {
+        self.links
+            .values()
+            .filter(|link| link.output_port == port_id || link.input_port == port_id)
+            .collect()
+    }
+
+    /// Ids of the ports linked to the port, whichever direction it has
+    pub fn connected_ports(&self, port_id: u32) -> Vec<u32> {
+        self.links_for_port(port_id)
+            .into_iter()
+            .map(|link| {
+                if link.output_port == port_id {
+                    link.input_port
                 } else {
-                    None
+                    link.output_port
                 }
+            })
+            .collect()
+    }
+
+    /// Whether audio from the first node already flows to the second one,
+    /// following the links from node to node
+    pub fn node_reaches(&self, from: u32, to: u32) -> bool {
+        let mut visited = HashSet::new();
+        let mut queue = vec![from];
+        while let Some(node_id) = queue.pop() {
+            if node_id == to {
+                return true;
+            }
+            if visited.insert(node_id) {
+                queue.extend(
+                    self.links
+                        .values()
+                        .filter(|link| link.output_node == node_id)
+                        .map(|link| link.input_node),
+                );
             }
         }
+        false
+    }
+
+    /// Name of the current default node of the given kind
+    pub fn default_node_name(&self, kind: DefaultNode) -> Option<&str> {
+        match kind {
+            DefaultNode::Sink => self.default_sink.as_deref(),
+            DefaultNode::Source => self.default_source.as_deref(),
+        }
+    }
+
+    /// Id of the current default node of the given kind, once it exists
+    pub fn default_node_id(&self, kind: DefaultNode) -> Option<u32> {
+        let name = self.default_node_name(kind)?;
+        self.nodes
+            .values()
+            .find(|node| node.node_name == name)
+            .map(|node| node.id)
     }
 
     pub fn ports_by_node(&self, node_id: u32) -> Vec<&Port> {
-        let mut vec = Vec::new();
-        for port in self.ports.values() {
-            if port.node_id == node_id {
-                vec.push(port);
+        match self.node_ports.get(&node_id) {
+            Some(port_ids) => port_ids
+                .iter()
+                .filter_map(|id| self.ports.get(id))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Channels of the node's input or output audio ports in the order of the
+    /// ports on the node, each channel only once
+    pub fn channel_layout(&self, node_id: u32, direction: Direction) -> Vec<Channel> {
+        let mut ports = self.ports_by_node(node_id);
+        ports.sort_by_key(|port| (port.local_port_id, port.id));
+        let mut layout = Vec::new();
+        for port in ports {
+            let same_direction = matches!(
+                (&port.direction, &direction),
+                (Direction::Input, Direction::Input) | (Direction::Output, Direction::Output)
+            );
+            let audio = port.kind == PortKind::Audio;
+            if same_direction && audio && !layout.contains(&port.channel) {
+                layout.push(port.channel.clone());
+            }
+        }
+        layout
+    }
+}
+
+/// Wakes up the threads waiting for something to appear in the
+/// [`PipewireState`], every time the mainloop has written to it
+#[derive(Debug, Default)]
+pub(crate) struct StateChanged {
+    lock: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl StateChanged {
+    /// Called by the mainloop once the state is no longer locked for writing
+    pub(crate) fn notify(&self) {
+        // Taken so that a waiter can not miss a change between checking the
+        // state and starting to wait
+        let _lock = self.lock.lock().unwrap();
+        self.condvar.notify_all();
+    }
+
+    /// Checks the state every time it changes, until `check` finds something
+    /// or the deadline passes
+    pub(crate) fn wait_until<T>(
+        &self,
+        state: &RwLock<PipewireState>,
+        deadline: Instant,
+        mut check: impl FnMut(&PipewireState) -> Option<T>,
+    ) -> Option<T> {
+        let mut lock = self.lock.lock().unwrap();
+        loop {
+            if let Some(found) = check(&state.read().unwrap()) {
+                return Some(found);
             }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            lock = self.condvar.wait_timeout(lock, remaining).unwrap().0;
         }
-        vec
+    }
+}
+
+/// A view of [`PipewireState`] ordered by id, so that serializing the same
+/// graph always gives the same output
+#[derive(Debug, Serialize)]
+pub struct StateSnapshot<'a> {
+    pub clients: BTreeMap<u32, &'a Client>,
+    pub nodes: BTreeMap<u32, &'a Node>,
+    pub ports: BTreeMap<u32, &'a Port>,
+    pub links: BTreeMap<u32, &'a Link>,
+    pub factories: BTreeMap<u32, &'a Factory>,
+    pub default_sink: Option<&'a str>,
+    pub default_source: Option<&'a str>,
+}
+
+impl PipewireState {
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            clients: self.clients.iter().map(|(id, c)| (*id, c)).collect(),
+            nodes: self.nodes.iter().map(|(id, n)| (*id, n)).collect(),
+            ports: self.ports.iter().map(|(id, p)| (*id, p)).collect(),
+            links: self.links.iter().map(|(id, l)| (*id, l)).collect(),
+            factories: self.factories.values().map(|f| (f.id, f)).collect(),
+            default_sink: self.default_sink.as_deref(),
+            default_source: self.default_source.as_deref(),
+        }
+    }
+}
+
+impl Serialize for PipewireState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+/// Parses the `object.serial` property, which not every PipeWire version sets
+fn parse_serial(serial: Option<&str>) -> Option<u64> {
+    serial?.parse().ok()
+}
+
+fn describe_serial(serial: Option<u64>) -> String {
+    match serial {
+        Some(serial) => format!("serial {serial}"),
+        None => "no serial".to_owned(),
+    }
+}
+
+fn remove_from_node_index(node_ports: &mut HashMap<u32, HashSet<u32>>, port: &Port) {
+    if let Some(port_ids) = node_ports.get_mut(&port.node_id) {
+        port_ids.remove(&port.id);
+        if port_ids.is_empty() {
+            node_ports.remove(&port.node_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A port of the node, taken from a state built around it
+    fn port(id: u32, node_id: u32) -> Port {
+        let mut state = StateBuilder::new()
+            .port(id, node_id, "playback_FL", Direction::Input, Channel::Left)
+            .build()
+            .unwrap();
+        state.ports.remove(&id).unwrap()
+    }
+
+    fn node(id: u32) -> Node {
+        let mut state = StateBuilder::new().node(id, 1, "node").build().unwrap();
+        state.nodes.remove(&id).unwrap()
+    }
+
+    fn add(state: &mut PipewireState, object: Object) {
+        let (id, object_type) = match &object {
+            Object::Port(port) => (port.id, ObjectType::Port),
+            Object::Node(node) => (node.id, ObjectType::Node),
+            _ => unreachable!(),
+        };
+        state.process_message(PipewireMessage::NewGlobal(id, object_type, object, None));
+    }
+
+    fn remove(state: &mut PipewireState, id: u32) {
+        state.process_message(PipewireMessage::GlobalRemoved(id));
+    }
+
+    /// Port ids of the node going by the index, and by looking at every port
+    fn indexed_and_scanned(state: &PipewireState, node_id: u32) -> (Vec<u32>, Vec<u32>) {
+        let mut indexed: Vec<u32> = state.ports_by_node(node_id).iter().map(|p| p.id).collect();
+        let mut scanned: Vec<u32> = state
+            .ports
+            .values()
+            .filter(|port| port.node_id == node_id)
+            .map(|port| port.id)
+            .collect();
+        indexed.sort_unstable();
+        scanned.sort_unstable();
+        (indexed, scanned)
+    }
+
+    #[test]
+    fn node_index_follows_port_churn() {
+        let mut state = PipewireState::default();
+        for round in 0..5 {
+            for id in 10..20 {
+                add(&mut state, Object::Port(port(id, 1 + id % 2)));
+            }
+            for id in (10..20).filter(|id| id % 3 == round % 3) {
+                remove(&mut state, id);
+            }
+            // A reused id may belong to another node
+            add(&mut state, Object::Port(port(10, 3)));
+            for node_id in 1..=3 {
+                let (indexed, scanned) = indexed_and_scanned(&state, node_id);
+                assert_eq!(indexed, scanned);
+            }
+        }
+        for id in 10..20 {
+            remove(&mut state, id);
+        }
+        assert!(state.node_ports.is_empty());
+    }
+
+    #[test]
+    fn removed_globals_leave_no_types_behind() {
+        let mut state = StateBuilder::new().client(1, "app").build().unwrap();
+        let baseline = state.object_types.len();
+        for round in 0..100 {
+            let node_id = 100 + round;
+            add(&mut state, Object::Node(node(node_id)));
+            add(&mut state, Object::Port(port(1000 + round, node_id)));
+            assert_eq!(state.nodes[&node_id].id, node_id);
+            remove(&mut state, 1000 + round);
+            remove(&mut state, node_id);
+        }
+        assert_eq!(state.object_types.len(), baseline);
+        assert!(state.nodes.is_empty() && state.ports.is_empty());
+    }
+
+    #[test]
+    fn connected_ports_go_both_ways() {
+        use Direction::{Input, Output};
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(2, 1, "source")
+            .port(3, 2, "capture_FL", Output, Channel::Left)
+            .port(4, 2, "capture_FR", Output, Channel::Right)
+            .node(5, 1, "sink")
+            .port(6, 5, "playback_FL", Input, Channel::Left)
+            .port(7, 5, "playback_FR", Input, Channel::Right)
+            .link(8, 3, 6)
+            .link(9, 3, 7)
+            .link(10, 4, 7)
+            .build()
+            .unwrap();
+        let sorted = |mut ids: Vec<u32>| {
+            ids.sort_unstable();
+            ids
+        };
+        let link_ids = |port_id| {
+            let links = state.links_for_port(port_id);
+            sorted(links.iter().map(|link| link.id).collect())
+        };
+        assert_eq!(link_ids(3), [8, 9]);
+        assert_eq!(link_ids(7), [9, 10]);
+        assert_eq!(sorted(state.connected_ports(3)), [6, 7]);
+        assert_eq!(sorted(state.connected_ports(7)), [3, 4]);
+        assert_eq!(state.connected_ports(6), [3]);
+        assert!(state.connected_ports(2).is_empty());
+    }
+
+    #[test]
+    fn channel_layout_follows_the_ports_of_the_node() {
+        use Direction::{Input, Output};
+        // Global ids in another order than the ports have on the node
+        let graph = || {
+            StateBuilder::new()
+                .client(1, "app")
+                .node(2, 1, "surround")
+                .port(40, 2, "playback_FC", Input, Channel::FrontCenter)
+                .with(&[("port.id", "2")])
+                .port(11, 2, "playback_FR", Input, Channel::Right)
+                .with(&[("port.id", "1")])
+                .port(27, 2, "playback_FL", Input, Channel::Left)
+                .with(&[("port.id", "0")])
+                .port(30, 2, "monitor_FL", Output, Channel::Left)
+                .build()
+                .unwrap()
+        };
+        let layout = [Channel::Left, Channel::Right, Channel::FrontCenter];
+        for _ in 0..10 {
+            let state = graph();
+            assert_eq!(state.channel_layout(2, Input), layout);
+            assert_eq!(state.channel_layout(2, Output), [Channel::Left]);
+        }
+    }
+
+    #[test]
+    fn factories_are_removed_by_id() {
+        let factory = [
+            ("factory.name", "link-factory"),
+            ("factory.type.name", "Link"),
+        ];
+        let mut state = StateBuilder::new()
+            .object(ObjectType::Factory, 4, &factory)
+            .build()
+            .unwrap();
+        remove(&mut state, 4);
+        assert!(state.factories.is_empty());
+        // A node taking over the id of a factory whose removal was missed
+        let mut state = StateBuilder::new()
+            .object(ObjectType::Factory, 4, &factory)
+            .build()
+            .unwrap();
+        let messages = state.process_message(PipewireMessage::NewGlobal(
+            4,
+            ObjectType::Node,
+            Object::Node(node(4)),
+            None,
+        ));
+        assert!(state.factories.is_empty());
+        assert!(matches!(
+            messages[0],
+            PipeswitchMessage::ObjectRemoved(Object::Factory(_))
+        ));
     }
 }
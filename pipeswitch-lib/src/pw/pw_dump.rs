@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use pipewire::keys::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{
+    parse_serial,
+    types::{Client, Factory, Link, LinkState, Node, Object, Port},
+    PipewireError, PipewireMessage, PipewireState,
+};
+
+/// One object of the array printed by `pw-dump`
+#[derive(Deserialize)]
+struct DumpObject {
+    id: u32,
+    #[serde(rename = "type")]
+    type_name: String,
+    info: Option<DumpInfo>,
+}
+
+#[derive(Deserialize)]
+struct DumpInfo {
+    #[serde(default)]
+    props: HashMap<String, Value>,
+    #[serde(rename = "output-node-id")]
+    output_node_id: Option<u32>,
+    #[serde(rename = "output-port-id")]
+    output_port_id: Option<u32>,
+    #[serde(rename = "input-node-id")]
+    input_node_id: Option<u32>,
+    #[serde(rename = "input-port-id")]
+    input_port_id: Option<u32>,
+    /// State of a link, ie. `active`
+    state: Option<String>,
+    error: Option<String>,
+}
+
+impl PipewireState {
+    /// Builds the state from the JSON printed by `pw-dump`, ie. to reproduce
+    /// the graph of a bug report without its hardware. Objects of other types
+    /// than ports, nodes, links, clients and factories are skipped. So are
+    /// objects that can not be read, which are given along with the state, one
+    /// error each, unless they are objects PipeWire makes for itself.
+    pub fn from_pw_dump(json: &str) -> Result<(Self, Vec<PipewireError>), PipewireError> {
+        let objects: Vec<Value> =
+            serde_json::from_str(json).map_err(|e| PipewireError::InvalidDump(e.to_string()))?;
+
+        let mut state = PipewireState::default();
+        let mut errors = Vec::new();
+        for (index, object) in objects.into_iter().enumerate() {
+            let object: DumpObject = match serde_json::from_value(object) {
+                Ok(object) => object,
+                Err(e) => {
+                    errors.push(PipewireError::InvalidDump(format!("object {index}: {e}")));
+                    continue;
+                }
+            };
+            let info = match object.info {
+                Some(info) => info,
+                None => continue,
+            };
+            let mut props: HashMap<String, String> = info
+                .props
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(value) => (key, value),
+                    value => (key, value.to_string()),
+                })
+                .collect();
+            let id = object.id;
+            let object = match object.type_name.as_str() {
+                "PipeWire:Interface:Port" => Port::from_props(id, &props).map(Object::Port),
+                "PipeWire:Interface:Node" => Node::from_props(id, &props).map(Object::Node),
+                "PipeWire:Interface:Client" => Client::from_props(id, &props).map(Object::Client),
+                "PipeWire:Interface:Factory" => {
+                    Factory::from_props(id, &props).map(Object::Factory)
+                }
+                "PipeWire:Interface:Link" => {
+                    // The linked ids are part of the info rather than the props
+                    let ids = [
+                        (*LINK_OUTPUT_NODE, info.output_node_id),
+                        (*LINK_OUTPUT_PORT, info.output_port_id),
+                        (*LINK_INPUT_NODE, info.input_node_id),
+                        (*LINK_INPUT_PORT, info.input_port_id),
+                    ];
+                    for (key, value) in ids {
+                        if let Some(value) = value {
+                            props.insert(key.to_owned(), value.to_string());
+                        }
+                    }
+                    let state = info
+                        .state
+                        .as_deref()
+                        .and_then(|state| LinkState::from_name(state, info.error.clone()));
+                    Link::from_props(id, &props).map(|mut link| {
+                        if let Some(state) = state {
+                            link.state = state;
+                        }
+                        Object::Link(link)
+                    })
+                }
+                _ => continue,
+            };
+            let object = match object {
+                Ok(object) => object,
+                // ie. the Dummy-Driver node, which a live graph skips as well
+                Err(e) if e.is_system_object() => continue,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            let serial = parse_serial(props.get("object.serial").map(String::as_str));
+            let message = PipewireMessage::NewGlobal(id, object.object_type(), object, serial);
+            state.process_message(message);
+        }
+        Ok((state, errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn port(id: u32, direction: &str) -> Value {
+        json!({
+            "id": id,
+            "type": "PipeWire:Interface:Port",
+            "info": { "props": {
+                "port.id": 0,
+                "node.id": 1,
+                "port.name": format!("port_{id}"),
+                "port.alias": format!("port_{id}"),
+                "port.direction": direction,
+            } },
+        })
+    }
+
+    fn link(id: u32, state: &str, error: Option<&str>) -> Value {
+        json!({
+            "id": id,
+            "type": "PipeWire:Interface:Link",
+            "info": {
+                "props": { "factory.id": 20 },
+                "output-node-id": 1,
+                "output-port-id": 2,
+                "input-node-id": 1,
+                "input-port-id": 3,
+                "state": state,
+                "error": error,
+            },
+        })
+    }
+
+    #[test]
+    fn links_keep_their_state() {
+        let dump = json!([
+            port(2, "out"),
+            port(3, "in"),
+            link(4, "active", None),
+            link(5, "error", Some("no more input formats")),
+            link(6, "unheard of", None),
+        ]);
+        let (state, errors) = PipewireState::from_pw_dump(&dump.to_string()).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(state.links[&4].state, LinkState::Active);
+        let error = LinkState::Error("no more input formats".to_owned());
+        assert_eq!(state.links[&5].state, error);
+        // Parsed, but left in the state a new link starts out in
+        assert_eq!(state.links[&6].state, LinkState::Init);
+        let json = serde_json::to_value(&state.links[&5]).unwrap();
+        assert_eq!(json["state"], json!({ "error": "no more input formats" }));
+    }
+}
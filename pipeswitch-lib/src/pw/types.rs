@@ -1,23 +1,33 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{collections::HashMap, fmt};
 
 use pipewire::{
     keys::*,
-    link::LinkInfo,
+    link::{LinkInfo, LinkState as PwLinkState},
+    node::NodeInfo,
     registry::GlobalObject,
     spa::{ForeignDict, ReadableDict},
     types::ObjectType,
 };
 
-use super::PipewireError;
+use super::{PipewireError, PipewireState};
 
-pub const VERSION: u32 = 3;
+/// Oldest object version whose properties we know how to parse
+pub const MIN_VERSION: u32 = 3;
+/// Newest object version known to work, newer ones are parsed with a warning
+pub const MAX_VERSION: u32 = 4;
 pub const KEY_RULE_NAME: &str = "pipeswitch.rule.name";
+/// Name of a link made by a rule with a `link_name_template`
+pub const KEY_LINK_NAME: &str = "pipeswitch.link.name";
 
 type PwIdType = u32;
 
-#[derive(Debug, Clone)]
+/// Serialized as PipeWire names it, `in` or `out`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum Direction {
+    #[serde(rename = "in")]
     Input,
+    #[serde(rename = "out")]
     Output,
 }
 
@@ -32,25 +42,78 @@ impl Direction {
     }
 }
 
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Input => f.write_str("in"),
+            Direction::Output => f.write_str("out"),
+        }
+    }
+}
+
+/// Serialized as its PipeWire name, see [`Channel::name`]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Channel {
     Left,
     Right,
     Mono,
+    FrontCenter,
+    LowFrequency,
+    SideLeft,
+    SideRight,
+    RearLeft,
+    RearRight,
+    RearCenter,
+    FrontLeftCenter,
+    FrontRightCenter,
+    /// Auxiliary channel, `AUXn` in PipeWire
+    Aux(u32),
 }
 
 impl Channel {
     fn from_channel<T: Into<String>>(input: Option<T>) -> Result<Option<Self>, PipewireError> {
-        if let Some(input) = input {
-            let input = input.into();
-            Ok(Some(match input.as_str() {
-                "FL" => Channel::Left,
-                "FR" => Channel::Right,
-                "MONO" => Channel::Mono,
+        input.map(Channel::from_name).transpose()
+    }
+
+    /// Parses a PipeWire channel name, such as `FL` or `AUX3`
+    pub fn from_name<T: Into<String>>(input: T) -> Result<Self, PipewireError> {
+        let input = input.into();
+        Ok(match input.as_str() {
+            "FL" => Channel::Left,
+            "FR" => Channel::Right,
+            "MONO" => Channel::Mono,
+            "FC" => Channel::FrontCenter,
+            "LFE" => Channel::LowFrequency,
+            "SL" => Channel::SideLeft,
+            "SR" => Channel::SideRight,
+            "RL" => Channel::RearLeft,
+            "RR" => Channel::RearRight,
+            "RC" => Channel::RearCenter,
+            "FLC" => Channel::FrontLeftCenter,
+            "FRC" => Channel::FrontRightCenter,
+            _ => match input.strip_prefix("AUX").map(|n| n.parse()) {
+                Some(Ok(n)) => Channel::Aux(n),
                 _ => Err(PipewireError::InvalidChannel(input))?,
-            }))
-        } else {
-            Ok(None)
+            },
+        })
+    }
+
+    /// The PipeWire channel name, such as `FL` or `AUX3`
+    pub fn name(&self) -> String {
+        match self {
+            Channel::Left => "FL".to_owned(),
+            Channel::Right => "FR".to_owned(),
+            Channel::Mono => "MONO".to_owned(),
+            Channel::FrontCenter => "FC".to_owned(),
+            Channel::LowFrequency => "LFE".to_owned(),
+            Channel::SideLeft => "SL".to_owned(),
+            Channel::SideRight => "SR".to_owned(),
+            Channel::RearLeft => "RL".to_owned(),
+            Channel::RearRight => "RR".to_owned(),
+            Channel::RearCenter => "RC".to_owned(),
+            Channel::FrontLeftCenter => "FLC".to_owned(),
+            Channel::FrontRightCenter => "FRC".to_owned(),
+            Channel::Aux(n) => format!("AUX{n}"),
         }
     }
 
@@ -58,12 +121,80 @@ impl Channel {
         Ok(match input {
             0 => Channel::Left,
             1 => Channel::Right,
-            _ => Err(PipewireError::InvalidChannel(format!("port.id {input}")))?,
+            _ => Channel::Aux(input),
         })
     }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl Serialize for Channel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+/// What a port carries, told from its `format.dsp`. Serialized in lowercase,
+/// ie. `midi`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortKind {
+    Audio,
+    /// `8 bit raw midi`, or `32 bit raw UMP` on newer PipeWire versions
+    Midi,
+    Video,
+    /// Anything else, ie. control ports
+    Other,
+}
+
+impl PortKind {
+    /// Ports without a format are taken to be audio, like they always were
+    fn from_dsp(dsp: Option<&str>) -> Self {
+        match dsp {
+            None => PortKind::Audio,
+            Some(dsp) if dsp.ends_with("audio") => PortKind::Audio,
+            Some(dsp) if dsp.ends_with("midi") || dsp.ends_with("UMP") => PortKind::Midi,
+            Some(dsp) if dsp.ends_with("video") => PortKind::Video,
+            Some(_) => PortKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for PortKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortKind::Audio => f.write_str("audio"),
+            PortKind::Midi => f.write_str("midi"),
+            PortKind::Video => f.write_str("video"),
+            PortKind::Other => f.write_str("other"),
+        }
+    }
+}
+
+/// Which of the defaults set in the PipeWire metadata, ie. by the desktop's
+/// sound settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultNode {
+    /// `default.audio.sink`
+    Sink,
+    /// `default.audio.source`
+    Source,
+}
+
+impl DefaultNode {
+    pub fn metadata_key(&self) -> &'static str {
+        match self {
+            DefaultNode::Sink => "default.audio.sink",
+            DefaultNode::Source => "default.audio.source",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Port {
     pub id: PwIdType,
     /// Usually 0 or 1
@@ -71,36 +202,41 @@ pub struct Port {
     pub path: Option<String>,
     pub node_id: PwIdType,
     pub dsp: Option<String>,
+    pub kind: PortKind,
+    /// Only meaningful for audio ports, others get one from their port id
     pub channel: Channel,
     pub name: String,
     pub direction: Direction,
     pub alias: String,
     pub physical: Option<bool>,
     pub terminal: Option<bool>,
+    /// Set on the output ports of a sink that carry what is played into it
+    pub monitor: Option<bool>,
 }
 
 impl Port {
     pub fn from_global(global: &GlobalObject<ForeignDict>) -> Result<Self, PipewireError> {
-        let props = global.props.as_ref().ok_or_else(|| {
-            PipewireError::MissingProps(
-                global.id,
-                ObjectType::Port,
-                map_props(global.props.as_ref().unwrap()),
-            )
-        })?;
-        let get_prop = |property| props.get(property).map(|v| v.to_string());
+        Port::from_props(global.id, &global_props(global, ObjectType::Port)?)
+    }
+
+    /// Parses the object from its properties, ie. when they do not come from
+    /// PipeWire directly
+    pub fn from_props(id: u32, props: &HashMap<String, String>) -> Result<Self, PipewireError> {
+        let get_prop = |property: &str| props.get(property).cloned();
         let get_prop_or = |property| {
             get_prop(property).ok_or_else(|| {
-                PipewireError::PropNotFound(global.id, ObjectType::Port, map_props(props), property)
+                PipewireError::PropNotFound(id, ObjectType::Port, props.clone(), property)
             })
         };
         let local_port_id = get_prop_or(*PORT_ID)?.parse()?;
+        let dsp = get_prop(*FORMAT_DSP);
         Ok(Port {
-            id: global.id,
+            id,
             local_port_id,
             path: get_prop(*OBJECT_PATH),
             node_id: get_prop_or(*NODE_ID)?.parse()?,
-            dsp: get_prop(*FORMAT_DSP),
+            kind: PortKind::from_dsp(dsp.as_deref()),
+            dsp,
             channel: Channel::from_channel(get_prop(*AUDIO_CHANNEL))?
                 .unwrap_or(Channel::from_portid(local_port_id)?),
             name: get_prop_or(*PORT_NAME)?,
@@ -108,11 +244,23 @@ impl Port {
             alias: get_prop_or(*PORT_ALIAS)?,
             physical: get_prop(*PORT_PHYSICAL).map(|v| v.parse()).transpose()?,
             terminal: get_prop(*PORT_TERMINAL).map(|v| v.parse()).transpose()?,
+            monitor: get_prop(*PORT_MONITOR).map(|v| v.parse()).transpose()?,
         })
     }
 }
 
-#[derive(Debug, Clone)]
+/// `alias (channel, direction)`, or the kind instead of the channel when it is
+/// not an audio port
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            PortKind::Audio => write!(f, "{} ({}, {})", self.alias, self.channel, self.direction),
+            kind => write!(f, "{} ({kind}, {})", self.alias, self.direction),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Node {
     pub id: PwIdType,
     pub path: Option<String>,
@@ -127,26 +275,36 @@ pub struct Node {
     pub media_category: Option<String>,
     pub media_class: Option<String>,
     pub media_role: Option<String>,
+    /// Title of what a stream is playing, ie. the track or the browser tab,
+    /// which changes while the node exists
+    pub media_name: Option<String>,
 }
 
 impl Node {
     pub fn from_global(global: &GlobalObject<ForeignDict>) -> Result<Self, PipewireError> {
-        let props = global.props.as_ref().ok_or_else(|| {
-            PipewireError::MissingProps(
-                global.id,
-                ObjectType::Node,
-                map_props(global.props.as_ref().unwrap()),
-            )
+        Node::from_props(global.id, &global_props(global, ObjectType::Node)?)
+    }
+
+    /// Parses the updated properties of an existing node
+    pub fn from_node_info(node_info: &NodeInfo) -> Result<Self, PipewireError> {
+        let props = node_info.props().ok_or_else(|| {
+            PipewireError::MissingProps(node_info.id(), ObjectType::Node, HashMap::new())
         })?;
-        let get_prop = |property| props.get(property).map(|v| v.to_string());
+        Node::from_props(node_info.id(), &map_props(props))
+    }
+
+    /// Parses the object from its properties, ie. when they do not come from
+    /// PipeWire directly
+    pub fn from_props(id: u32, props: &HashMap<String, String>) -> Result<Self, PipewireError> {
+        let get_prop = |property: &str| props.get(property).cloned();
         let get_prop_or = |property| {
             get_prop(property).ok_or_else(|| {
-                PipewireError::PropNotFound(global.id, ObjectType::Node, map_props(props), property)
+                PipewireError::PropNotFound(id, ObjectType::Node, props.clone(), property)
             })
         };
 
         Ok(Node {
-            id: global.id,
+            id,
             path: get_prop(*OBJECT_PATH),
             factory_id: get_prop(*FACTORY_ID).map(|v| v.parse()).transpose()?,
             client_id: get_prop_or(*CLIENT_ID)?.parse()?,
@@ -159,11 +317,116 @@ impl Node {
             media_category: get_prop(*MEDIA_CATEGORY),
             media_class: get_prop(*MEDIA_CLASS),
             media_role: get_prop(*MEDIA_ROLE),
+            media_name: get_prop(*MEDIA_NAME),
         })
     }
+
+    /// Whether audio goes into the node, ie. `Audio/Sink` or a recording
+    /// stream, `Stream/Input/Audio`. Duplex nodes are neither sinks nor sources.
+    pub fn is_sink(&self) -> bool {
+        matches!(
+            self.media_class_parts().as_slice(),
+            [_, "Sink", ..] | ["Stream", "Input", ..]
+        )
+    }
+
+    /// Whether audio comes out of the node, ie. `Audio/Source`,
+    /// `Audio/Source/Virtual` or a playback stream, `Stream/Output/Audio`
+    pub fn is_source(&self) -> bool {
+        matches!(
+            self.media_class_parts().as_slice(),
+            [_, "Source", ..] | ["Stream", "Output", ..]
+        )
+    }
+
+    /// Whether audio both goes into and comes out of the node, `Audio/Duplex`
+    pub fn is_duplex(&self) -> bool {
+        matches!(self.media_class_parts().as_slice(), [_, "Duplex", ..])
+    }
+
+    fn media_class_parts(&self) -> Vec<&str> {
+        match &self.media_class {
+            Some(class) => class.split('/').collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of distinct channels among the audio ports of the node going in
+    /// the direction, ie. the outputs of a source or the inputs of a sink
+    pub fn channel_count(&self, state: &PipewireState, direction: Direction) -> usize {
+        state.channel_layout(self.id, direction).len()
+    }
+}
+
+/// `node_name [nick]`, or only the name if the node has no nick
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.node_nick {
+            Some(nick) => write!(f, "{} [{nick}]", self.node_name),
+            None => f.write_str(&self.node_name),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+/// How far PipeWire has got in setting up a link, serialized in lowercase.
+/// Links in the error state exist, but carry no audio.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkState {
+    /// With the message PipeWire gave
+    Error(String),
+    Unlinked,
+    Init,
+    Negotiating,
+    Allocating,
+    Paused,
+    Active,
+}
+
+impl LinkState {
+    fn from_info(state: PwLinkState) -> Self {
+        match state {
+            PwLinkState::Error(message) => LinkState::Error(message.to_owned()),
+            PwLinkState::Unlinked => LinkState::Unlinked,
+            PwLinkState::Init => LinkState::Init,
+            PwLinkState::Negotiating => LinkState::Negotiating,
+            PwLinkState::Allocating => LinkState::Allocating,
+            PwLinkState::Paused => LinkState::Paused,
+            PwLinkState::Active => LinkState::Active,
+        }
+    }
+
+    /// Parses the state as `pw-dump` names it, ie. `active`, along with its
+    /// error message if there is one
+    pub fn from_name(name: &str, error: Option<String>) -> Option<Self> {
+        Some(match name {
+            "error" => LinkState::Error(error.unwrap_or_default()),
+            "unlinked" => LinkState::Unlinked,
+            "init" => LinkState::Init,
+            "negotiating" => LinkState::Negotiating,
+            "allocating" => LinkState::Allocating,
+            "paused" => LinkState::Paused,
+            "active" => LinkState::Active,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Error(message) => write!(f, "error: {message}"),
+            LinkState::Unlinked => f.write_str("unlinked"),
+            LinkState::Init => f.write_str("init"),
+            LinkState::Negotiating => f.write_str("negotiating"),
+            LinkState::Allocating => f.write_str("allocating"),
+            LinkState::Paused => f.write_str("paused"),
+            LinkState::Active => f.write_str("active"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Link {
     pub id: PwIdType,
     pub factory_id: PwIdType,
@@ -173,6 +436,14 @@ pub struct Link {
     pub input_node: PwIdType,
     pub input_port: PwIdType,
     pub rule_name: Option<String>,
+    pub link_name: Option<String>,
+    /// Aliases of the linked ports, filled in from the current state when the
+    /// ports are known
+    pub output_alias: Option<String>,
+    pub input_alias: Option<String>,
+    /// Kept up to date as PipeWire negotiates the link
+    pub state: LinkState,
+    #[serde(skip)]
     pub(crate) proxy_id: u32,
 }
 
@@ -206,12 +477,56 @@ impl Link {
             input_node: link_info.input_node_id(),
             input_port: link_info.input_port_id(),
             rule_name: get_prop(KEY_RULE_NAME),
+            link_name: get_prop(KEY_LINK_NAME),
+            output_alias: None,
+            input_alias: None,
+            state: LinkState::from_info(link_info.state()),
             proxy_id,
         })
     }
+
+    /// Parses the link from its properties, which have to include the nodes
+    /// and ports it links. It has no proxy, so it can not be destroyed. Its
+    /// state is not part of the properties, and starts out as `init`.
+    pub fn from_props(id: u32, props: &HashMap<String, String>) -> Result<Self, PipewireError> {
+        let get_prop = |property: &str| props.get(property).cloned();
+        let get_prop_or = |property| {
+            get_prop(property).ok_or_else(|| {
+                PipewireError::PropNotFound(id, ObjectType::Link, props.clone(), property)
+            })
+        };
+        Ok(Link {
+            id,
+            factory_id: get_prop_or(*FACTORY_ID)?.parse()?,
+            client_id: get_prop(*CLIENT_ID).map(|v| v.parse()).transpose()?,
+            output_node: get_prop_or(*LINK_OUTPUT_NODE)?.parse()?,
+            output_port: get_prop_or(*LINK_OUTPUT_PORT)?.parse()?,
+            input_node: get_prop_or(*LINK_INPUT_NODE)?.parse()?,
+            input_port: get_prop_or(*LINK_INPUT_PORT)?.parse()?,
+            rule_name: get_prop(KEY_RULE_NAME),
+            link_name: get_prop(KEY_LINK_NAME),
+            output_alias: None,
+            input_alias: None,
+            state: LinkState::Init,
+            proxy_id: 0,
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
+/// `output_alias -> input_alias (id)`, with port ids in place of unknown aliases
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let port = |alias: &Option<String>, id: PwIdType| match alias {
+            Some(alias) => alias.clone(),
+            None => format!("port {id}"),
+        };
+        let output = port(&self.output_alias, self.output_port);
+        let input = port(&self.input_alias, self.input_port);
+        write!(f, "{output} -> {input} ({})", self.id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Client {
     pub id: PwIdType,
     pub module_id: PwIdType,
@@ -221,31 +536,26 @@ pub struct Client {
     pub gid: PwIdType,
     pub label: String,
     pub application_name: String,
+    pub binary: Option<String>,
 }
 
 impl Client {
     pub fn from_global(global: &GlobalObject<ForeignDict>) -> Result<Self, PipewireError> {
-        let props = global.props.as_ref().ok_or_else(|| {
-            PipewireError::MissingProps(
-                global.id,
-                ObjectType::Client,
-                map_props(global.props.as_ref().unwrap()),
-            )
-        })?;
-        let get_prop = |property| props.get(property).map(|v| v.to_string());
+        Client::from_props(global.id, &global_props(global, ObjectType::Client)?)
+    }
+
+    /// Parses the object from its properties, ie. when they do not come from
+    /// PipeWire directly
+    pub fn from_props(id: u32, props: &HashMap<String, String>) -> Result<Self, PipewireError> {
+        let get_prop = |property: &str| props.get(property).cloned();
         let get_prop_or = |property| {
             get_prop(property).ok_or_else(|| {
-                PipewireError::PropNotFound(
-                    global.id,
-                    ObjectType::Client,
-                    map_props(props),
-                    property,
-                )
+                PipewireError::PropNotFound(id, ObjectType::Client, props.clone(), property)
             })
         };
 
         Ok(Client {
-            id: global.id,
+            id,
             module_id: get_prop_or(*MODULE_ID)?.parse()?,
             protocol: get_prop_or(*PROTOCOL)?,
             pid: get_prop_or(*SEC_PID)?.parse()?,
@@ -253,11 +563,12 @@ impl Client {
             gid: get_prop_or(*SEC_GID)?.parse()?,
             label: get_prop_or(*SEC_LABEL)?,
             application_name: get_prop_or(*APP_NAME)?,
+            binary: get_prop(*APP_PROCESS_BINARY),
         })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Factory {
     pub id: PwIdType,
     pub module_id: PwIdType,
@@ -267,27 +578,21 @@ pub struct Factory {
 
 impl Factory {
     pub fn from_global(global: &GlobalObject<ForeignDict>) -> Result<Self, PipewireError> {
-        let props = global.props.as_ref().ok_or_else(|| {
-            PipewireError::MissingProps(
-                global.id,
-                ObjectType::Factory,
-                map_props(global.props.as_ref().unwrap()),
-            )
-        })?;
-        let get_prop = |property| props.get(property).map(|v| v.to_string());
+        Factory::from_props(global.id, &global_props(global, ObjectType::Factory)?)
+    }
+
+    /// Parses the object from its properties, ie. when they do not come from
+    /// PipeWire directly
+    pub fn from_props(id: u32, props: &HashMap<String, String>) -> Result<Self, PipewireError> {
+        let get_prop = |property: &str| props.get(property).cloned();
         let get_prop_or = |property| {
             get_prop(property).ok_or_else(|| {
-                PipewireError::PropNotFound(
-                    global.id,
-                    ObjectType::Factory,
-                    map_props(props),
-                    property,
-                )
+                PipewireError::PropNotFound(id, ObjectType::Factory, props.clone(), property)
             })
         };
 
         Ok(Factory {
-            id: global.id,
+            id,
             module_id: get_prop_or(*MODULE_ID)?.parse()?,
             name: get_prop_or(*FACTORY_NAME)?,
             type_name: get_prop_or(*FACTORY_TYPE_NAME)?,
@@ -295,7 +600,7 @@ impl Factory {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub enum Object {
     Port(Port),
     Node(Node),
@@ -306,22 +611,281 @@ pub enum Object {
 
 impl Object {
     pub fn from_global(global: &GlobalObject<ForeignDict>) -> Result<Option<Self>, PipewireError> {
-        if global.version != VERSION {
-            Err(PipewireError::InvalidVersion(global.version))?
+        check_version(global.id, global.version)?;
+        let object = match global.type_ {
+            ObjectType::Port => Port::from_global(global).map(Self::Port),
+            ObjectType::Node => Node::from_global(global).map(Self::Node),
+            ObjectType::Client => Client::from_global(global).map(Self::Client),
+            ObjectType::Factory => Factory::from_global(global).map(Self::Factory),
+            _ => return Ok(None),
+        };
+        match object {
+            Ok(object) => Ok(Some(object)),
+            Err(e) if e.is_system_object() => {
+                log::debug!("skipping object of PipeWire itself: {e}");
+                Ok(None)
+            }
+            Err(e) => Err(e),
         }
-        match global.type_ {
-            ObjectType::Port => Ok(Some(Self::Port(Port::from_global(global)?))),
-            ObjectType::Node => Ok(Some(Self::Node(Node::from_global(global)?))),
-            ObjectType::Client => Ok(Some(Self::Client(Client::from_global(global)?))),
-            ObjectType::Factory => Ok(Some(Self::Factory(Factory::from_global(global)?))),
-            _ => Ok(None),
+    }
+
+    pub fn object_type(&self) -> ObjectType {
+        match self {
+            Object::Port(_) => ObjectType::Port,
+            Object::Node(_) => ObjectType::Node,
+            Object::Link(_) => ObjectType::Link,
+            Object::Client(_) => ObjectType::Client,
+            Object::Factory(_) => ObjectType::Factory,
         }
     }
 }
 
+/// Fails on versions older than the properties we parse, and only warns about
+/// newer ones
+fn check_version(id: u32, version: u32) -> Result<(), PipewireError> {
+    if version < MIN_VERSION {
+        Err(PipewireError::InvalidVersion(version))?
+    } else if version > MAX_VERSION {
+        log::warn!("object {id} has unknown version {version}, parsing anyway");
+    }
+    Ok(())
+}
+
+/// Whether the properties belong to an object PipeWire makes for itself rather
+/// than for a client, like the `Dummy-Driver` and `Freewheel-Driver` nodes
+pub(crate) fn is_system_object(props: &HashMap<String, String>) -> bool {
+    let factory = props.get("factory.name").map(String::as_str);
+    let group = props.get("node.group").map(String::as_str);
+    factory == Some("support.node.driver")
+        || matches!(group, Some("pipewire.dummy" | "pipewire.freewheel"))
+}
+
+fn global_props(
+    global: &GlobalObject<ForeignDict>,
+    object_type: ObjectType,
+) -> Result<HashMap<String, String>, PipewireError> {
+    global
+        .props
+        .as_ref()
+        .map(map_props)
+        .ok_or_else(|| PipewireError::MissingProps(global.id, object_type, HashMap::new()))
+}
+
 fn map_props(props: &ForeignDict) -> HashMap<String, String> {
     props
         .iter()
         .map(|(v1, v2)| (v1.into(), v2.into()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use pipewire::{permissions::Permission, spa::WritableDict, Properties};
+
+    use super::*;
+
+    fn port_props(channel: Option<&str>, port_id: &str) -> HashMap<String, String> {
+        let mut props: HashMap<String, String> = [
+            (*PORT_ID, port_id),
+            (*NODE_ID, "1"),
+            (*PORT_NAME, "playback"),
+            (*PORT_ALIAS, "playback"),
+            (*PORT_DIRECTION, "in"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+        if let Some(channel) = channel {
+            props.insert(AUDIO_CHANNEL.to_owned(), channel.to_owned());
+        }
+        props
+    }
+
+    #[test]
+    fn newer_versions_are_parsed() {
+        for version in [MIN_VERSION, 4, MAX_VERSION + 1] {
+            assert!(check_version(1, version).is_ok());
+        }
+        let too_old = check_version(1, MIN_VERSION - 1);
+        assert!(matches!(too_old, Err(PipewireError::InvalidVersion(2))));
+    }
+
+    #[test]
+    fn objects_with_newer_properties_parse() {
+        // Properties version 4 objects carry along with the ones we read
+        let extra = [("object.serial", "40"), ("object.register", "false")];
+        let with_extra = |props: &[(&'static str, &'static str)]| {
+            props.iter().chain(&extra).copied().collect::<Vec<_>>()
+        };
+        let client = with_extra(&[(*APP_NAME, "app")]);
+        let node = with_extra(&[(*CLIENT_ID, "1"), (*NODE_NAME, "node")]);
+        let factory = with_extra(&[
+            (*FACTORY_NAME, "link-factory"),
+            (*FACTORY_TYPE_NAME, "Link"),
+        ]);
+        let state = crate::StateBuilder::new()
+            .object(ObjectType::Client, 1, &client)
+            .object(ObjectType::Node, 2, &node)
+            .object(ObjectType::Factory, 4, &factory)
+            .build()
+            .unwrap();
+        assert_eq!(state.clients[&1].application_name, "app");
+        assert_eq!(state.nodes[&2].node_name, "node");
+        assert_eq!(state.factories["Link"].id, 4);
+    }
+
+    /// A global as the registry announces it, whose properties are read from
+    /// `props` for as long as it lives
+    fn global(
+        id: u32,
+        type_: ObjectType,
+        version: u32,
+        props: &Properties,
+    ) -> GlobalObject<ForeignDict> {
+        // The dict belongs to `props`, which outlives the global in every test
+        let dict = unsafe { ForeignDict::from_ptr(props.get_dict_ptr()) };
+        GlobalObject {
+            id,
+            permissions: Permission::all(),
+            type_,
+            version,
+            props: Some(dict),
+        }
+    }
+
+    fn properties(props: &[(&str, &str)]) -> Properties {
+        let mut properties = Properties::new();
+        for (key, value) in props {
+            properties.insert(*key, *value);
+        }
+        properties
+    }
+
+    #[test]
+    fn version_4_globals_parse() {
+        let client = properties(&[
+            (*MODULE_ID, "0"),
+            (*PROTOCOL, "protocol-native"),
+            (*SEC_PID, "10"),
+            (*SEC_UID, "1000"),
+            (*SEC_GID, "1000"),
+            (*SEC_LABEL, "unconfined"),
+            (*APP_NAME, "app"),
+            ("object.serial", "40"),
+        ]);
+        let node = properties(&[(*CLIENT_ID, "1"), (*NODE_NAME, "node")]);
+        let port = port_props(Some("FL"), "0");
+        let port: Vec<(&str, &str)> = port
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let port = properties(&port);
+        let factory = properties(&[
+            (*MODULE_ID, "0"),
+            (*FACTORY_NAME, "link-factory"),
+            (*FACTORY_TYPE_NAME, "Link"),
+        ]);
+        let parse = |type_, props, version| Object::from_global(&global(5, type_, version, props));
+        let globals = [
+            (ObjectType::Client, &client),
+            (ObjectType::Node, &node),
+            (ObjectType::Port, &port),
+            (ObjectType::Factory, &factory),
+        ];
+        for (type_, props) in globals {
+            let object = parse(type_.clone(), props, 4).unwrap().unwrap();
+            assert_eq!(object.object_type(), type_);
+            let too_old = parse(type_, props, MIN_VERSION - 1);
+            assert!(matches!(too_old, Err(PipewireError::InvalidVersion(2))));
+        }
+    }
+
+    #[test]
+    fn surround_channels_round_trip() {
+        let names = [
+            "FL", "FR", "MONO", "FC", "LFE", "SL", "SR", "RL", "RR", "RC", "FLC", "FRC", "AUX0",
+            "AUX12",
+        ];
+        for name in names {
+            assert_eq!(Channel::from_name(name).unwrap().name(), name);
+        }
+        assert_eq!(Channel::from_name("LFE").unwrap(), Channel::LowFrequency);
+        assert!(Channel::from_name("AUX").is_err());
+        assert!(Channel::from_name("XYZ").is_err());
+    }
+
+    #[test]
+    fn port_channel_from_audio_channel() {
+        let port = Port::from_props(2, &port_props(Some("FC"), "2")).unwrap();
+        assert_eq!(port.channel, Channel::FrontCenter);
+        let port = Port::from_props(3, &port_props(Some("LFE"), "3")).unwrap();
+        assert_eq!(port.channel, Channel::LowFrequency);
+    }
+
+    #[test]
+    fn port_channel_from_port_id() {
+        let port = Port::from_props(2, &port_props(None, "1")).unwrap();
+        assert_eq!(port.channel, Channel::Right);
+        let port = Port::from_props(3, &port_props(None, "4")).unwrap();
+        assert_eq!(port.channel, Channel::Aux(4));
+    }
+
+    #[test]
+    fn media_class_tells_sinks_and_sources() {
+        let classes = [
+            "Audio/Sink",
+            "Audio/Source",
+            "Audio/Source/Virtual",
+            "Audio/Duplex",
+            "Stream/Output/Audio",
+            "Stream/Input/Audio",
+        ];
+        let mut builder = crate::StateBuilder::new().client(1, "app");
+        for (id, class) in (10..).zip(classes) {
+            builder = builder.node(id, 1, class).with(&[(*MEDIA_CLASS, class)]);
+        }
+        let state = builder.node(20, 1, "unknown").build().unwrap();
+        let kind = |id| {
+            let node = &state.nodes[&id];
+            (node.is_sink(), node.is_source(), node.is_duplex())
+        };
+        assert_eq!(kind(10), (true, false, false));
+        assert_eq!(kind(11), (false, true, false));
+        assert_eq!(kind(12), (false, true, false));
+        assert_eq!(kind(13), (false, false, true));
+        assert_eq!(kind(14), (false, true, false));
+        assert_eq!(kind(15), (true, false, false));
+        assert_eq!(kind(20), (false, false, false));
+    }
+
+    #[test]
+    fn channels_are_counted_per_direction() {
+        use Direction::{Input, Output};
+        let state = crate::StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "sink")
+            .port(11, 10, "playback_FL", Input, Channel::Left)
+            .port(12, 10, "playback_FR", Input, Channel::Right)
+            .port(13, 10, "monitor_FL", Output, Channel::Left)
+            .with(&[(*PORT_MONITOR, "true")])
+            .port(14, 10, "monitor_FR", Output, Channel::Right)
+            .with(&[(*PORT_MONITOR, "true")])
+            .node(20, 1, "headset")
+            .port(21, 20, "capture_MONO", Output, Channel::Mono)
+            .port(22, 20, "playback_FL", Input, Channel::Left)
+            .port(23, 20, "playback_FR", Input, Channel::Right)
+            .node(30, 1, "synth")
+            .port(31, 30, "output_FL", Output, Channel::Left)
+            .port(32, 30, "output_FR", Output, Channel::Right)
+            .port(33, 30, "midi_out", Output, Channel::FrontCenter)
+            .with(&[(*FORMAT_DSP, "8 bit raw midi")])
+            .node(40, 1, "portless")
+            .build()
+            .unwrap();
+        let count = |node, direction| state.nodes[&node].channel_count(&state, direction);
+        assert_eq!((count(10, Input), count(10, Output)), (2, 2));
+        assert_eq!((count(20, Input), count(20, Output)), (2, 1));
+        assert_eq!((count(30, Input), count(30, Output)), (0, 2));
+        assert_eq!((count(40, Input), count(40, Output)), (0, 0));
+    }
+}
@@ -1,22 +1,46 @@
-use anyhow::Result;
-use inotify::{Inotify, WatchMask};
+use anyhow::{anyhow, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
 use log::*;
 use pipeswitch_lib::config::Config;
 use pipeswitch_lib::{Pipeswitch, PipeswitchMessage};
-use std::path::Path;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::control::Command;
+use crate::rules::{overlapping_rules, rule_errors, same_direction_rules, validate_rules};
+
+/// How long the config file has to stay untouched before it is reloaded,
+/// also how often the stop flag of the listener is checked
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(200);
 
 #[derive(Debug)]
 pub enum Event {
     Pipeswitch(PipeswitchMessage),
     ConfigModified(Config),
+    Command(Command),
+    /// Rule name and the timer that elapsed
+    LinkDelayElapsed(String, u64),
+    /// Key of a removed port and the timer that elapsed
+    PortRemovalElapsed(String, u64),
+    /// Id of a link removed by someone else and the timer that elapsed
+    LinkRemovalElapsed(u32, u64),
+    /// Rule, output and input node whose channel layouts are compared again,
+    /// and the timer that elapsed
+    LayoutCheckElapsed(String, u32, u32, u64),
+    /// Links waited for the link factory for as long as they may, with the
+    /// timer that elapsed
+    LinkFactoryElapsed(u64),
+    /// Stop the daemon, ie. after SIGTERM
+    Shutdown,
 }
 
 pub fn load_config_or_default(path: &Path) -> Result<Config> {
-    Ok(if let Some((conf, _)) = Config::load_from(path)? {
+    Ok(if let Some((conf, _)) = Config::load_with_dropins(path)? {
         trace!("Found existing config");
         conf
     } else {
@@ -27,6 +51,45 @@ pub fn load_config_or_default(path: &Path) -> Result<Config> {
     })
 }
 
+/// Loads the config for a reload, failing if it is missing or any of its rules
+/// are invalid, so that the previous config can be kept instead
+pub fn load_valid_config(path: &Path) -> Result<Config> {
+    let (conf, _) =
+        Config::load_with_dropins(path)?.ok_or_else(|| anyhow!("config file is missing"))?;
+    validate_rules(&conf)?;
+    Ok(conf)
+}
+
+/// Loads the config and builds all of its rules without touching PipeWire,
+/// printing every error found. Returns whether the config is valid.
+pub fn check_config(path: &Path) -> bool {
+    let config = match Config::load_with_dropins(path) {
+        Ok(Some((conf, _))) => conf,
+        Ok(None) => {
+            eprintln!("{}: config file not found", path.display());
+            return false;
+        }
+        Err(e) => {
+            eprintln!("{}: {e}", path.display());
+            return false;
+        }
+    };
+    let errors = rule_errors(&config);
+    for e in &errors {
+        eprintln!("{}: {e}", path.display());
+    }
+    for warning in overlapping_rules(&config)
+        .into_iter()
+        .chain(same_direction_rules(&config))
+    {
+        eprintln!("{}: warning: {warning}", path.display());
+    }
+    if errors.is_empty() {
+        println!("{}: ok, {} rules", path.display(), config.links.len());
+    }
+    errors.is_empty()
+}
+
 pub struct ConfigListener {
     running: Arc<AtomicBool>,
     join_handle: Option<JoinHandle<()>>,
@@ -42,26 +105,106 @@ impl ConfigListener {
             move || {
                 let mut inotify =
                     Inotify::init().expect("Error while initializing inotify instance");
-                inotify
-                    .add_watch(&path, WatchMask::MODIFY)
-                    .expect("Failed to add file watch");
+                let mut file_watch = Some(
+                    inotify
+                        .add_watch(&path, WatchMask::MODIFY)
+                        .expect("Failed to add file watch"),
+                );
+                // Editors that save by renaming a new file over the config replace
+                // the file being watched, which is noticed from its directory
+                let file_name = path.file_name().map(ToOwned::to_owned);
+                let parent = match path.parent() {
+                    Some(parent) if parent != Path::new("") => parent,
+                    _ => Path::new("."),
+                };
+                let dir_mask = WatchMask::CREATE | WatchMask::MOVED_TO;
+                let dir_watch = match inotify.add_watch(parent, dir_mask) {
+                    Ok(watch) => Some(watch),
+                    Err(e) => {
+                        error!("Failed to watch config directory: {e}");
+                        None
+                    }
+                };
+                let dropin_dir = Config::dropin_dir(&path);
+                if dropin_dir.is_dir() {
+                    let mask = WatchMask::MODIFY
+                        | WatchMask::CREATE
+                        | WatchMask::DELETE
+                        | WatchMask::MOVED_FROM
+                        | WatchMask::MOVED_TO;
+                    if let Err(e) = inotify.add_watch(&dropin_dir, mask) {
+                        error!("Failed to watch drop-in config directory: {e}");
+                    }
+                }
+                // The configs it includes as of the last time it was loaded
+                let mut include_watches = Vec::new();
+                if let Ok(Some((config, _))) = Config::load_from(&path) {
+                    watch_included(&mut inotify, &mut include_watches, &config.included);
+                }
+                let mut modified = false;
+                // Poll instead of blocking, so that the thread can be stopped
                 while running.load(Ordering::Relaxed) {
+                    std::thread::sleep(RELOAD_DEBOUNCE);
                     let mut buffer = [0; 1024];
-                    let events = inotify
-                        .read_events_blocking(&mut buffer)
-                        .expect("Error while reading events");
-                    for _ in events {
-                        match load_config_or_default(&path) {
-                            Ok(cfg) => {
-                                sender
-                                    .send(Event::ConfigModified(cfg))
-                                    .expect("Failed to send ConfigModified");
+                    let mut changed = false;
+                    let mut replaced = false;
+                    match inotify.read_events(&mut buffer) {
+                        Ok(events) => {
+                            for event in events {
+                                if Some(&event.wd) == dir_watch.as_ref() {
+                                    // Only the config itself, not its neighbours
+                                    if event.name == file_name.as_deref() {
+                                        replaced = true;
+                                        changed = true;
+                                    }
+                                } else if event.mask.contains(EventMask::IGNORED) {
+                                    // The file was deleted or replaced
+                                    if Some(&event.wd) == file_watch.as_ref() {
+                                        file_watch = None;
+                                    }
+                                    // Watched again once the config is reloaded
+                                    let watches = include_watches.len();
+                                    include_watches.retain(|(wd, _)| *wd != event.wd);
+                                    if include_watches.len() != watches {
+                                        changed = true;
+                                    }
+                                } else {
+                                    changed = true;
+                                }
                             }
-                            Err(err) => {
-                                error!("Error loading updated config: {err}")
-                            }
-                        };
+                        }
+                        Err(e) if e.kind() != ErrorKind::WouldBlock => {
+                            error!("Error while reading config events: {e}")
+                        }
+                        _ => {}
+                    }
+                    if replaced {
+                        debug!("Config file was replaced, watching the new one");
+                        match inotify.add_watch(&path, WatchMask::MODIFY) {
+                            Ok(watch) => file_watch = Some(watch),
+                            Err(e) => error!("Failed to watch the replaced config file: {e}"),
+                        }
+                    }
+                    // Editors often write in several steps, wait until they are done
+                    if changed {
+                        modified = true;
+                        continue;
                     }
+                    if !modified {
+                        continue;
+                    }
+                    modified = false;
+                    match load_valid_config(&path) {
+                        Ok(cfg) => {
+                            watch_included(&mut inotify, &mut include_watches, &cfg.included);
+                            sender
+                                .send(Event::ConfigModified(cfg))
+                                .expect("Failed to send ConfigModified");
+                        }
+                        Err(err) => {
+                            error!("Error loading updated config, keeping the previous one: {err}")
+                        }
+                    };
                 }
             }
         });
@@ -72,6 +215,23 @@ impl ConfigListener {
     }
 }
 
+/// Watches the included configs that are not watched yet
+fn watch_included(
+    inotify: &mut Inotify,
+    watches: &mut Vec<(WatchDescriptor, PathBuf)>,
+    included: &[PathBuf],
+) {
+    for path in included {
+        if watches.iter().any(|(_, watched)| watched == path) {
+            continue;
+        }
+        match inotify.add_watch(path, WatchMask::MODIFY) {
+            Ok(watch) => watches.push((watch, path.clone())),
+            Err(e) => error!("Failed to watch included config {}: {e}", path.display()),
+        }
+    }
+}
+
 impl Drop for ConfigListener {
     fn drop(&mut self) {
         self.running.store(false, Ordering::Relaxed);
@@ -79,9 +239,12 @@ impl Drop for ConfigListener {
     }
 }
 
-pub fn start_pipeswitch_thread(sender: Sender<Event>) -> Result<(Pipeswitch, JoinHandle<()>)> {
+pub fn start_pipeswitch_thread(
+    sender: Sender<Event>,
+    remote: Option<&str>,
+) -> Result<(Pipeswitch, JoinHandle<()>)> {
     let (ps_sender, ps_receiver) = channel();
-    let ps = Pipeswitch::new(Some(ps_sender))?;
+    let ps = Pipeswitch::new(Some(ps_sender), remote)?;
     Ok((
         ps,
         std::thread::spawn(move || {
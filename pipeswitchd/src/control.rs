@@ -0,0 +1,76 @@
+use pipeswitch_lib::types::LinkState;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, Sender};
+
+use crate::config::Event;
+
+/// Name used for links created by hand through a control interface, so that
+/// they are not mistaken for links of a removed rule.
+pub const MANUAL_RULE_NAME: &str = "pipeswitch.manual";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleSummary {
+    pub name: String,
+    pub links: u32,
+    pub enabled: bool,
+    /// Whether the rule has every link it expects
+    pub connected: bool,
+}
+
+/// How many ports a rule currently matches and how many links it owns
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleStatus {
+    pub name: String,
+    pub enabled: bool,
+    /// Ports matching the source of the rule
+    pub outputs: u32,
+    /// Ports matching any of the sinks of the rule
+    pub inputs: u32,
+    pub links: u32,
+    /// Links of the rule that PipeWire failed to set up
+    #[serde(default)]
+    pub failed: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkSummary {
+    pub id: u32,
+    pub output_port: u32,
+    pub input_port: u32,
+    pub rule_name: Option<String>,
+    pub state: LinkState,
+}
+
+/// Requests sent to the daemon from outside, each carrying a channel for the
+/// answer.
+#[derive(Debug)]
+pub enum Command {
+    ListRules(Sender<Vec<RuleSummary>>),
+    ListLinks(Sender<Vec<LinkSummary>>),
+    /// Every rule, sorted by name
+    RuleStatus(Sender<Vec<RuleStatus>>),
+    ReloadConfig(Sender<Result<(), String>>),
+    CreateLink {
+        output: u32,
+        input: u32,
+        reply: Sender<Result<Option<u32>, String>>,
+    },
+    DestroyLink(u32, Sender<Result<bool, String>>),
+    /// Disabling a rule destroys its links until it is enabled again
+    SetRuleEnabled {
+        name: String,
+        enabled: bool,
+        reply: Sender<Result<(), String>>,
+    },
+    /// Links the rules of the named profile instead of the active one, or of
+    /// no profile at all
+    ActivateProfile(Option<String>, Sender<Result<(), String>>),
+}
+
+/// Sends a command to the daemon and waits for the answer. Returns `None` if
+/// the daemon is no longer listening.
+pub fn request<T>(sender: &Sender<Event>, command: impl FnOnce(Sender<T>) -> Command) -> Option<T> {
+    let (reply_sender, reply_receiver) = channel();
+    sender.send(Event::Command(command(reply_sender))).ok()?;
+    reply_receiver.recv().ok()
+}
@@ -0,0 +1,87 @@
+use std::sync::{mpsc::Sender, Mutex};
+
+use anyhow::Result;
+use zbus::{blocking::Connection, blocking::ConnectionBuilder, dbus_interface, fdo};
+
+use crate::config::Event;
+use crate::control::{self, Command};
+
+pub const BUS_NAME: &str = "net.teascade.Pipeswitch";
+pub const OBJECT_PATH: &str = "/net/teascade/Pipeswitch";
+
+struct ControlInterface {
+    sender: Mutex<Sender<Event>>,
+}
+
+impl ControlInterface {
+    fn request<T>(&self, command: impl FnOnce(Sender<T>) -> Command) -> fdo::Result<T> {
+        let sender = self.sender.lock().unwrap().clone();
+        control::request(&sender, command)
+            .ok_or_else(|| fdo::Error::Failed("daemon did not answer".to_owned()))
+    }
+}
+
+#[dbus_interface(name = "net.teascade.Pipeswitch1")]
+impl ControlInterface {
+    /// Returns the name and current link count of every rule
+    fn list_rules(&self) -> fdo::Result<Vec<(String, u32)>> {
+        Ok(self
+            .request(Command::ListRules)?
+            .into_iter()
+            .map(|r| (r.name, r.links))
+            .collect())
+    }
+
+    /// Returns the id, output port, input port and rule name of every link
+    fn list_links(&self) -> fdo::Result<Vec<(u32, u32, u32, String)>> {
+        Ok(self
+            .request(Command::ListLinks)?
+            .into_iter()
+            .map(|l| {
+                let rule_name = l.rule_name.unwrap_or_default();
+                (l.id, l.output_port, l.input_port, rule_name)
+            })
+            .collect())
+    }
+
+    fn reload_config(&self) -> fdo::Result<()> {
+        self.request(Command::ReloadConfig)?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Returns the id of the new link, or 0 if PipeWire did not create one
+    fn create_link(&self, output_port: u32, input_port: u32) -> fdo::Result<u32> {
+        self.request(|reply| Command::CreateLink {
+            output: output_port,
+            input: input_port,
+            reply,
+        })?
+        .map(|id| id.unwrap_or(0))
+        .map_err(fdo::Error::Failed)
+    }
+
+    fn destroy_link(&self, link_id: u32) -> fdo::Result<bool> {
+        self.request(|reply| Command::DestroyLink(link_id, reply))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Links the rules of the named profile instead of the active one, or of
+    /// no profile if the name is empty
+    fn activate_profile(&self, name: String) -> fdo::Result<()> {
+        let name = (!name.is_empty()).then_some(name);
+        self.request(|reply| Command::ActivateProfile(name, reply))?
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+/// Claims the bus name on the session bus and serves the control interface
+/// until the returned connection is dropped.
+pub fn start(sender: Sender<Event>) -> Result<Connection> {
+    let interface = ControlInterface {
+        sender: Mutex::new(sender),
+    };
+    Ok(ConnectionBuilder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, interface)?
+        .build()?)
+}
@@ -0,0 +1,15 @@
+use anyhow::Result;
+use pipeswitch_lib::Pipeswitch;
+
+/// Connects to PipeWire, waits for the existing graph and prints it as JSON,
+/// ordered by id so that dumps can be diffed
+pub fn dump_graph() -> Result<()> {
+    let pipeswitch = Pipeswitch::new(None, None)?;
+    pipeswitch.roundtrip()?;
+    // Links are bound during the first roundtrip, their info arrives on the second
+    pipeswitch.roundtrip()?;
+
+    let state = pipeswitch.lock_current_state();
+    println!("{}", serde_json::to_string_pretty(&*state)?);
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use pipeswitch_lib::{
+    config::{ConfigBuilder, Distribute, LinkConfig, MatchMode, NodeOrTarget, Pairing, Target},
+    types::Port,
+    Pipeswitch, PipewireState,
+};
+
+/// Connects to PipeWire, waits for the existing graph and prints a config with
+/// a rule recreating each of its links
+pub fn export_links() -> Result<()> {
+    let pipeswitch = Pipeswitch::new(None, None)?;
+    pipeswitch.roundtrip()?;
+    // Links are bound during the first roundtrip, their info arrives on the second
+    pipeswitch.roundtrip()?;
+
+    // New rules are written in the order of their names, so that exports can
+    // be diffed
+    let mut builder = ConfigBuilder::new()?;
+    for (name, link) in links_as_rules(&pipeswitch.lock_current_state()) {
+        builder = builder.rule(&name, link);
+    }
+    let (_, exported) = builder.build()?;
+    print!("{exported}");
+    Ok(())
+}
+
+/// Rules matching exactly the ports of each link, named after them
+fn links_as_rules(state: &PipewireState) -> HashMap<String, LinkConfig> {
+    // Sorted by name first, so that the same graph always gets the same names
+    let mut rules = BTreeMap::new();
+    for link in state.links.values() {
+        let (output, input) = match (
+            state.ports.get(&link.output_port),
+            state.ports.get(&link.input_port),
+        ) {
+            (Some(output), Some(input)) => (output, input),
+            _ => continue,
+        };
+        let ((source, output_node), (sink, input_node)) =
+            match (port_target(output, state), port_target(input, state)) {
+                (Some(source), Some(sink)) => (source, sink),
+                _ => continue,
+            };
+        let name = format!(
+            "{output_node}_{}_to_{input_node}_{}",
+            output.name, input.name
+        );
+        let monitor = output.monitor == Some(true);
+        rules.insert(
+            (rule_name(&name), output.id, input.id),
+            (source, sink, monitor),
+        );
+    }
+
+    let mut links = HashMap::new();
+    for ((name, _, _), (source, sink, monitor)) in rules {
+        let mut unique_name = name.clone();
+        let mut n = 1;
+        while links.contains_key(&unique_name) {
+            n += 1;
+            unique_name = format!("{name}_{n}");
+        }
+        links.insert(
+            unique_name,
+            LinkConfig {
+                sink: NodeOrTarget::Target(sink).into(),
+                source: NodeOrTarget::Target(source),
+                special_empty_ports: None,
+                duplicate_mono: false,
+                exclusive: false,
+                include_monitor: monitor,
+                move_stream: false,
+                priority: 0,
+                link_delay_ms: None,
+                channel_map: Vec::new(),
+                link_properties: BTreeMap::new(),
+                link_name_template: None,
+                pairing: Pairing::All,
+                distribute: Distribute::All,
+            },
+        );
+    }
+    links
+}
+
+/// A target matching only the given port, by the names of its client, node and
+/// itself, along with the node name
+fn port_target<'a>(port: &Port, state: &'a PipewireState) -> Option<(Target, &'a str)> {
+    let node = state.nodes.get(&port.node_id)?;
+    let client = state.clients.get(&node.client_id);
+    let target = Target {
+        client: client.map(|c| regex::escape(&c.application_name).into()),
+        node: Some(regex::escape(&node.node_name).into()),
+        port: Some(regex::escape(&port.name).into()),
+        // The names are escaped for regex, whatever the general match mode is
+        match_mode: Some(MatchMode::Regex),
+        ..Default::default()
+    };
+    Some((target, node.node_name.as_str()))
+}
+
+/// Lowercase letters, digits and underscores only, so that the name needs no
+/// quoting in the config
+fn rule_name(name: &str) -> String {
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            sanitized.push(c.to_ascii_lowercase());
+        } else if !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    sanitized.trim_matches('_').to_owned()
+}
@@ -0,0 +1,84 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{Level, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value};
+
+/// Whether the JSON logger was set, so that records can be given fields of
+/// their own
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Writes every record as a line of JSON to stderr
+struct JsonLogger {
+    level: Level,
+    /// Only records of this module and its submodules are written
+    module: &'static str,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with(self.module)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            write(record, Map::new());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sets the JSON logger as the global logger, in place of stderrlog
+pub fn init(module: &'static str, level: Level) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(JsonLogger { level, module }))?;
+    log::set_max_level(level.to_level_filter());
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Logs the message with the given fields when the JSON logger is in use.
+/// Returns false without logging anything otherwise, as the text format has
+/// no place for them.
+pub fn log_with_fields(
+    level: Level,
+    target: &str,
+    message: fmt::Arguments,
+    fields: &[(&str, Value)],
+) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let record = Record::builder()
+        .level(level)
+        .target(target)
+        .args(message)
+        .build();
+    let extra = fields
+        .iter()
+        .map(|(key, value)| ((*key).to_owned(), value.clone()))
+        .collect();
+    // The global logger is the JsonLogger, which filters by level and module
+    if log::logger().enabled(record.metadata()) {
+        write(&record, extra);
+    }
+    true
+}
+
+/// Writes the record as a line of JSON, along with the extra fields
+fn write(record: &Record, extra: Map<String, Value>) {
+    let mut fields = Map::new();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    fields.insert("timestamp".to_owned(), timestamp.into());
+    fields.insert("level".to_owned(), record.level().as_str().into());
+    fields.insert("target".to_owned(), record.target().into());
+    fields.insert("message".to_owned(), record.args().to_string().into());
+    fields.extend(extra);
+    eprintln!("{}", Value::Object(fields));
+}
@@ -1,55 +1,218 @@
 use std::{
+    cell::RefCell,
     collections::{HashMap, HashSet},
-    sync::mpsc::channel,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    time::Duration,
 };
 
-use config::{load_config_or_default, start_pipeswitch_thread, ConfigListener};
+use config::{load_config_or_default, load_valid_config, start_pipeswitch_thread, ConfigListener};
+use control::{Command, LinkSummary, RuleStatus, RuleSummary, MANUAL_RULE_NAME};
 use log::*;
+use metrics::{Metrics, MetricsServer};
+use persist::PersistedLink;
 use pipeswitch_lib::{
-    config::Config,
-    types::{Link, Object, Port},
-    Pipeswitch, PipeswitchMessage, PipewireError,
+    config::{Config, General, LogFormat},
+    types::{Channel, DefaultNode, Link, LinkState, Node, Object, Port, KEY_LINK_NAME},
+    NewLink, Pipeswitch, PipeswitchError, PipeswitchMessage, PipewireError, DEFAULT_LINK_TIMEOUT,
+    LINK_FACTORY,
 };
+use timers::Timers;
 
 use crate::config::Event;
 use crate::rules::*;
 
 mod config;
+mod control;
+mod dbus;
+mod dump;
+mod export;
+mod json_log;
+mod metrics;
+mod osc;
+mod persist;
 mod rules;
+mod signals;
+mod socket;
+mod timers;
+
+/// How long to wait before reconnecting a link that was removed externally
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+/// A port that appears again within this long after being removed is taken
+/// to be the same port, ie. when a device renegotiates its format
+const PORT_FLAP_WINDOW: Duration = Duration::from_millis(250);
+/// Creating links taking longer than this is warned about, ie. when the link
+/// factory was not ready yet
+const SLOW_LINK_LATENCY: Duration = Duration::from_secs(1);
 
 fn main() {
+    let mut dry_run = false;
+    let mut once = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "dump" => {
+                dump::dump_graph()
+                    .map_err(|e| panic!("Failed to dump PipeWire graph: {e}"))
+                    .unwrap();
+                return;
+            }
+            "export" => {
+                export::export_links()
+                    .map_err(|e| panic!("Failed to export PipeWire links: {e}"))
+                    .unwrap();
+                return;
+            }
+            "status" => {
+                socket::print_status()
+                    .map_err(|e| panic!("Failed to get the status of pipeswitchd: {e}"))
+                    .unwrap();
+                return;
+            }
+            "--dry-run" => dry_run = true,
+            "--once" => once = true,
+            "--check" => {
+                let path = match args.next() {
+                    Some(path) => PathBuf::from(path),
+                    None => Config::default_path().unwrap(),
+                };
+                let valid = config::check_config(&path);
+                std::process::exit(if valid { 0 } else { 1 });
+            }
+            _ => {
+                eprintln!("unknown argument: {arg}");
+                eprintln!("usage: pipeswitchd [--dry-run] [--once] | pipeswitchd --check [path]");
+                eprintln!("       pipeswitchd dump | pipeswitchd export | pipeswitchd status");
+                std::process::exit(2);
+            }
+        }
+    }
+
     let config_path = &Config::default_path().unwrap();
     let config = load_config_or_default(config_path)
         .map_err(|e| panic!("Failed to load Config at startup: {e}"))
         .unwrap();
 
-    stderrlog::new()
-        .module(module_path!())
-        .verbosity(config.log.level)
-        .timestamp(stderrlog::Timestamp::Second)
-        .init()
-        .unwrap();
+    match config.log.format {
+        LogFormat::Text => stderrlog::new()
+            .module(module_path!())
+            .verbosity(config.log.level)
+            .timestamp(stderrlog::Timestamp::Second)
+            .init()
+            .unwrap(),
+        LogFormat::Json => json_log::init(module_path!(), config.log.level).unwrap(),
+    }
     let (sender, receiver) = channel();
 
-    let (pipeswitch, _join) = start_pipeswitch_thread(sender.clone())
+    let remote = config.general.remote.as_deref();
+    let (pipeswitch, _join) = start_pipeswitch_thread(sender.clone(), remote)
         .map_err(|e| panic!("Failed to start listening to Pipewire: {e}"))
         .unwrap();
-    let mut daemon = PipeswitchDaemon::new(pipeswitch, &config);
+    // Let the existing graph arrive first, so that links surviving from an
+    // earlier run are adopted instead of being created again
+    for _ in 0..2 {
+        if let Err(e) = pipeswitch.roundtrip() {
+            error!("Failed to wait for the PipeWire registry: {e}");
+        }
+    }
+    // A dry run links nothing, so it has nothing to keep track of
+    let state_path = persist::default_path().filter(|_| !dry_run);
+    let mut daemon =
+        PipeswitchDaemon::new(pipeswitch, &config, sender.clone(), dry_run, state_path);
+    if once {
+        // Links are made with object.linger, so they stay after exiting
+        daemon.link_delayed_now();
+        info!("rules applied once, exiting");
+        return;
+    }
+
+    let mut _metrics_server = None;
+    if config.metrics.enabled {
+        match MetricsServer::start(config.metrics.port, daemon.metrics.clone()) {
+            Ok(server) => _metrics_server = Some(server),
+            Err(e) => error!("Failed to start metrics server: {e}"),
+        }
+    }
+
+    let mut _dbus = None;
+    if config.general.dbus_interface {
+        match dbus::start(sender.clone()) {
+            Ok(connection) => _dbus = Some(connection),
+            Err(e) => error!("Failed to start D-Bus interface: {e}"),
+        }
+    }
+
+    if config.osc.enabled {
+        if let Err(e) = osc::start(config.osc.port, sender.clone()) {
+            error!("Failed to start OSC server: {e}");
+        }
+    }
+
+    if let Some(socket_path) = &config.general.control_socket {
+        if let Err(e) = socket::start(socket_path, sender.clone()) {
+            error!("Failed to open control socket: {e}");
+        }
+    }
+
+    if let Err(e) = signals::start(config_path, sender.clone()) {
+        error!("Failed to listen for signals: {e}");
+    }
 
     let mut _listener = None;
     if config.general.hotreload_config {
         _listener = Some(ConfigListener::start(config_path, sender));
     }
 
-    while let Ok(event) = receiver.recv() {
+    // The existing graph has been linked by now. Does nothing outside of systemd.
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        warn!("Failed to notify systemd of readiness: {e}");
+    }
+
+    loop {
+        let event = match receiver.try_recv() {
+            Ok(event) => event,
+            Err(_) => {
+                // Caught up, so a new node has had all of its ports matched
+                daemon.connect_queued_links();
+                match receiver.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                }
+            }
+        };
+        // Links to the ports arriving one after another are created together,
+        // anything else sees them created first
+        if !matches!(
+            event,
+            Event::Pipeswitch(PipeswitchMessage::NewObject(Object::Port(_)))
+        ) {
+            daemon.connect_queued_links();
+        }
         match event {
             Event::Pipeswitch(pw) => {
                 use PipeswitchMessage::*;
                 match pw {
+                    Disconnected => daemon.pipewire_disconnected(),
+                    Reconnected => daemon.pipewire_reconnected(),
+                    NewObject(_) | ObjectRemoved(_) | ObjectChanged(..) | DefaultChanged(_)
+                        if daemon.reconnecting => {}
                     NewObject(Object::Port(port)) => daemon.new_port(port),
                     NewObject(Object::Link(link)) => daemon.new_link(link),
+                    NewObject(Object::Factory(factory)) if factory.type_name == LINK_FACTORY => {
+                        daemon.link_factory_found()
+                    }
                     ObjectRemoved(Object::Port(port)) => daemon.port_deleted(&port),
                     ObjectRemoved(Object::Link(link)) => daemon.link_deleted(&link),
+                    ObjectRemoved(Object::Node(node)) => daemon.node_removed(&node),
+                    ObjectChanged(Object::Node(old), Object::Node(node)) => {
+                        daemon.node_changed(&old, &node)
+                    }
+                    ObjectChanged(_, Object::Link(link)) => daemon.link_state_changed(&link),
+                    DefaultChanged(kind) => daemon.default_changed(kind),
+                    LinksCreated(count, latency) => daemon.links_created(count, latency),
                     Error(e) => {
                         if let PipewireError::PropNotFound(..) = e {
                             warn!("{e}")
@@ -61,8 +224,61 @@ fn main() {
                 }
             }
             Event::ConfigModified(conf) => {
-                daemon.update_config(&conf);
+                if let Err(e) = daemon.update_config(&conf) {
+                    error!("Error applying updated config, keeping the previous one: {e}");
+                }
             }
+            Event::Command(command) => daemon.handle_command(command, config_path),
+            Event::LinkDelayElapsed(rule_name, timer) => {
+                daemon.link_delay_elapsed(rule_name, timer)
+            }
+            Event::PortRemovalElapsed(key, timer) => daemon.port_removal_elapsed(key, timer),
+            Event::LinkRemovalElapsed(id, timer) => daemon.link_removal_elapsed(id, timer),
+            Event::LayoutCheckElapsed(rule_name, output, input, timer) => {
+                daemon.layout_check_elapsed((rule_name, output, input), timer)
+            }
+            Event::LinkFactoryElapsed(timer) => daemon.link_factory_elapsed(timer),
+            Event::Shutdown => break,
+        }
+    }
+    info!("shutting down");
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+    daemon.shutdown();
+}
+
+/// What a config changes about the rules, worked out before applying it
+#[derive(Default)]
+struct ConfigPlan {
+    /// Rules that are kept as they are
+    unchanged: Vec<String>,
+    /// New versions of rules that exist already
+    modified: Vec<LinkRules>,
+    added: Vec<LinkRules>,
+    removed: Vec<String>,
+}
+
+/// The general settings of the config that the daemon keeps itself
+#[derive(Clone, Copy)]
+struct Settings {
+    linger_links: bool,
+    stop_on_first_match: bool,
+    auto_reconnect: bool,
+    unlink_on_exit: bool,
+    only_manage_own_links: bool,
+    prevent_cycles: bool,
+    strict_channels: bool,
+}
+
+impl From<&General> for Settings {
+    fn from(general: &General) -> Self {
+        Settings {
+            linger_links: general.linger_links,
+            stop_on_first_match: general.stop_on_first_match,
+            auto_reconnect: general.auto_reconnect,
+            unlink_on_exit: general.unlink_on_exit,
+            only_manage_own_links: general.only_manage_own_links,
+            prevent_cycles: general.prevent_cycles,
+            strict_channels: general.strict_channels,
         }
     }
 }
@@ -71,28 +287,158 @@ struct PipeswitchDaemon {
     rules: HashMap<String, LinkRules>,
     pipeswitch: Pipeswitch,
     linger_links: bool,
+    stop_on_first_match: bool,
+    auto_reconnect: bool,
+    unlink_on_exit: bool,
+    only_manage_own_links: bool,
+    prevent_cycles: bool,
+    strict_channels: bool,
+    /// Rules and their output and input node already warned about having
+    /// channel layouts of different sizes, and the sizes warned about
+    warned_layouts: HashMap<(String, u32, u32), (usize, usize)>,
+    /// Rules and their output and input node not linked by strict_channels,
+    /// tried again when either node gains or loses ports
+    refused_layouts: HashSet<(String, u32, u32)>,
+    /// Latest timer of rules and their output and input node whose layouts
+    /// differed, compared once the nodes are done announcing their ports
+    layout_checks: HashMap<(String, u32, u32), u64>,
+    /// Rules to link again at the end of the batch, since a node they were
+    /// refused for has changed
+    layout_rechecks: HashSet<String>,
+    /// Channels of the channel_map of each rule last warned about as missing
+    missing_mapped: HashMap<String, Vec<Channel>>,
+    /// Rules with a channel_map that matched new ports in the batch
+    mapped_rechecks: HashSet<String>,
+    /// Links destroyed by Pipeswitch whose removal has not been seen yet
+    destroyed_links: RefCell<HashSet<u32>>,
+    /// Rules that have had every link they expect, until they lose all of them
+    connected_rules: RefCell<HashSet<String>>,
+    metrics: Arc<Metrics>,
+    timers: Timers,
+    /// Latest delay timer of each rule that is waiting to be linked
+    pending_links: HashMap<String, u64>,
+    /// Removed ports by path or alias, which are only forgotten by the rules
+    /// if they do not come back within `PORT_FLAP_WINDOW`
+    pending_port_removals: HashMap<String, (Port, u64)>,
+    /// Links of rules removed by someone else, which the rules keep until
+    /// `PORT_FLAP_WINDOW` has passed, in case their ports flapped and the link
+    /// is made again
+    lost_links: HashMap<u32, (Link, u64)>,
+    /// Rule, output and input of links to new ports, created together once
+    /// the ports that arrived at the same time have all been matched
+    queued_links: Vec<(String, Port, Port)>,
+    /// Links waiting for the link factory to be discovered, and the timer
+    /// after which they fail
+    awaiting_factory: Vec<(String, Port, Port)>,
+    factory_timer: Option<u64>,
+    timer_count: u64,
+    /// Links managed in an earlier run, and where to keep track of them
+    persisted_links: Vec<PersistedLink>,
+    state_path: Option<PathBuf>,
+    /// PipeWire went away, and the graph arriving again is matched all at once
+    /// when it has been reconnected
+    reconnecting: bool,
+    /// Only log what would be linked and destroyed
+    dry_run: bool,
+    /// The profile whose rules are linked along with the rest, kept across
+    /// reloads unless general.profile is edited
+    active_profile: Option<String>,
+    /// The config last applied, without the active profile merged in
+    config: Config,
 }
 
 impl PipeswitchDaemon {
-    pub fn new(pipeswitch: Pipeswitch, config: &Config) -> Self {
+    pub fn new(
+        pipeswitch: Pipeswitch,
+        config: &Config,
+        event_sender: Sender<Event>,
+        dry_run: bool,
+        state_path: Option<PathBuf>,
+    ) -> Self {
         let mut daemon = PipeswitchDaemon {
             pipeswitch,
             rules: HashMap::default(),
             linger_links: false,
+            stop_on_first_match: false,
+            auto_reconnect: false,
+            unlink_on_exit: false,
+            only_manage_own_links: true,
+            prevent_cycles: false,
+            strict_channels: false,
+            warned_layouts: HashMap::new(),
+            refused_layouts: HashSet::new(),
+            layout_checks: HashMap::new(),
+            layout_rechecks: HashSet::new(),
+            missing_mapped: HashMap::new(),
+            mapped_rechecks: HashSet::new(),
+            destroyed_links: RefCell::default(),
+            connected_rules: RefCell::default(),
+            metrics: Arc::default(),
+            timers: Timers::start(event_sender),
+            pending_links: HashMap::default(),
+            pending_port_removals: HashMap::default(),
+            lost_links: HashMap::default(),
+            queued_links: Vec::new(),
+            awaiting_factory: Vec::new(),
+            factory_timer: None,
+            timer_count: 0,
+            persisted_links: Vec::new(),
+            state_path,
+            reconnecting: false,
+            dry_run,
+            active_profile: config.general.profile.clone(),
+            config: config.clone(),
         };
-        daemon.update_config(config);
+        if dry_run {
+            info!("dry run, PipeWire will not be modified");
+        }
+        if let Some(path) = &daemon.state_path {
+            match persist::load(path) {
+                Ok(links) => daemon.persisted_links = links,
+                Err(e) => warn!("Failed to load managed links from earlier run: {e}"),
+            }
+        }
+        if let Err(e) = daemon.update_config(config) {
+            error!("Error applying config, starting without rules: {e}");
+        }
+        daemon.adopt_existing_links();
         daemon
     }
 
     fn new_link(&mut self, link: Link) {
+        if let LinkState::Error(_) = link.state {
+            self.link_state_changed(&link);
+        }
+        if self.lost_links.remove(&link.id).is_some() {
+            // PipeWire reused the id, so the lost link is not coming back
+            self.forget_link(link.id, true);
+        }
         if let Some(new_rule_name) = link.rule_name.clone() {
+            if new_rule_name == MANUAL_RULE_NAME {
+                return;
+            }
             let mut exists = false;
+            let mut changed = false;
+            let replaced = self
+                .rules
+                .get(&new_rule_name)
+                .and_then(|rule| self.lost_link_replaced_by(rule, &link));
             for (rule_name, rule) in self.rules.iter_mut() {
                 if new_rule_name == *rule_name
-                    && rule.input.matching_ports.contains(&link.input_port)
+                    && rule.matches_input(link.input_port)
                     && rule.output.matching_ports.contains(&link.output_port)
                 {
-                    rule.links.insert(link.id);
+                    if let Some(lost) = replaced {
+                        // Its ports flapped, so the rule keeps its link under a new id
+                        rule.links.remove(&lost);
+                        self.lost_links.remove(&lost);
+                        trace!("link {lost} of rule [{rule_name}] is back as {}", link.id);
+                        rule.links.insert(link.id);
+                        changed = true;
+                    } else if rule.links.insert(link.id) {
+                        Metrics::increment(&self.metrics.links_created);
+                        changed = true;
+                    }
                     let link_id = link.id;
                     trace!("New link {link_id} for rule [{rule_name}]");
                     exists = true;
@@ -100,11 +446,298 @@ impl PipeswitchDaemon {
             }
             if !exists {
                 let link_id = link.id;
-                if !self.linger_links && self.pipeswitch.destroy_link(link).unwrap() {
-                    info!("old link {link_id} from old config rule [{new_rule_name}] destroyed");
+                if !self.linger_links {
+                    match self.destroy_link(link) {
+                        Ok(true) => log_link_event(
+                            "destroyed",
+                            &new_rule_name,
+                            link_id,
+                            format_args!(
+                                "old link {link_id} from old config rule [{new_rule_name}] \
+                                 destroyed"
+                            ),
+                        ),
+                        Ok(false) => {}
+                        Err(e) => error!("failed to destroy old link {link_id}: {e}"),
+                    }
+                }
+            }
+            self.update_rule_metrics();
+            if changed {
+                self.save_links();
+            }
+        }
+    }
+
+    /// Warns about a link PipeWire failed to set up, as it exists but no audio
+    /// flows through it
+    fn link_state_changed(&self, link: &Link) {
+        let description = match &link.rule_name {
+            Some(rule_name) => format!("link {} of rule [{rule_name}]", link.id),
+            None => format!("link {}", link.id),
+        };
+        match &link.state {
+            LinkState::Error(message) => warn!("{description} failed: {message}"),
+            state => trace!("{description} is now {state}"),
+        }
+    }
+
+    /// Notes how long PipeWire took to create a batch of links
+    fn links_created(&self, count: usize, latency: Duration) {
+        debug!("{count} links created in {}ms", latency.as_millis());
+        if latency >= SLOW_LINK_LATENCY {
+            warn!("creating {count} links took {}ms", latency.as_millis());
+        }
+        self.metrics.observe_link_latency(latency);
+    }
+
+    /// Writes the links managed by each rule to disk, so that they can be
+    /// adopted after a restart
+    fn save_links(&self) {
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return,
+        };
+        let state = self.pipeswitch.lock_current_state();
+        let mut links = Vec::new();
+        for rule in self.rules.values() {
+            for link in rule.links.iter().filter_map(|id| state.links.get(id)) {
+                if let (Some(output), Some(input)) = (
+                    state.ports.get(&link.output_port),
+                    state.ports.get(&link.input_port),
+                ) {
+                    links.push(PersistedLink {
+                        rule: rule.name.clone(),
+                        output: output.alias.clone(),
+                        input: input.alias.clone(),
+                    });
+                }
+            }
+        }
+        drop(state);
+        if let Err(e) = persist::save(path, &links) {
+            warn!("Failed to save managed links: {e}");
+        }
+    }
+
+    /// If the ports are already linked, adopts the link into the rule unless it
+    /// was created for another rule, now or in an earlier run. Returns whether
+    /// a link already exists, so that a duplicate is not created.
+    fn reconcile_existing_link(&mut self, rule_name: &str, output: &Port, input: &Port) -> bool {
+        let state = self.pipeswitch.lock_current_state();
+        let existing = state
+            .links
+            .values()
+            .find(|l| l.output_port == output.id && l.input_port == input.id)
+            .cloned();
+        drop(state);
+
+        let link = match existing {
+            Some(link) => link,
+            None => return false,
+        };
+        let recorded = self
+            .persisted_links
+            .iter()
+            .any(|p| p.rule == rule_name && p.output == output.alias && p.input == input.alias);
+        let unowned = link.rule_name.is_none();
+        if unowned || link.rule_name.as_deref() == Some(rule_name) || recorded {
+            if let Some(rule) = self.rules.get_mut(rule_name) {
+                if rule.links.insert(link.id) {
+                    let link_id = link.id;
+                    debug!("adopted existing link {link_id} for rule [{rule_name}]");
+                    self.update_rule_metrics();
+                    self.save_links();
+                }
+            }
+        } else {
+            let (o_name, i_name) = (&output.alias, &input.alias);
+            debug!("{o_name} is already linked to {i_name} outside of [{rule_name}]");
+        }
+        true
+    }
+
+    /// Adopts the links already in the graph that a rule would have made, so
+    /// that they are managed like its own. Links made for another rule are
+    /// left alone. Also covers disabled and delayed rules, which do not link
+    /// right away.
+    fn adopt_existing_links(&mut self) {
+        let state = self.pipeswitch.lock_current_state();
+        let mut adopted = 0;
+        for rule in self.rules.values_mut() {
+            for link in state.links.values() {
+                let (output, input) = match (
+                    state.ports.get(&link.output_port),
+                    state.ports.get(&link.input_port),
+                ) {
+                    (Some(output), Some(input)) => (output, input),
+                    _ => continue,
+                };
+                let owned_by_other = link
+                    .rule_name
+                    .as_deref()
+                    .map_or(false, |name| name != rule.name);
+                if !owned_by_other
+                    && rule.output.matching_ports.contains(&output.id)
+                    && rule.should_link(output, input, &state)
+                    && rule.links.insert(link.id)
+                {
+                    let rule_name = &rule.name;
+                    debug!("adopted existing link {link} for rule [{rule_name}]");
+                    adopted += 1;
                 }
             }
         }
+        drop(state);
+        if adopted > 0 {
+            info!("adopted {adopted} existing links");
+            self.update_rule_metrics();
+            self.save_links();
+        }
+    }
+
+    /// Forgets everything about the old graph, since PipeWire gives its
+    /// objects new ids once it is back
+    fn pipewire_disconnected(&mut self) {
+        warn!("lost connection to PipeWire, reconnecting");
+        self.reconnecting = true;
+        for rule in self.rules.values_mut() {
+            rule.forget_graph();
+        }
+        self.destroyed_links.borrow_mut().clear();
+        self.pending_links.clear();
+        self.pending_port_removals.clear();
+        self.lost_links.clear();
+        self.awaiting_factory.clear();
+        self.factory_timer = None;
+        self.warned_layouts.clear();
+        self.refused_layouts.clear();
+        self.layout_checks.clear();
+        self.layout_rechecks.clear();
+        self.missing_mapped.clear();
+        self.mapped_rechecks.clear();
+        self.update_rule_metrics();
+    }
+
+    /// Matches every rule against the graph PipeWire came back with, like at
+    /// startup
+    fn pipewire_reconnected(&mut self) {
+        info!("reconnected to PipeWire");
+        self.reconnecting = false;
+        let ports: Vec<Port> = self
+            .pipeswitch
+            .lock_current_state()
+            .ports
+            .values()
+            .cloned()
+            .collect();
+        let rule_names: HashSet<String> = self.rules.keys().cloned().collect();
+        for port in ports {
+            self.new_port_for_rules(port, rule_names.clone());
+        }
+        self.connect_queued_links();
+        self.adopt_existing_links();
+        self.update_rule_metrics();
+    }
+
+    fn update_rule_metrics(&self) {
+        self.update_rule_health();
+        *self.metrics.rule_links.lock().unwrap() = self
+            .rules
+            .values()
+            .map(|rule| (rule.name.clone(), rule.links.len()))
+            .collect();
+        *self.metrics.connected_rules.lock().unwrap() =
+            self.connected_rules.borrow().iter().cloned().collect();
+    }
+
+    /// Notes the rules that now have every link they expect, and the ones that
+    /// have lost all of their links since
+    fn update_rule_health(&self) {
+        let state = self.pipeswitch.lock_current_state();
+        let mut connected_rules = self.connected_rules.borrow_mut();
+        connected_rules.retain(|name| self.rules.contains_key(name));
+        // Rules moving streams never have links of their own
+        for rule in self.rules.values().filter(|rule| !rule.move_stream) {
+            let name = rule.name.as_str();
+            if connected_rules.contains(name) {
+                if rule.links.is_empty() && rule.dry_run_links.is_empty() {
+                    connected_rules.remove(name);
+                    log_rule_event(
+                        "rule_disconnected",
+                        name,
+                        format_args!("rule [{name}] lost all of its links"),
+                    );
+                }
+                continue;
+            }
+            let mut linked: HashSet<(u32, u32)> = rule
+                .links
+                .iter()
+                .filter_map(|id| state.links.get(id))
+                .map(|link| (link.output_port, link.input_port))
+                .collect();
+            linked.extend(&rule.dry_run_links);
+            let expected = rule.expected_links(&state);
+            if !expected.is_empty() && expected.is_subset(&linked) {
+                connected_rules.insert(name.to_owned());
+                let mut link_ids: Vec<u32> = rule.links.iter().copied().collect();
+                link_ids.sort_unstable();
+                log_rule_event(
+                    "rule_connected",
+                    name,
+                    format_args!("rule [{name}] is fully connected with links {link_ids:?}"),
+                );
+            }
+        }
+    }
+
+    /// Whether the rule may destroy the link. With only_manage_own_links, links
+    /// made by hand or for another rule are preserved, even if adopted.
+    fn may_destroy(&self, rule_name: &str, link: &Link) -> bool {
+        if !self.only_manage_own_links || link.rule_name.as_deref() == Some(rule_name) {
+            return true;
+        }
+        info!("link {link} preserved, it was not made by [{rule_name}]");
+        false
+    }
+
+    /// Destroys a link, remembering that it was Pipeswitch that did so
+    fn destroy_link(&self, link: Link) -> Result<bool, PipeswitchError> {
+        let link_id = link.id;
+        if self.dry_run {
+            info!("would destroy link {link_id}");
+            return Ok(false);
+        }
+        // Noted before asking, so that the removal is never taken as external
+        self.destroyed_links.borrow_mut().insert(link_id);
+        let destroyed = self.pipeswitch.destroy_link(link);
+        if !matches!(destroyed, Ok(true)) {
+            self.destroyed_links.borrow_mut().remove(&link_id);
+        }
+        destroyed
+    }
+
+    /// Destroys the links of all rules if configured to, before Pipeswitch
+    /// itself is dropped
+    fn shutdown(&self) {
+        if !self.unlink_on_exit || self.linger_links {
+            return;
+        }
+        let mut destroyed = 0;
+        for rule in self.rules.values() {
+            for link in self.fetch_links(&rule.links) {
+                if !self.may_destroy(&rule.name, &link) {
+                    continue;
+                }
+                match self.destroy_link(link) {
+                    Ok(true) => destroyed += 1,
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to destroy link on exit: {e}"),
+                }
+            }
+        }
+        info!("{destroyed} links destroyed on exit");
     }
 
     fn fetch_links<'a, T: IntoIterator<Item = &'a u32>>(&self, link_ids: T) -> Vec<Link> {
@@ -118,107 +751,222 @@ impl PipeswitchDaemon {
         links
     }
 
-    fn update_config(&mut self, config: &Config) {
+    /// Applies the config, or goes back to the previous rules and settings if
+    /// PipeWire fails partway through, leaving the previous config running
+    fn update_config(&mut self, config: &Config) -> Result<(), PipeswitchError> {
         debug!("rechecking config");
-        let linger_changed = self.linger_links != config.general.linger_links;
-        self.linger_links = config.general.linger_links;
+        let previous_profile = self.active_profile.clone();
+        if config.general.profile != self.config.general.profile {
+            self.active_profile = config.general.profile.clone();
+        }
+        let unmerged = config;
+        let config = &self.with_active_profile(config);
+        for warning in overlapping_rules(config)
+            .into_iter()
+            .chain(same_direction_rules(config))
+        {
+            warn!("{warning}");
+        }
+        let plan = self.plan_config(config);
+        let previous_rules = self.rules.clone();
+        let previous_settings = self.settings();
+        let previous_warned = self.warned_layouts.clone();
+        let previous_refused = self.refused_layouts.clone();
+        let previous_checks = self.layout_checks.clone();
+        let previous_pending = self.pending_links.clone();
+        let previous_links: HashSet<u32> = {
+            let state = self.pipeswitch.lock_current_state();
+            state.links.keys().copied().collect()
+        };
+        if let Err(e) = self.apply_config(config, plan) {
+            self.queued_links.clear();
+            // Links made for the new rules before the failure belong to no rule
+            // once the previous rules are back
+            let created: Vec<u32> = self
+                .rules
+                .values()
+                .flat_map(|rule| rule.links.iter().copied())
+                .filter(|id| !previous_links.contains(id))
+                .collect();
+            for link in self.fetch_links(&created) {
+                if let Err(e) = self.destroy_link(link) {
+                    error!("failed to destroy link made by the failed reload: {e}");
+                }
+            }
+            self.rules = previous_rules;
+            self.apply_settings(previous_settings);
+            self.active_profile = previous_profile;
+            self.warned_layouts = previous_warned;
+            self.refused_layouts = previous_refused;
+            self.layout_checks = previous_checks;
+            self.pending_links = previous_pending;
+            // Links destroyed before the failure are made again
+            let rule_names: Vec<String> = self.rules.keys().cloned().collect();
+            for rule_name in rule_names {
+                self.link_missing(&rule_name);
+            }
+            self.update_rule_metrics();
+            return Err(e);
+        }
 
-        // Contains all of the rule names that still need to be checked.
-        let mut dirty_rule_names: HashSet<String> = self
-            .rules
-            .keys()
-            .chain(config.links.keys())
-            .cloned()
-            .collect();
+        self.config = unmerged.clone();
+        // Warned about again on every reload
+        self.missing_mapped.clear();
+        let rule_names: Vec<String> = self.rules.keys().cloned().collect();
+        self.warn_missing_mapped_channels(rule_names);
 
-        let mut modified_count = 0;
-        let mut new_count = 0;
-        let mut removed_count = 0;
-        let mut lingering_links = 0;
+        Metrics::increment(&self.metrics.config_reloads);
+        self.update_rule_metrics();
+        debug!("config checked");
+        Ok(())
+    }
 
-        // Go through all new and old rules and destroy any links that do not
-        // match up with the new configuration.
-        for rule_name in dirty_rule_names.clone() {
-            let curr_rule = self.rules.get(&rule_name);
-            let new_rule = config
-                .links
-                .get(&rule_name)
-                .map(|c| LinkRules::from((rule_name.clone(), c.clone())));
-
-            match (curr_rule, new_rule) {
-                (Some(curr), Some(mut new)) => {
-                    if new.input != curr.input || new.output != curr.output {
-                        // Same rule exists, but has changed
-                        debug!("rule [{rule_name}] changed");
-                        if self.linger_links {
-                            new.links.extend(&curr.links);
-                        } else {
-                            for link in self.fetch_links(&curr.links) {
-                                let link_id = link.id;
-                                if self.pipeswitch.destroy_link(link).unwrap() {
-                                    info!("old rule [{rule_name}] link {link_id} destroyed");
-                                }
-                            }
-                        }
-                        self.rules.insert(rule_name, new);
-                        modified_count += 1;
-                    } else {
-                        // Same rule exists and has not changed
-                        // Check for lingering links anyway
-                        if linger_changed && !self.linger_links {
-                            info!("deleting old lingered links");
-                            for link in self.fetch_links(&curr.links) {
-                                let link_id = link.id;
-                                if (!curr.input.matching_ports.contains(&link.input_port)
-                                    || !curr.output.matching_ports.contains(&link.output_port))
-                                    && self.pipeswitch.destroy_link(link).unwrap()
-                                {
-                                    info!("old rule [{rule_name}] link {link_id} destroyed");
-                                    lingering_links += 1;
-                                }
-                            }
-                        }
-                        debug!("rule [{rule_name}] was unmodified");
-                        dirty_rule_names.remove(&rule_name);
+    /// The config with the rules of the active profile merged in. A profile
+    /// that can not be activated anymore is deactivated.
+    fn with_active_profile(&mut self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(profile_name) = &self.active_profile {
+            if let Err(e) = config.activate_profile(profile_name) {
+                warn!("{e}, deactivating profile [{profile_name}]");
+                self.active_profile = None;
+            }
+        }
+        config
+    }
+
+    /// Replaces the rules of the active profile with the rules of the given
+    /// one, or only removes them without one. The links of the old profile
+    /// are destroyed unless links linger, and the previous profile stays
+    /// active if the config can not be applied.
+    fn activate_profile(&mut self, name: Option<String>) -> Result<(), String> {
+        // Goes by the config that is running, not whatever is on disk now
+        let config = self.config.clone();
+        if let Some(name) = &name {
+            let checked = config.clone().activate_profile(name);
+            checked.map_err(|e| e.to_string())?;
+        }
+        let previous = std::mem::replace(&mut self.active_profile, name);
+        if let Err(e) = self.update_config(&config) {
+            self.active_profile = previous;
+            return Err(e.to_string());
+        }
+        match &self.active_profile {
+            Some(name) => info!("profile [{name}] activated"),
+            None => info!("profile deactivated"),
+        }
+        Ok(())
+    }
+
+    /// Works out which rules the config adds, changes and removes, without
+    /// touching the rules or PipeWire yet. Rules that can not be built are
+    /// logged and left as they were.
+    fn plan_config(&self, config: &Config) -> ConfigPlan {
+        let mut plan = ConfigPlan::default();
+        for (rule_name, cfg) in &config.links {
+            let curr = self.rules.get(rule_name);
+            if curr.map_or(false, |curr| curr.built_from(cfg, &config.general)) {
+                plan.unchanged.push(rule_name.clone());
+                continue;
+            }
+            let new = match LinkRules::try_from((rule_name.clone(), cfg.clone(), &config.general)) {
+                Ok(new) => new,
+                Err(e) => {
+                    error!("{e}");
+                    if curr.is_some() {
+                        plan.unchanged.push(rule_name.clone());
                     }
+                    continue;
                 }
-                (Some(curr), None) => {
-                    // Previous config had a rule which this one does not.
-                    for link in self.fetch_links(&curr.links) {
-                        let link_id = link.id;
-                        if !self.linger_links && self.pipeswitch.destroy_link(link).unwrap() {
-                            info!("old rule [{rule_name}] link {link_id} destroyed");
-                        }
-                    }
-                    self.rules.remove(&rule_name);
-                    dirty_rule_names.remove(&rule_name);
-                    removed_count += 1;
+            };
+            match curr {
+                Some(curr) if !new.links_differently(curr) => {
+                    plan.unchanged.push(rule_name.clone())
                 }
-                (None, Some(new)) => {
-                    // New config has a rule previous config did not.
-                    self.rules.insert(rule_name, new);
-                    new_count += 1;
+                Some(_) => plan.modified.push(new),
+                None => plan.added.push(new),
+            }
+        }
+        for rule_name in self.rules.keys() {
+            if !config.links.contains_key(rule_name) {
+                plan.removed.push(rule_name.clone());
+            }
+        }
+        plan
+    }
+
+    /// Destroys the links the plan calls for, swaps in the new rules and links
+    /// their ports. Stops at the first request PipeWire fails, ie. when the
+    /// link factory is gone.
+    fn apply_config(&mut self, config: &Config, plan: ConfigPlan) -> Result<(), PipeswitchError> {
+        let linger_changed = self.linger_links != config.general.linger_links;
+        self.apply_settings(Settings::from(&config.general));
+
+        let mut lingering_links = 0;
+        if linger_changed && !self.linger_links {
+            for rule_name in &plan.unchanged {
+                if let Some(rule) = self.rules.get(rule_name) {
+                    lingering_links += self.destroy_lingering_links(rule_name, rule)?;
                 }
-                _ => {}
             }
         }
+        for rule_name in &plan.unchanged {
+            debug!("rule [{rule_name}] was unmodified");
+        }
 
-        let ports: Vec<Port> = self
-            .pipeswitch
-            .lock_current_state()
-            .ports
-            .values()
-            .cloned()
-            .collect();
+        let mut dirty_rule_names = HashSet::new();
+        let mut old_moved_streams = Vec::new();
+        let (modified_count, new_count) = (plan.modified.len(), plan.added.len());
+        for mut new in plan.modified {
+            let rule_name = new.name.clone();
+            debug!("rule [{rule_name}] changed");
+            let curr = &self.rules[&rule_name];
+            new.enabled = curr.enabled;
+            if self.linger_links {
+                new.links.extend(&curr.links);
+                new.moved_streams.extend(&curr.moved_streams);
+            } else {
+                old_moved_streams.push((rule_name.clone(), curr.moved_streams.clone()));
+                self.destroy_old_links(&rule_name, &curr.links)?;
+            }
+            self.rules.insert(rule_name.clone(), new);
+            dirty_rule_names.insert(rule_name);
+        }
+        for rule_name in &plan.removed {
+            if let Some(curr) = self.rules.get(rule_name) {
+                self.destroy_old_links(rule_name, &curr.links)?;
+                self.release_streams(rule_name, &curr.moved_streams);
+            }
+            self.rules.remove(rule_name);
+        }
+        for new in plan.added {
+            dirty_rule_names.insert(new.name.clone());
+            self.rules.insert(new.name.clone(), new);
+        }
 
         // Goes through all the rule_names that still need to have their ports checked
         if !dirty_rule_names.is_empty() {
             trace!("re-checking following rules: {dirty_rule_names:?}");
+            let ports: Vec<Port> = self
+                .pipeswitch
+                .lock_current_state()
+                .ports
+                .values()
+                .cloned()
+                .collect();
             for port in ports {
                 self.new_port_for_rules(port, dirty_rule_names.clone());
             }
+            let queued = std::mem::take(&mut self.queued_links);
+            self.connect_ports(queued)?;
+        }
+        // Streams the changed rule no longer moves go back to their default
+        for (rule_name, streams) in old_moved_streams {
+            let moved = &self.rules[&rule_name].moved_streams;
+            let released: HashSet<u32> = streams.difference(moved).copied().collect();
+            self.release_streams(&rule_name, &released);
         }
 
+        let removed_count = plan.removed.len();
         let mut messages = Vec::new();
         if new_count > 0 {
             messages.push(format!("{new_count} new rules"))
@@ -239,75 +987,1285 @@ impl PipeswitchDaemon {
             }
             info!("{}", message.join(": "));
         }
+        Ok(())
+    }
 
-        debug!("config checked");
+    /// Clears the target of the streams a rule moved, so that the session
+    /// manager picks their sink again, unless links linger
+    fn release_streams(&self, rule_name: &str, streams: &HashSet<u32>) {
+        if self.linger_links {
+            return;
+        }
+        for &stream_id in streams {
+            if self.dry_run {
+                info!("would release stream {stream_id} moved by [{rule_name}]");
+                continue;
+            }
+            match self.pipeswitch.set_stream_target(stream_id, None) {
+                Ok(true) => info!("released stream {stream_id} moved by [{rule_name}]"),
+                Ok(false) => warn!("failed to release stream {stream_id} moved by [{rule_name}]"),
+                Err(e) => error!("failed to release stream {stream_id}: {e}"),
+            }
+        }
+    }
+
+    /// Destroys the links of a rule that was changed or removed, unless links
+    /// linger
+    fn destroy_old_links(
+        &self,
+        rule_name: &str,
+        links: &HashSet<u32>,
+    ) -> Result<(), PipeswitchError> {
+        if self.linger_links {
+            return Ok(());
+        }
+        for link in self.fetch_links(links) {
+            let link_id = link.id;
+            if self.may_destroy(rule_name, &link) && self.destroy_link(link)? {
+                log_link_event(
+                    "destroyed",
+                    rule_name,
+                    link_id,
+                    format_args!("old rule [{rule_name}] link {link_id} destroyed"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            linger_links: self.linger_links,
+            stop_on_first_match: self.stop_on_first_match,
+            auto_reconnect: self.auto_reconnect,
+            unlink_on_exit: self.unlink_on_exit,
+            only_manage_own_links: self.only_manage_own_links,
+            prevent_cycles: self.prevent_cycles,
+            strict_channels: self.strict_channels,
+        }
+    }
+
+    fn apply_settings(&mut self, settings: Settings) {
+        self.linger_links = settings.linger_links;
+        self.stop_on_first_match = settings.stop_on_first_match;
+        self.auto_reconnect = settings.auto_reconnect;
+        self.unlink_on_exit = settings.unlink_on_exit;
+        self.only_manage_own_links = settings.only_manage_own_links;
+        self.prevent_cycles = settings.prevent_cycles;
+        self.strict_channels = settings.strict_channels;
+    }
+
+    /// Destroys links of the rule that its ports do not match anymore, returning
+    /// how many were destroyed
+    fn destroy_lingering_links(
+        &self,
+        rule_name: &str,
+        rule: &LinkRules,
+    ) -> Result<usize, PipeswitchError> {
+        info!("deleting old lingered links");
+        let mut destroyed = 0;
+        for link in self.fetch_links(&rule.links) {
+            let link_id = link.id;
+            if (!rule.matches_input(link.input_port)
+                || !rule.output.matching_ports.contains(&link.output_port))
+                && self.may_destroy(rule_name, &link)
+                && self.destroy_link(link)?
+            {
+                log_link_event(
+                    "destroyed",
+                    rule_name,
+                    link_id,
+                    format_args!("old rule [{rule_name}] link {link_id} destroyed"),
+                );
+                destroyed += 1;
+            }
+        }
+        Ok(destroyed)
+    }
+
+    fn handle_command(&mut self, command: Command, config_path: &Path) {
+        match command {
+            Command::ListRules(reply) => {
+                let rules = self
+                    .rules
+                    .values()
+                    .map(|rule| RuleSummary {
+                        name: rule.name.clone(),
+                        links: rule.links.len() as u32,
+                        enabled: rule.enabled,
+                        connected: self.connected_rules.borrow().contains(&rule.name),
+                    })
+                    .collect();
+                let _ = reply.send(rules);
+            }
+            Command::RuleStatus(reply) => {
+                let state = self.pipeswitch.lock_current_state();
+                let mut rules: Vec<RuleStatus> = self
+                    .rules
+                    .values()
+                    .map(|rule| RuleStatus {
+                        name: rule.name.clone(),
+                        enabled: rule.enabled,
+                        outputs: rule.output.matching_ports.len() as u32,
+                        inputs: rule.input_ports().len() as u32,
+                        links: rule.links.len() as u32,
+                        failed: rule
+                            .links
+                            .iter()
+                            .filter_map(|id| state.links.get(id))
+                            .filter(|link| matches!(link.state, LinkState::Error(_)))
+                            .count() as u32,
+                    })
+                    .collect();
+                rules.sort_by(|a, b| a.name.cmp(&b.name));
+                let _ = reply.send(rules);
+            }
+            Command::ListLinks(reply) => {
+                let links = self
+                    .pipeswitch
+                    .lock_current_state()
+                    .links
+                    .values()
+                    .map(|link| LinkSummary {
+                        id: link.id,
+                        output_port: link.output_port,
+                        input_port: link.input_port,
+                        rule_name: link.rule_name.clone(),
+                        state: link.state.clone(),
+                    })
+                    .collect();
+                let _ = reply.send(links);
+            }
+            Command::ReloadConfig(reply) => {
+                let result = load_valid_config(config_path)
+                    .and_then(|conf| Ok(self.update_config(&conf)?))
+                    .map_err(|e| e.to_string());
+                let _ = reply.send(result);
+            }
+            Command::CreateLink {
+                output,
+                input,
+                reply,
+            } => {
+                let state = self.pipeswitch.lock_current_state();
+                let ports = (
+                    state.ports.get(&output).cloned(),
+                    state.ports.get(&input).cloned(),
+                );
+                drop(state);
+                let result = match ports {
+                    (Some(output), Some(input)) if self.dry_run => {
+                        let (o_name, i_name) = (&output.alias, &input.alias);
+                        info!("would connect {o_name} to {i_name}");
+                        Ok(None)
+                    }
+                    (Some(output), Some(input)) => self
+                        .pipeswitch
+                        .create_link(output, input, MANUAL_RULE_NAME.to_owned())
+                        .map(|link| link.map(|l| l.id))
+                        .map_err(|e| e.to_string()),
+                    (None, _) => Err(format!("no port with id {output}")),
+                    (_, None) => Err(format!("no port with id {input}")),
+                };
+                let _ = reply.send(result);
+            }
+            Command::DestroyLink(link_id, reply) => {
+                let link = self
+                    .pipeswitch
+                    .lock_current_state()
+                    .links
+                    .get(&link_id)
+                    .cloned();
+                let result = match link {
+                    Some(link) => self.destroy_link(link).map_err(|e| e.to_string()),
+                    None => Err(format!("no link with id {link_id}")),
+                };
+                let _ = reply.send(result);
+            }
+            Command::SetRuleEnabled {
+                name,
+                enabled,
+                reply,
+            } => {
+                let _ = reply.send(self.set_rule_enabled(&name, enabled));
+            }
+            Command::ActivateProfile(name, reply) => {
+                let _ = reply.send(self.activate_profile(name));
+            }
+        }
+    }
+
+    fn set_rule_enabled(&mut self, rule_name: &str, enabled: bool) -> Result<(), String> {
+        let rule = match self.rules.get_mut(rule_name) {
+            Some(rule) => rule,
+            None => return Err(format!("no rule named [{rule_name}]")),
+        };
+        if rule.enabled == enabled {
+            return Ok(());
+        }
+        rule.enabled = enabled;
+        if enabled {
+            info!("rule [{rule_name}] enabled");
+            self.link_missing(rule_name);
+        } else {
+            info!("rule [{rule_name}] disabled");
+            let link_ids = rule.links.clone();
+            let moved_streams = std::mem::take(&mut rule.moved_streams);
+            self.pending_links.remove(rule_name);
+            self.release_streams(rule_name, &moved_streams);
+            for link in self.fetch_links(&link_ids) {
+                if !self.may_destroy(rule_name, &link) {
+                    continue;
+                }
+                if let Err(e) = self.destroy_link(link) {
+                    error!("failed to destroy link of disabled rule [{rule_name}]: {e}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-matches the rules that follow the default node of the given kind,
+    /// moving their links over to the new default
+    fn default_changed(&mut self, kind: DefaultNode) {
+        let state = self.pipeswitch.lock_current_state();
+        let default_name = state
+            .default_node_name(kind)
+            .unwrap_or("nothing")
+            .to_owned();
+        let ports: Vec<Port> = state.ports.values().cloned().collect();
+        drop(state);
+        info!("{} changed to {default_name}", kind.metadata_key());
+
+        let mut affected = HashSet::new();
+        for rule in self.rules.values_mut() {
+            for side in rule.inputs.iter_mut().chain([&mut rule.output]) {
+                if side.default_node == Some(kind) {
+                    side.forget_ports();
+                    affected.insert(rule.name.clone());
+                }
+            }
+        }
+        if affected.is_empty() {
+            return;
+        }
+
+        for port in ports {
+            self.new_port_for_rules(port, affected.clone());
+        }
+        self.connect_queued_links();
+        if !self.linger_links {
+            for rule_name in &affected {
+                if let Some(rule) = self.rules.get(rule_name) {
+                    if let Err(e) = self.destroy_lingering_links(rule_name, rule) {
+                        error!("failed to destroy lingering links of [{rule_name}]: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Re-matches the ports of a node whose properties changed in place, and
+    /// destroys the links of rules that do not match it anymore
+    fn node_changed(&mut self, old: &Node, node: &Node) {
+        let affected = |rule: &LinkRules| rule.depends_on_change(old, node);
+        if !self.rules.values().any(affected) {
+            trace!("node {node} changed, but no rule matches on what changed");
+            return;
+        }
+        let ports: Vec<Port> = self
+            .pipeswitch
+            .lock_current_state()
+            .ports_by_node(node.id)
+            .into_iter()
+            .cloned()
+            .collect();
+        debug!("node {node} changed, re-checking {} ports", ports.len());
+
+        let mut previously_matched = HashSet::new();
+        for port in &ports {
+            for rule in self.rules.values() {
+                if rule.matches_input(port.id) || rule.output.matching_ports.contains(&port.id) {
+                    previously_matched.insert(rule.name.clone());
+                }
+            }
+            // Nothing flapped, so the ports are matched again right away
+            self.forget_port(port);
+        }
+        for port in ports {
+            self.new_port(port);
+        }
+        self.connect_queued_links();
+        if !self.linger_links {
+            for rule_name in &previously_matched {
+                if let Some(rule) = self.rules.get(rule_name) {
+                    if let Err(e) = self.destroy_lingering_links(rule_name, rule) {
+                        error!("failed to destroy lingering links of [{rule_name}]: {e}");
+                    }
+                }
+            }
+        }
     }
 
     fn new_port(&mut self, port: Port) {
+        if let Some((old, _)) = self.pending_port_removals.remove(&port_key(&port)) {
+            // The same port flapped, so it is only moved over to its new id
+            debug!("port {port} came back as {}, was {}", port.id, old.id);
+            self.forget_port(&old);
+        }
+        self.recheck_layouts(port.node_id);
         self.new_port_for_rules(port, self.rules.keys().cloned().collect())
     }
 
+    fn node_removed(&mut self, node: &Node) {
+        for rule in self.rules.values_mut() {
+            rule.output.forget_node(node.id);
+            for sink in &mut rule.inputs {
+                sink.forget_node(node.id);
+            }
+        }
+        let other_nodes =
+            |(_, output, input): &(String, u32, u32)| *output != node.id && *input != node.id;
+        self.warned_layouts.retain(|key, _| other_nodes(key));
+        self.refused_layouts.retain(other_nodes);
+        self.layout_checks.retain(|key, _| other_nodes(key));
+    }
+
+    /// Links the rules refused for the node again at the end of the batch,
+    /// as its layout may match now
+    fn recheck_layouts(&mut self, node_id: u32) {
+        let refused = self
+            .refused_layouts
+            .iter()
+            .filter(|(_, output, input)| *output == node_id || *input == node_id);
+        for (rule_name, _, _) in refused {
+            self.layout_rechecks.insert(rule_name.clone());
+        }
+    }
+
+    /// Waits a moment before the rules forget the port, in case it comes back
     fn port_deleted(&mut self, port: &Port) {
+        self.timer_count += 1;
+        let timer = self.timer_count;
+        let key = port_key(port);
+        let pending = (port.clone(), timer);
+        if let Some((old, _)) = self.pending_port_removals.insert(key.clone(), pending) {
+            self.forget_port(&old);
+        }
+        let event = Event::PortRemovalElapsed(key, timer);
+        self.timers.schedule(PORT_FLAP_WINDOW, event);
+    }
+
+    fn port_removal_elapsed(&mut self, key: String, timer: u64) {
+        if self.pending_port_removals.get(&key).map(|(_, t)| *t) != Some(timer) {
+            // The port came back, or was removed again and waits for a later timer
+            return;
+        }
+        if let Some((port, _)) = self.pending_port_removals.remove(&key) {
+            self.forget_port(&port);
+        }
+    }
+
+    fn forget_port(&mut self, port: &Port) {
         use pipeswitch_lib::types::Direction;
         match &port.direction {
             Direction::Input => {
                 for rule in self.rules.values_mut() {
-                    rule.input.delete_port(port);
+                    for sink in &mut rule.inputs {
+                        sink.delete_port(port);
+                    }
                 }
             }
             Direction::Output => {
                 for rule in self.rules.values_mut() {
                     rule.output.delete_port(port);
+                    rule.moved_streams.remove(&port.node_id);
+                    rule.release_sink(port);
+                    rule.forget_source_port(port);
                 }
             }
         }
+        for rule in self.rules.values_mut() {
+            rule.dry_run_links
+                .retain(|(output, input)| *output != port.id && *input != port.id);
+        }
+        self.recheck_layouts(port.node_id);
     }
 
     fn link_deleted(&mut self, link: &Link) {
+        let id = link.id;
+        let external = !self.destroyed_links.borrow_mut().remove(&id);
+        if external && self.rules.values().any(|rule| rule.links.contains(&id)) {
+            // Its ports may be flapping, and the link made again once they are back
+            self.timer_count += 1;
+            let timer = self.timer_count;
+            trace!("link {id} removed, waiting to see whether it is made again");
+            self.lost_links.insert(id, (link.clone(), timer));
+            let event = Event::LinkRemovalElapsed(id, timer);
+            self.timers.schedule(PORT_FLAP_WINDOW, event);
+            return;
+        }
+        self.forget_link(id, external);
+    }
+
+    fn link_removal_elapsed(&mut self, id: u32, timer: u64) {
+        if self.lost_links.get(&id).map(|(_, t)| *t) != Some(timer) {
+            // Replaced by a link between the same ports meanwhile
+            return;
+        }
+        self.lost_links.remove(&id);
+        self.forget_link(id, true);
+    }
+
+    /// The lost link of the rule that the link replaces, ie. one between the
+    /// same ports from before they flapped
+    fn lost_link_replaced_by(&self, rule: &LinkRules, link: &Link) -> Option<u32> {
+        link.output_alias.as_ref()?;
+        self.lost_links
+            .iter()
+            .filter(|(id, _)| rule.links.contains(id))
+            .find(|(_, (lost, _))| {
+                lost.output_alias == link.output_alias && lost.input_alias == link.input_alias
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Removes the link from its rules, reconnecting them if it was removed
+    /// by someone else
+    fn forget_link(&mut self, id: u32, external: bool) {
+        let mut reconnect = Vec::new();
+        let mut removed = false;
         for rule in self.rules.values_mut() {
-            let id = link.id;
             if rule.links.remove(&id) {
                 let rule_name = &rule.name;
-                trace!("Link {id} from rule [{rule_name}] deleted");
+                if external {
+                    debug!("link {id} of rule [{rule_name}] externally removed");
+                } else {
+                    trace!("link {id} of rule [{rule_name}] destroyed by pipeswitch");
+                }
+                Metrics::increment(&self.metrics.links_removed);
+                removed = true;
+                if external && self.auto_reconnect && rule.enabled {
+                    reconnect.push((rule.name.clone(), rule.link_delay));
+                }
             }
         }
+        self.update_rule_metrics();
+        if removed {
+            self.save_links();
+        }
+
+        for (rule_name, delay) in reconnect {
+            info!("link {id} of rule [{rule_name}] was removed externally, reconnecting");
+            // Wait a moment, since the link may have been removed along with its ports
+            self.delay_linking(rule_name, delay.unwrap_or(RECONNECT_DELAY));
+        }
     }
 
     fn new_port_for_rules(&mut self, port: Port, rules: HashSet<String>) {
         use pipeswitch_lib::types::Direction;
-        let mut state = self.pipeswitch.lock_current_state();
-        for (_, rule) in self.rules.iter_mut().filter(|(n, _)| {
-            // dbg!(&n, &rules, rules.contains(*n));
-            rules.contains(*n)
-        }) {
-            let (r1, r2) = if let Direction::Input = port.direction {
-                (&mut rule.input, &mut rule.output)
+        let state = self.pipeswitch.lock_current_state();
+        // Higher priority rules get the first claim on the port
+        let mut ordered: Vec<&mut LinkRules> = self
+            .rules
+            .values_mut()
+            .filter(|rule| rules.contains(&rule.name))
+            .collect();
+        ordered.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+
+        let mut to_link = Vec::new();
+        let mut to_delay = Vec::new();
+        for rule in ordered {
+            let matched = if let Direction::Input = port.direction {
+                // Every sink gets to match the port, not only the first one
+                rule.inputs.iter_mut().fold(false, |matched, sink| {
+                    sink.add_if_matches(&port, &state) || matched
+                })
             } else {
-                (&mut rule.output, &mut rule.input)
+                let matched = rule.output.add_if_matches(&port, &state);
+                if matched {
+                    rule.assign_sink(&port);
+                }
+                matched
             };
-            if r1.add_if_matches(&port, &state) {
-                for old_port_id in &r2.matching_ports {
-                    let old_port = state.ports.get(old_port_id).unwrap().clone();
-                    if r1.should_ignore_channel(r2) || port.channel == old_port.channel {
-                        let op_alias = old_port.alias.clone();
-                        let (i_name, o_name) = if let Direction::Input = port.direction {
-                            (&port.alias, &op_alias)
+            if matched && !rule.channel_map.is_empty() {
+                self.mapped_rechecks.insert(rule.name.clone());
+            }
+            if matched && rule.enabled {
+                if let Some(delay) = rule.link_delay {
+                    to_delay.push((rule.name.clone(), delay));
+                } else {
+                    let other_ports = if let Direction::Input = port.direction {
+                        rule.output.matching_ports.clone()
+                    } else {
+                        rule.input_ports()
+                    };
+                    for old_port in sorted_ports(&other_ports, &state) {
+                        let (output, input) = if let Direction::Input = port.direction {
+                            (old_port, port.clone())
                         } else {
-                            (&op_alias, &port.alias)
+                            (port.clone(), old_port)
                         };
-                        drop(state);
-                        if let Some(link) = self
-                            .pipeswitch
-                            .create_link(port.clone(), old_port, rule.name.clone())
-                            .unwrap()
-                        {
-                            let link_id = link.id;
-                            info!("connected {o_name} to {i_name} ({link_id})");
+                        if rule.should_link(&output, &input, &state) {
+                            to_link.push((rule.name.clone(), output, input));
+                        }
+                    }
+                }
+                if self.stop_on_first_match {
+                    break;
+                }
+            }
+        }
+        drop(state);
+
+        for (rule_name, delay) in to_delay {
+            self.delay_linking(rule_name, delay);
+        }
+        self.queued_links.extend(to_link);
+    }
+
+    /// Creates the links queued for new ports, all with a single roundtrip
+    fn connect_queued_links(&mut self) {
+        let queued = std::mem::take(&mut self.queued_links);
+        if !queued.is_empty() {
+            // Already logged, and the links are tried again on the next change
+            let _ = self.connect_ports(queued);
+        }
+        for rule_name in std::mem::take(&mut self.layout_rechecks) {
+            // Refused again while linking if the layouts still differ
+            let other_rules = |(name, _, _): &(String, u32, u32)| *name != rule_name;
+            self.refused_layouts.retain(other_rules);
+            self.link_missing(&rule_name);
+        }
+        let mapped = std::mem::take(&mut self.mapped_rechecks);
+        self.warn_missing_mapped_channels(mapped);
+    }
+
+    /// Warns about channels of the channel_map of the rules that their ports
+    /// do not have, unless the same channels were warned about last time
+    fn warn_missing_mapped_channels(&mut self, rule_names: impl IntoIterator<Item = String>) {
+        let state = self.pipeswitch.lock_current_state();
+        for rule_name in rule_names {
+            let missing = match self.rules.get(&rule_name) {
+                Some(rule) => rule.missing_mapped_channels(&state),
+                None => continue,
+            };
+            if missing.is_empty() {
+                self.missing_mapped.remove(&rule_name);
+            } else if self.missing_mapped.get(&rule_name) != Some(&missing) {
+                warn!("channels {missing:?} of the channel_map of [{rule_name}] were not found");
+                self.missing_mapped.insert(rule_name, missing);
+            }
+        }
+    }
+
+    /// Links the rule once no new matching ports have appeared for `delay`
+    fn delay_linking(&mut self, rule_name: String, delay: Duration) {
+        self.timer_count += 1;
+        let timer = self.timer_count;
+        trace!("linking [{rule_name}] delayed by {}ms", delay.as_millis());
+        self.pending_links.insert(rule_name.clone(), timer);
+        let event = Event::LinkDelayElapsed(rule_name, timer);
+        self.timers.schedule(delay, event);
+    }
+
+    /// Links the rules still waiting for their link delay without waiting, for
+    /// when nothing will be around once the delay has elapsed
+    fn link_delayed_now(&mut self) {
+        let rule_names: Vec<String> = self.pending_links.drain().map(|(name, _)| name).collect();
+        for rule_name in rule_names {
+            self.link_missing(&rule_name);
+        }
+    }
+
+    fn link_delay_elapsed(&mut self, rule_name: String, timer: u64) {
+        if self.pending_links.get(&rule_name) != Some(&timer) {
+            // Another port has appeared meanwhile and restarted the delay
+            return;
+        }
+        self.pending_links.remove(&rule_name);
+        self.link_missing(&rule_name);
+    }
+
+    /// Links every pair of matching ports of the rule that is not linked yet,
+    /// already linked pairs are skipped by `connect_ports`
+    fn link_missing(&mut self, rule_name: &str) {
+        let rule = match self.rules.get(rule_name) {
+            Some(rule) if rule.enabled => rule,
+            _ => return,
+        };
+        let state = self.pipeswitch.lock_current_state();
+        let mut to_link = Vec::new();
+        let input_ports = sorted_ports(&rule.input_ports(), &state);
+        for output in sorted_ports(&rule.output.matching_ports, &state) {
+            for input in &input_ports {
+                if rule.should_link(&output, input, &state) {
+                    to_link.push((rule_name.to_owned(), output.clone(), input.clone()));
+                }
+            }
+        }
+        drop(state);
+
+        // Already logged, and the links are tried again on the next change
+        let _ = self.connect_ports(to_link);
+    }
+
+    /// Whether the channel layouts of the nodes of the ports differ in size
+    /// and the rule pairs their ports up by channel
+    fn channel_mismatch(&self, rule_name: &str, output: &Port, input: &Port) -> bool {
+        match self.rules.get(rule_name) {
+            Some(rule) if rule.pairs_by_channel(output, input) => {
+                let mismatch = self.layout_mismatch(output.node_id, input.node_id);
+                mismatch.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// The sizes of the channel layouts of the output and input node and a
+    /// description of them, if they differ in size
+    fn layout_mismatch(&self, output: u32, input: u32) -> Option<((usize, usize), String)> {
+        use pipeswitch_lib::types::Direction;
+        let state = self.pipeswitch.lock_current_state();
+        let outputs = state.channel_layout(output, Direction::Output);
+        let inputs = state.channel_layout(input, Direction::Input);
+        if outputs.len() == inputs.len() {
+            return None;
+        }
+        let describe = |node_id: u32, layout: &[pipeswitch_lib::types::Channel]| {
+            let name = match state.nodes.get(&node_id) {
+                Some(node) => node.to_string(),
+                None => format!("node {node_id}"),
+            };
+            let channels: Vec<String> = layout.iter().map(ToString::to_string).collect();
+            format!("{name} ({})", channels.join(", "))
+        };
+        let (from, to) = (describe(output, &outputs), describe(input, &inputs));
+        Some(((outputs.len(), inputs.len()), format!("{from} to {to}")))
+    }
+
+    /// Compares the layouts again once no mismatching port has been seen for
+    /// `PORT_FLAP_WINDOW`, since a node announces its ports one at a time
+    fn check_layouts_later(&mut self, key: (String, u32, u32)) {
+        self.timer_count += 1;
+        let timer = self.timer_count;
+        let (rule_name, output, input) = key.clone();
+        self.layout_checks.insert(key, timer);
+        let event = Event::LayoutCheckElapsed(rule_name, output, input, timer);
+        self.timers.schedule(PORT_FLAP_WINDOW, event);
+    }
+
+    /// Warns about layouts that still differ, unless they were already warned
+    /// about with the same sizes, and links the ports refused meanwhile if they
+    /// match after all
+    fn layout_check_elapsed(&mut self, key: (String, u32, u32), timer: u64) {
+        if self.layout_checks.get(&key) != Some(&timer) {
+            return;
+        }
+        self.layout_checks.remove(&key);
+        let (rule_name, output, input) = &key;
+        if !self.rules.contains_key(rule_name) {
+            return;
+        }
+        match self.layout_mismatch(*output, *input) {
+            Some((sizes, mismatch)) => {
+                if self.warned_layouts.insert(key.clone(), sizes) == Some(sizes) {
+                    return;
+                }
+                if self.strict_channels {
+                    warn!("refused to link [{rule_name}]: {mismatch}");
+                } else {
+                    warn!("[{rule_name}] links {mismatch}, only matching channels are linked");
+                }
+            }
+            None => {
+                self.warned_layouts.remove(&key);
+                if self.refused_layouts.remove(&key) {
+                    let rule_name = rule_name.clone();
+                    self.link_missing(&rule_name);
+                }
+            }
+        }
+    }
+
+    /// Moves the node of the output port to the node of the input port, once
+    /// per stream, instead of linking the ports
+    fn move_stream(&mut self, rule_name: &str, output: &Port, input: &Port) {
+        let state = self.pipeswitch.lock_current_state();
+        let (stream, target) = match (
+            state.nodes.get(&output.node_id),
+            state.nodes.get(&input.node_id),
+        ) {
+            (Some(stream), Some(target)) => (stream.clone(), target.clone()),
+            _ => return,
+        };
+        drop(state);
+        // Only streams follow target.object, setting it on a device does nothing
+        let class = stream.media_class.as_deref().unwrap_or_default();
+        if !class.starts_with("Stream/") {
+            debug!("not moving {stream} for [{rule_name}], it is not a stream");
+            return;
+        }
+        let newly_moved = match self.rules.get_mut(rule_name) {
+            Some(rule) => rule.moved_streams.insert(stream.id),
+            None => false,
+        };
+        if !newly_moved {
+            return;
+        }
+        if self.dry_run {
+            info!("would move {stream} to {target} for [{rule_name}]");
+            return;
+        }
+        match self
+            .pipeswitch
+            .set_stream_target(stream.id, Some(&target.node_name))
+        {
+            Ok(true) => info!("moved {stream} to {target} for [{rule_name}]"),
+            Ok(false) => warn!("failed to move {stream} to {target} for [{rule_name}]"),
+            Err(e) => error!("failed to move {stream} to {target}: {e}"),
+        }
+    }
+
+    /// Links each output to its input for the rule, creating all of the links
+    /// that pass the checks with a single roundtrip
+    /// Fails only if none of the links could be asked for, ie. when the link
+    /// factory is gone. Links PipeWire refused are counted and skipped.
+    fn connect_ports(&mut self, to_link: Vec<(String, Port, Port)>) -> Result<(), PipeswitchError> {
+        if !self.dry_run && !to_link.is_empty() && !self.pipeswitch.link_factory_known() {
+            self.await_link_factory(to_link);
+            return Ok(());
+        }
+        let mut rule_names = Vec::new();
+        let mut links = Vec::new();
+        for (rule_name, output, input) in to_link {
+            if let Some(link) = self.prepare_link(&rule_name, output, input) {
+                rule_names.push(rule_name);
+                links.push(link);
+            }
+        }
+        if links.is_empty() {
+            return Ok(());
+        }
+        if links.len() > 1 {
+            debug!("creating {} links at once", links.len());
+        }
+        let names: Vec<(String, String)> = links
+            .iter()
+            .map(|link| (link.output().alias.clone(), link.input().alias.clone()))
+            .collect();
+        match self.pipeswitch.create_links(links, DEFAULT_LINK_TIMEOUT) {
+            Ok(created) => {
+                for (rule_name, link) in rule_names.iter().zip(created) {
+                    match link {
+                        Some(link) if self.replaces_lost_link(rule_name, &link) => {
+                            trace!("connected {link} again after its ports flapped")
+                        }
+                        Some(link) => {
+                            let name = match &link.link_name {
+                                Some(name) => format!(" as {name}"),
+                                None => String::new(),
+                            };
+                            log_link_event(
+                                "connected",
+                                rule_name,
+                                link.id,
+                                format_args!("connected {link}{name}"),
+                            )
+                        }
+                        None => Metrics::increment(&self.metrics.link_errors),
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for (o_name, i_name) in names {
+                    Metrics::increment(&self.metrics.link_errors);
+                    error!("failed to connect {o_name} to {i_name}: {e}")
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn replaces_lost_link(&self, rule_name: &str, link: &Link) -> bool {
+        match self.rules.get(rule_name) {
+            Some(rule) => self.lost_link_replaced_by(rule, link).is_some(),
+            None => false,
+        }
+    }
+
+    /// Keeps the links until the link factory has been discovered, which may
+    /// happen a moment after the ports right after PipeWire has started
+    fn await_link_factory(&mut self, to_link: Vec<(String, Port, Port)>) {
+        if self.factory_timer.is_none() {
+            let count = to_link.len();
+            debug!("{count} links deferred until the link factory has been discovered");
+            self.timer_count += 1;
+            let timer = self.timer_count;
+            self.factory_timer = Some(timer);
+            let event = Event::LinkFactoryElapsed(timer);
+            self.timers.schedule(DEFAULT_LINK_TIMEOUT, event);
+        }
+        self.awaiting_factory.extend(to_link);
+    }
+
+    fn link_factory_found(&mut self) {
+        self.factory_timer = None;
+        let awaiting = std::mem::take(&mut self.awaiting_factory);
+        if awaiting.is_empty() {
+            return;
+        }
+        let count = awaiting.len();
+        debug!("link factory discovered, linking {count} deferred links");
+        let state = self.pipeswitch.lock_current_state();
+        // Ports may have gone away while waiting
+        let awaiting: Vec<(String, Port, Port)> = awaiting
+            .into_iter()
+            .filter(|(_, output, input)| {
+                state.ports.contains_key(&output.id) && state.ports.contains_key(&input.id)
+            })
+            .collect();
+        drop(state);
+        // Already logged, and the links are tried again on the next change
+        let _ = self.connect_ports(awaiting);
+    }
+
+    fn link_factory_elapsed(&mut self, timer: u64) {
+        if self.factory_timer != Some(timer) {
+            return;
+        }
+        self.factory_timer = None;
+        for (_, output, input) in std::mem::take(&mut self.awaiting_factory) {
+            Metrics::increment(&self.metrics.link_errors);
+            error!(
+                "failed to connect {} to {}: {}",
+                output.alias,
+                input.alias,
+                PipeswitchError::NoLinkFactory
+            );
+        }
+    }
+
+    /// Checks whether the rule may link the ports, displacing other links if
+    /// it is exclusive. Gives the link to create, unless there is nothing
+    /// left to create.
+    fn prepare_link(&mut self, rule_name: &str, output: Port, input: Port) -> Option<NewLink> {
+        if self
+            .rules
+            .get(rule_name)
+            .map_or(false, |rule| rule.move_stream)
+        {
+            self.move_stream(rule_name, &output, &input);
+            return None;
+        }
+        if output.node_id == input.node_id {
+            warn!("refused to link {output} to {input} for [{rule_name}], both are on one node");
+            return None;
+        }
+        if self.prevent_cycles
+            && self
+                .pipeswitch
+                .lock_current_state()
+                .node_reaches(input.node_id, output.node_id)
+        {
+            warn!("refused to link {output} to {input} for [{rule_name}], it would form a cycle");
+            return None;
+        }
+        if self.channel_mismatch(rule_name, &output, &input) {
+            // Warned about once the nodes have settled
+            let key = (rule_name.to_owned(), output.node_id, input.node_id);
+            self.check_layouts_later(key.clone());
+            if self.strict_channels {
+                trace!("not linking {output} to {input} for [{rule_name}], layouts differ");
+                self.refused_layouts.insert(key);
+                return None;
+            }
+        }
+        if self.reconcile_existing_link(rule_name, &output, &input) {
+            return None;
+        }
+        let rule = match self.rules.get(rule_name) {
+            Some(rule) => rule,
+            None => return None,
+        };
+        if rule.exclusive {
+            let displaced: Vec<Link> = self
+                .pipeswitch
+                .lock_current_state()
+                .links
+                .values()
+                .filter(|l| {
+                    l.output_port == output.id
+                        && l.rule_name.as_deref() != Some(rule_name)
+                        && !rule.links.contains(&l.id)
+                })
+                .cloned()
+                .collect();
+            for link in displaced {
+                let link_id = link.id;
+                if self.linger_links {
+                    debug!("lingering link {link_id} not displaced by [{rule_name}]");
+                } else if self.may_destroy(rule_name, &link) {
+                    match self.destroy_link(link) {
+                        Ok(true) => log_link_event(
+                            "displaced",
+                            rule_name,
+                            link_id,
+                            format_args!(
+                                "link {link_id} displaced by exclusive rule [{rule_name}]"
+                            ),
+                        ),
+                        Ok(false) => {}
+                        Err(e) => {
+                            error!("failed to displace link {link_id} for [{rule_name}]: {e}")
                         }
-                        state = self.pipeswitch.lock_current_state();
                     }
                 }
             }
         }
+
+        let mut properties = rule.link_properties.clone();
+        if let Some(name) = rule.link_name(&output) {
+            properties.insert(KEY_LINK_NAME.to_owned(), name);
+        }
+        let (o_name, i_name) = (output.alias.clone(), input.alias.clone());
+        if self.dry_run {
+            let rule = self.rules.get_mut(rule_name)?;
+            if rule.dry_run_links.insert((output.id, input.id)) {
+                info!("would connect {o_name} to {i_name} for [{rule_name}]");
+            }
+            return None;
+        }
+        match NewLink::new(output, input, rule_name.to_owned()) {
+            Ok(link) => Some(link.with_properties(properties)),
+            Err(e) => {
+                Metrics::increment(&self.metrics.link_errors);
+                error!("failed to connect {o_name} to {i_name}: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// What a port is recognized by when it is removed and added again, since it
+/// gets a new id
+fn port_key(port: &Port) -> String {
+    match &port.path {
+        Some(path) => path.clone(),
+        None => format!("{} {}", port.alias, port.direction),
+    }
+}
+
+/// Logs a link being connected or destroyed, with the event, rule and link id
+/// as fields of their own for the JSON log format
+fn log_link_event(event: &str, rule_name: &str, link_id: u32, message: std::fmt::Arguments) {
+    let fields = [
+        ("event", event.into()),
+        ("rule", rule_name.into()),
+        ("link_id", link_id.into()),
+    ];
+    if !json_log::log_with_fields(Level::Info, module_path!(), message, &fields) {
+        info!("{message}");
+    }
+}
+
+/// Logs a rule becoming connected or losing its links, with the event and
+/// rule as fields of their own for the JSON log format
+fn log_rule_event(event: &str, rule_name: &str, message: std::fmt::Arguments) {
+    let fields = [("event", event.into()), ("rule", rule_name.into())];
+    if !json_log::log_with_fields(Level::Info, module_path!(), message, &fields) {
+        info!("{message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, mpsc::Receiver};
+
+    use pipeswitch_lib::{
+        types::{Direction, KEY_RULE_NAME},
+        ObjectType, PipewireState, StateBuilder,
+    };
+
+    use super::*;
+    use Channel::*;
+    use Direction::{Input, Output};
+
+    /// A config with the default settings and the given `[link.*]` tables
+    fn config(links: &str) -> Config {
+        let general = "[general]\nlinger_links = false\nhotreload_config = false\n";
+        let toml = format!("{general}[log]\nlevel = \"info\"\n{links}");
+        Config::from_string(&toml).unwrap().0
+    }
+
+    /// A daemon over the graph that keeps no links on disk, along with the
+    /// events it schedules. PipeWire itself is gone, so anything asking it
+    /// fails.
+    fn daemon(
+        state: PipewireState,
+        config: &Config,
+        dry_run: bool,
+    ) -> (PipeswitchDaemon, Receiver<Event>) {
+        let (sender, receiver) = channel();
+        let pipeswitch = Pipeswitch::offline(state);
+        let daemon = PipeswitchDaemon::new(pipeswitch, config, sender, dry_run, None);
+        (daemon, receiver)
+    }
+
+    /// A stereo source and sink, with the left channels linked by rule `test`
+    fn stereo_graph() -> StateBuilder {
+        let link = [
+            ("link.output.port", "3"),
+            ("link.input.port", "6"),
+            (KEY_RULE_NAME, "test"),
+        ];
+        StateBuilder::new()
+            .client(1, "app")
+            .node(2, 1, "source")
+            .port(3, 2, "out_FL", Output, Left)
+            .port(4, 2, "out_FR", Output, Right)
+            .node(5, 1, "sink")
+            .port(6, 5, "in_FL", Input, Left)
+            .port(7, 5, "in_FR", Input, Right)
+            .object(ObjectType::Link, 8, &link)
+    }
+
+    const STEREO_RULE: &str = "[link.test]\nsource = \"source\"\nsink = \"sink\"\n";
+
+    #[test]
+    fn shutdown_survives_pipewire_being_gone() {
+        let mut config = config(STEREO_RULE);
+        config.general.unlink_on_exit = true;
+        let (daemon, _events) = daemon(stereo_graph().build().unwrap(), &config, false);
+        assert!(daemon.rules["test"].links.contains(&8));
+        // Destroying the link fails, which is logged instead of panicking
+        daemon.shutdown();
+        assert!(daemon.destroyed_links.borrow().is_empty());
+        drop(daemon);
+    }
+
+    #[test]
+    fn flapping_ports_keep_their_links() {
+        let state = stereo_graph().build().unwrap();
+        let (mut daemon, events) = daemon(state, &config(STEREO_RULE), true);
+        let counts = |daemon: &PipeswitchDaemon| {
+            let metrics = &daemon.metrics;
+            let created = metrics.links_created.load(Ordering::Relaxed);
+            (created, metrics.links_removed.load(Ordering::Relaxed))
+        };
+        let before = counts(&daemon);
+        let (link, port) = {
+            let state = daemon.pipeswitch.lock_current_state();
+            (state.links[&8].clone(), state.ports[&3].clone())
+        };
+        // The link goes away with its port, which comes back under a new id
+        // and is linked again by the rule
+        daemon.link_deleted(&link);
+        daemon.port_deleted(&port);
+        let link = [
+            ("link.output.port", "13"),
+            ("link.input.port", "6"),
+            (KEY_RULE_NAME, "test"),
+        ];
+        let flapped = StateBuilder::new()
+            .client(1, "app")
+            .node(2, 1, "source")
+            .port(13, 2, "out_FL", Output, Left)
+            .port(4, 2, "out_FR", Output, Right)
+            .node(5, 1, "sink")
+            .port(6, 5, "in_FL", Input, Left)
+            .port(7, 5, "in_FR", Input, Right)
+            .object(ObjectType::Link, 18, &link)
+            .build()
+            .unwrap();
+        let (port, link) = (flapped.ports[&13].clone(), flapped.links[&18].clone());
+        daemon.pipeswitch.replace_state(flapped);
+        daemon.new_port(port);
+        daemon.connect_queued_links();
+        daemon.new_link(link);
+        // Once the window has passed, nothing is left to forget
+        for _ in 0..2 {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Event::PortRemovalElapsed(key, timer) => daemon.port_removal_elapsed(key, timer),
+                Event::LinkRemovalElapsed(id, timer) => daemon.link_removal_elapsed(id, timer),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(daemon.rules["test"].links, HashSet::from([18]));
+        assert_eq!(counts(&daemon), before);
+    }
+
+    #[test]
+    fn new_ports_are_linked_in_port_order() {
+        // Mono ports whose global ids are in another order than their own
+        let graph = |with_sink_port: bool| {
+            let builder = StateBuilder::new()
+                .client(1, "app")
+                .node(2, 1, "source")
+                .port(40, 2, "out_a", Output, Mono)
+                .with(&[("port.id", "0")])
+                .port(11, 2, "out_b", Output, Mono)
+                .with(&[("port.id", "1")])
+                .port(27, 2, "out_c", Output, Mono)
+                .with(&[("port.id", "2")])
+                .node(5, 1, "sink");
+            let builder = if with_sink_port {
+                builder.port(6, 5, "in_MONO", Input, Mono)
+            } else {
+                builder
+            };
+            builder.build().unwrap()
+        };
+        let config = config(STEREO_RULE);
+        for _ in 0..10 {
+            let (mut daemon, _events) = daemon(graph(false), &config, true);
+            let state = graph(true);
+            let port = state.ports[&6].clone();
+            daemon.pipeswitch.replace_state(state);
+            daemon.new_port(port);
+            let queued: Vec<(u32, u32)> = daemon
+                .queued_links
+                .iter()
+                .map(|(_, output, input)| (output.id, input.id))
+                .collect();
+            assert_eq!(queued, [(40, 6), (11, 6), (27, 6)]);
+        }
+    }
+
+    #[test]
+    fn media_name_is_matched_again_when_it_changes() {
+        let graph = |title| {
+            StateBuilder::new()
+                .client(1, "app")
+                .node(2, 1, "browser")
+                .with(&[("media.name", title)])
+                .port(3, 2, "output_MONO", Output, Mono)
+                .node(5, 1, "sink")
+                .port(6, 5, "playback_MONO", Input, Mono)
+                .build()
+                .unwrap()
+        };
+        let link = "[link.test]\nsource = { media_name = \"Song B\" }\nsink = \"sink\"\n";
+        let (mut daemon, _events) = daemon(graph("Song A"), &config(link), true);
+        assert!(daemon.rules["test"].dry_run_links.is_empty());
+        let old = daemon.pipeswitch.lock_current_state().nodes[&2].clone();
+        let state = graph("Song B");
+        let node = state.nodes[&2].clone();
+        daemon.pipeswitch.replace_state(state);
+        daemon.node_changed(&old, &node);
+        assert_eq!(daemon.rules["test"].dry_run_links, HashSet::from([(3, 6)]));
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_previous_rules() {
+        let previous = config(STEREO_RULE);
+        let (mut daemon, _events) = daemon(stereo_graph().build().unwrap(), &previous, false);
+        let links = r#"
+            [link.test]
+            source = "source"
+            sink = "sink"
+            exclusive = true
+
+            [link.other]
+            source = "sink"
+            sink = "source"
+        "#;
+        // Destroying the old link of the changed rule fails halfway through
+        assert!(daemon.update_config(&config(links)).is_err());
+        assert_eq!(daemon.rules.len(), 1);
+        assert!(!daemon.rules["test"].exclusive);
+        assert_eq!(daemon.rules["test"].links, HashSet::from([8]));
+        assert!(daemon.config.links.keys().eq(["test"]));
+        assert_eq!(daemon.metrics.config_reloads.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn switching_profiles_swaps_their_rules() {
+        /// The links the rules would make, by rule
+        fn linked(daemon: &PipeswitchDaemon) -> Vec<(&str, (u32, u32))> {
+            let rules = daemon.rules.values();
+            let links = rules.flat_map(|rule| rule.dry_run_links.iter().map(|l| (&*rule.name, *l)));
+            links.collect()
+        }
+        let profiles = r#"
+            [link]
+
+            [profile.left.link.left]
+            source = { node = "source", channel = "FL" }
+            sink = { node = "sink", channel = "FL" }
+
+            [profile.right.link.right]
+            source = { node = "source", channel = "FR" }
+            sink = { node = "sink", channel = "FR" }
+        "#;
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(2, 1, "source")
+            .port(3, 2, "out_FL", Output, Left)
+            .port(4, 2, "out_FR", Output, Right)
+            .node(5, 1, "sink")
+            .port(6, 5, "in_FL", Input, Left)
+            .port(7, 5, "in_FR", Input, Right)
+            .build()
+            .unwrap();
+        // The profiles are not active, so none of their rules link anything
+        let (mut daemon, _events) = daemon(state, &config(profiles), true);
+        assert!(daemon.rules.is_empty());
+        daemon.activate_profile(Some("left".to_owned())).unwrap();
+        assert_eq!(linked(&daemon), [("left", (3, 6))]);
+        daemon.activate_profile(Some("right".to_owned())).unwrap();
+        assert_eq!(linked(&daemon), [("right", (4, 7))]);
+        assert!(daemon.activate_profile(Some("center".to_owned())).is_err());
+        assert_eq!(daemon.active_profile.as_deref(), Some("right"));
+        daemon.activate_profile(None).unwrap();
+        assert!(daemon.rules.is_empty());
+    }
+
+    #[test]
+    fn overlapping_rules_claim_ports_by_priority() {
+        let graph = |with_sink_port: bool| {
+            let builder = StateBuilder::new()
+                .client(1, "app")
+                .node(2, 1, "source")
+                .port(3, 2, "out_MONO", Output, Mono)
+                .node(5, 1, "sink");
+            let builder = if with_sink_port {
+                builder.port(6, 5, "in_MONO", Input, Mono)
+            } else {
+                builder
+            };
+            builder.build().unwrap()
+        };
+        let rules = |stop_on_first_match: bool| {
+            let mut config = config(
+                "[link.a]\nsource = \"source\"\nsink = \"sink\"\n\
+                [link.b]\nsource = \"source\"\nsink = \"sink\"\npriority = 10\n\
+                [link.c]\nsource = \"source\"\nsink = \"sink\"\n",
+            );
+            config.general.stop_on_first_match = stop_on_first_match;
+            config
+        };
+        // Rules of the same priority go by name, whatever order they are kept in
+        for (stop_on_first_match, expected) in [(false, vec!["b", "a", "c"]), (true, vec!["b"])] {
+            let config = rules(stop_on_first_match);
+            for _ in 0..10 {
+                let (mut daemon, _events) = daemon(graph(false), &config, true);
+                let state = graph(true);
+                let port = state.ports[&6].clone();
+                daemon.pipeswitch.replace_state(state);
+                daemon.new_port(port);
+                let claimed: Vec<&str> = daemon
+                    .queued_links
+                    .iter()
+                    .map(|(rule_name, _, _)| rule_name.as_str())
+                    .collect();
+                assert_eq!(claimed, expected);
+            }
+        }
     }
 }
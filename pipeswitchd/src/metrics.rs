@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::*;
+use tiny_http::{Header, Response, Server};
+
+/// Counters kept up to date by the daemon and read by the metrics server
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub config_reloads: AtomicU64,
+    pub links_created: AtomicU64,
+    pub links_removed: AtomicU64,
+    pub link_errors: AtomicU64,
+    pub rule_links: Mutex<BTreeMap<String, usize>>,
+    /// Rules that have every link they expect
+    pub connected_rules: Mutex<BTreeSet<String>>,
+    /// How long PipeWire took to create each batch of links
+    pub link_latency: Mutex<Histogram>,
+}
+
+/// Upper bounds of the link latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations of each bucket alone, the last one being above every bound
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_link_latency(&self, latency: Duration) {
+        self.link_latency
+            .lock()
+            .unwrap()
+            .observe(latency.as_secs_f64());
+    }
+
+    fn render(&self) -> String {
+        let rule_links = self.rule_links.lock().unwrap();
+        let connected_rules = self.connected_rules.lock().unwrap();
+        let mut text = String::new();
+        let mut counter = |name: &str, help: &str, value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(text, "# HELP pipeswitch_{name} {help}");
+            let _ = writeln!(text, "# TYPE pipeswitch_{name} counter");
+            let _ = writeln!(text, "pipeswitch_{name} {value}");
+        };
+        counter(
+            "config_reloads_total",
+            "Times the config has been applied",
+            &self.config_reloads,
+        );
+        counter(
+            "links_created_total",
+            "Links created by rules",
+            &self.links_created,
+        );
+        counter(
+            "links_removed_total",
+            "Managed links that have been destroyed",
+            &self.links_removed,
+        );
+        counter(
+            "link_errors_total",
+            "Failed attempts to create a link",
+            &self.link_errors,
+        );
+
+        let managed: usize = rule_links.values().sum();
+        let _ = writeln!(
+            text,
+            "# HELP pipeswitch_managed_links Links currently managed"
+        );
+        let _ = writeln!(text, "# TYPE pipeswitch_managed_links gauge");
+        let _ = writeln!(text, "pipeswitch_managed_links {managed}");
+        let _ = writeln!(
+            text,
+            "# HELP pipeswitch_rule_links Links currently managed per rule"
+        );
+        let _ = writeln!(text, "# TYPE pipeswitch_rule_links gauge");
+        for (rule_name, links) in rule_links.iter() {
+            let rule_name = rule_name.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                text,
+                "pipeswitch_rule_links{{rule=\"{rule_name}\"}} {links}"
+            );
+        }
+        let _ = writeln!(
+            text,
+            "# HELP pipeswitch_rule_connected Whether the rule has every link it expects"
+        );
+        let _ = writeln!(text, "# TYPE pipeswitch_rule_connected gauge");
+        for rule_name in rule_links.keys() {
+            let connected = u8::from(connected_rules.contains(rule_name));
+            let rule_name = rule_name.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                text,
+                "pipeswitch_rule_connected{{rule=\"{rule_name}\"}} {connected}"
+            );
+        }
+
+        let latency = self.link_latency.lock().unwrap();
+        let name = "pipeswitch_link_latency_seconds";
+        let _ = writeln!(text, "# HELP {name} Time taken to create a batch of links");
+        let _ = writeln!(text, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+            cumulative += count;
+            let _ = writeln!(text, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(text, "{name}_bucket{{le=\"+Inf\"}} {}", latency.count);
+        let _ = writeln!(text, "{name}_sum {}", latency.sum);
+        let _ = writeln!(text, "{name}_count {}", latency.count);
+        text
+    }
+}
+
+pub struct MetricsServer {
+    server: Arc<Server>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn start(port: u16, metrics: Arc<Metrics>) -> Result<MetricsServer> {
+        let server = Arc::new(Server::http(("127.0.0.1", port)).map_err(|e| anyhow!(e))?);
+        let join_handle = std::thread::spawn({
+            let server = server.clone();
+            move || {
+                let content_type =
+                    Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                        .unwrap();
+                for request in server.incoming_requests() {
+                    let response = if request.url() == "/metrics" {
+                        Response::from_string(metrics.render())
+                            .with_header(content_type.clone())
+                            .boxed()
+                    } else {
+                        Response::from_string("not found")
+                            .with_status_code(404)
+                            .boxed()
+                    };
+                    if let Err(e) = request.respond(response) {
+                        debug!("failed to respond to metrics request: {e}");
+                    }
+                }
+            }
+        });
+        Ok(MetricsServer {
+            server,
+            join_handle: Some(join_handle),
+        })
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        self.join_handle.take().unwrap().join().unwrap()
+    }
+}
@@ -0,0 +1,86 @@
+use std::{net::UdpSocket, sync::mpsc::Sender, thread::JoinHandle};
+
+use anyhow::Result;
+use log::*;
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::config::Event;
+use crate::control::{self, Command};
+
+/// Listens for OSC messages on localhost in a background thread. Understood
+/// addresses are `/pipeswitch/reload`, `/pipeswitch/rule/<name>/enable` or
+/// `/disable`, and `/pipeswitch/profile/<name>` or `/pipeswitch/profile/off`.
+/// `enable` may be given a number or boolean, so that it can be bound to a
+/// toggle.
+pub fn start(port: u16, sender: Sender<Event>) -> Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind(("127.0.0.1", port))?;
+    Ok(std::thread::spawn(move || {
+        let mut buffer = [0; rosc::decoder::MTU];
+        loop {
+            let size = match socket.recv_from(&mut buffer) {
+                Ok((size, _)) => size,
+                Err(e) => {
+                    error!("Error receiving OSC packet: {e}");
+                    continue;
+                }
+            };
+            match rosc::decoder::decode_udp(&buffer[..size]) {
+                Ok((_, packet)) => handle_packet(packet, &sender),
+                Err(e) => warn!("invalid OSC packet: {e:?}"),
+            }
+        }
+    }))
+}
+
+fn handle_packet(packet: OscPacket, sender: &Sender<Event>) {
+    match packet {
+        OscPacket::Message(message) => handle_message(message, sender),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                handle_packet(packet, sender);
+            }
+        }
+    }
+}
+
+fn handle_message(message: OscMessage, sender: &Sender<Event>) {
+    let addr = message.addr.as_str();
+    let parts: Vec<&str> = addr.trim_start_matches('/').split('/').collect();
+    let result = match parts.as_slice() {
+        ["pipeswitch", "reload"] => control::request(sender, Command::ReloadConfig),
+        ["pipeswitch", "rule", name, action @ ("enable" | "disable")] => {
+            let enabled = *action == "enable" && is_on(message.args.first());
+            control::request(sender, |reply| Command::SetRuleEnabled {
+                name: name.to_string(),
+                enabled,
+                reply,
+            })
+        }
+        ["pipeswitch", "profile", name] => {
+            let name = (*name != "off").then(|| name.to_string());
+            control::request(sender, |reply| Command::ActivateProfile(name, reply))
+        }
+        _ => {
+            warn!("ignoring OSC message to unknown address {addr}");
+            return;
+        }
+    };
+    match result {
+        Some(Ok(())) => debug!("OSC {addr} done"),
+        Some(Err(e)) => error!("OSC {addr} failed: {e}"),
+        None => {}
+    }
+}
+
+/// Whether the argument of an enable message means on, which it does if
+/// there is none
+fn is_on(arg: Option<&OscType>) -> bool {
+    match arg {
+        Some(OscType::Int(n)) => *n != 0,
+        Some(OscType::Long(n)) => *n != 0,
+        Some(OscType::Float(n)) => *n != 0.0,
+        Some(OscType::Double(n)) => *n != 0.0,
+        Some(OscType::Bool(b)) => *b,
+        _ => true,
+    }
+}
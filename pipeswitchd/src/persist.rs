@@ -0,0 +1,35 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// A link managed by a rule, identified by the aliases of its ports since
+/// PipeWire ids do not survive restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PersistedLink {
+    pub rule: String,
+    pub output: String,
+    pub input: String,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::state_dir().map(|dir| dir.join("pipeswitch").join("links.json"))
+}
+
+pub fn load(path: &Path) -> Result<Vec<PersistedLink>> {
+    if !path.try_exists()? {
+        Ok(Vec::new())
+    } else {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+pub fn save(path: &Path, links: &[PersistedLink]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(fs::write(path, serde_json::to_string_pretty(links)?)?)
+}
@@ -1,152 +1,1876 @@
-use std::collections::HashSet;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
+use anyhow::{anyhow, Result};
 use log::*;
 use pipeswitch_lib::{
-    config::{LinkConfig, NodeOrTarget},
-    types::Port,
-    PipewireState,
+    config::{
+        Config, Distribute, General, LinkConfig, MatchMode, NodeOrTarget, Pairing, Patterns,
+        PortField, Sinks, Target,
+    },
+    types::{self, Channel, DefaultNode, Direction, Node, Port, PortKind},
+    PipeswitchError, PipewireState,
 };
 use regex::{Regex, RegexBuilder};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LinkRules {
     pub name: String,
-    pub input: Rule,
+    /// One for each sink of the rule, all linked to the same source
+    pub inputs: Vec<Rule>,
     pub output: Rule,
     pub links: HashSet<u32>,
+    /// Output and input ports the rule would have linked in a dry run, where
+    /// it has no links of its own
+    pub dry_run_links: HashSet<(u32, u32)>,
+    /// Disabled rules keep track of their ports, but do not link them
+    pub enabled: bool,
+    pub exclusive: bool,
+    pub priority: i32,
+    pub link_delay: Option<Duration>,
+    pub duplicate_mono: bool,
+    /// Source and sink channels to link, replacing the same-channel rule
+    pub channel_map: Vec<(Channel, Channel)>,
+    /// Extra properties given to every link of the rule
+    pub link_properties: HashMap<String, String>,
+    /// Name of the links, with `{n}` standing for the n:th capture of the source
+    pub link_name_template: Option<String>,
+    /// Source streams are moved to the sink instead of being linked to it
+    pub move_stream: bool,
+    /// Stream nodes already moved to a sink by this rule
+    pub moved_streams: HashSet<u32>,
+    /// Whether ports are linked to every matching port or only to the ports
+    /// of the latest node on each side
+    pub pairing: Pairing,
+    /// Whether each source node is linked to every sink or only to one
+    pub distribute: Distribute,
+    /// Which sink each source node was given with `round_robin` distribution
+    pub assignments: SinkAssignments,
+    /// What `expected_links` last found, and the changes of the source and
+    /// sinks it was worked out for
+    expected: RefCell<Option<(Vec<u64>, HashSet<(u32, u32)>)>>,
+    /// What this rule was built from, to skip rebuilding it when unchanged
+    config: LinkConfig,
+    case_sensitive: bool,
+    match_mode: MatchMode,
+    default_special_empty_ports: bool,
 }
 
-impl From<(String, LinkConfig)> for LinkRules {
-    fn from((name, cfg): (String, LinkConfig)) -> Self {
-        LinkRules {
-            name: name.clone(),
-            input: Rule::from_node_or_target(name.clone(), cfg.special_empty_ports, &cfg.sink),
-            output: Rule::from_node_or_target(name, cfg.special_empty_ports, &cfg.source),
+impl TryFrom<(String, LinkConfig, &General)> for LinkRules {
+    type Error = anyhow::Error;
+
+    fn try_from((name, cfg, general): (String, LinkConfig, &General)) -> Result<Self> {
+        if cfg.sink.targets().is_empty() {
+            return Err(anyhow!("rule [{name}] has no sinks"));
+        }
+        if cfg.distribute == Distribute::RoundRobin && matches!(cfg.sink, Sinks::One(_)) {
+            return Err(anyhow!(
+                "rule [{name}] distributes sources but has only one sink"
+            ));
+        }
+        let inputs: Vec<Rule> = cfg
+            .sink
+            .targets()
+            .iter()
+            .map(|sink| Rule::from_node_or_target(name.clone(), &cfg, general, sink, true))
+            .collect::<Result<_>>()?;
+        let output = Rule::from_node_or_target(name.clone(), &cfg, general, &cfg.source, false)?;
+        let mut channel_map = Vec::new();
+        for mapping in &cfg.channel_map {
+            let channel = |channel: &str| {
+                Channel::from_name(channel)
+                    .map_err(|e| anyhow!("invalid channel_map in rule [{name}]: {e}"))
+            };
+            channel_map.push((channel(&mapping.from)?, channel(&mapping.to)?));
+        }
+        for key in cfg.link_properties.keys() {
+            if RESERVED_LINK_PROPERTIES.contains(&key.as_str()) {
+                return Err(anyhow!(
+                    "link property {key} of rule [{name}] is set by pipeswitch"
+                ));
+            }
+        }
+        let link_properties = cfg
+            .link_properties
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_string()))
+            .collect();
+        Ok(LinkRules {
+            name,
+            inputs,
+            output,
             links: HashSet::new(),
+            enabled: true,
+            exclusive: cfg.exclusive,
+            priority: cfg.priority,
+            link_delay: cfg
+                .link_delay_ms
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            duplicate_mono: cfg.duplicate_mono,
+            channel_map,
+            link_properties,
+            link_name_template: cfg.link_name_template.clone(),
+            move_stream: cfg.move_stream,
+            moved_streams: HashSet::new(),
+            dry_run_links: HashSet::new(),
+            pairing: cfg.pairing,
+            distribute: cfg.distribute,
+            assignments: SinkAssignments::default(),
+            expected: RefCell::default(),
+            config: cfg,
+            case_sensitive: general.case_sensitive,
+            match_mode: general.match_mode,
+            default_special_empty_ports: general.default_special_empty_ports,
+        })
+    }
+}
+
+/// Link properties that pipeswitch sets itself, and which can not be overridden
+const RESERVED_LINK_PROPERTIES: &[&str] = &[
+    "link.output.node",
+    "link.output.port",
+    "link.input.node",
+    "link.input.port",
+    "object.linger",
+    types::KEY_RULE_NAME,
+    types::KEY_LINK_NAME,
+];
+
+/// The channel a target is limited to, if any
+fn target_channel(name: &str, node_or_target: &NodeOrTarget) -> Result<Option<Channel>> {
+    match node_or_target {
+        NodeOrTarget::Target(Target {
+            channel: Some(channel),
+            ..
+        }) => Channel::from_name(channel.as_str())
+            .map(Some)
+            .map_err(|e| anyhow!("invalid channel in rule [{name}]: {e}")),
+        _ => Ok(None),
+    }
+}
+
+/// Checks that every rule of the config can be built
+pub fn validate_rules(config: &Config) -> Result<()> {
+    match rule_errors(config).into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Pairs of rules that would fight over the same links, as warnings naming
+/// both rules. Only rules with the same source and sink, or with the source
+/// and sink the other way around, are found, since patterns that merely
+/// happen to match the same names can not be told apart from the config.
+pub fn overlapping_rules(config: &Config) -> Vec<String> {
+    let mut names: Vec<&String> = config.links.keys().collect();
+    names.sort();
+    let mut warnings = Vec::new();
+    for (i, first) in names.iter().enumerate() {
+        for second in &names[i + 1..] {
+            let (a, b) = (&config.links[*first], &config.links[*second]);
+            let mut sink_pairs = a
+                .sink
+                .targets()
+                .iter()
+                .flat_map(|sink_a| b.sink.targets().iter().map(move |sink_b| (sink_a, sink_b)));
+            let conflict = sink_pairs.find_map(|(sink_a, sink_b)| {
+                if same_target(&a.source, &b.source) && same_target(sink_a, sink_b) {
+                    Some("link the same source to the same sink")
+                } else if same_target(&a.source, sink_b) && same_target(sink_a, &b.source) {
+                    Some("link the same nodes in opposite directions, into each other")
+                } else {
+                    None
+                }
+            });
+            if let Some(conflict) = conflict {
+                warnings.push(format!("rules [{first}] and [{second}] {conflict}"));
+            }
+        }
+    }
+    warnings
+}
+
+/// Whether the two would match exactly the same ports, a node name being the
+/// same as a target with only that node
+fn same_target(a: &NodeOrTarget, b: &NodeOrTarget) -> bool {
+    let as_target = |node_or_target: &NodeOrTarget| match node_or_target {
+        NodeOrTarget::NodeName(node) => Target {
+            node: Some(node.clone().into()),
+            ..Default::default()
+        },
+        NodeOrTarget::Target(target) => target.clone(),
+    };
+    as_target(a) == as_target(b)
+}
+
+/// Rules whose source and sink can only have ports of the same direction, as
+/// warnings naming the rule. PipeWire refuses to link two inputs or two
+/// outputs, but that only shows once the ports appear. The direction is only
+/// known from the config for targets of a plain sink, source or stream
+/// `media_class`, ie. `Audio/Sink`, so any other rule is left alone. The
+/// rules of every profile are checked as well, whether active or not.
+pub fn same_direction_rules(config: &Config) -> Vec<String> {
+    let mut rules: Vec<(String, &LinkConfig)> = sorted_links(&config.links)
+        .into_iter()
+        .map(|(name, link)| (format!("rule [{name}]"), link))
+        .collect();
+    let mut profile_names: Vec<&String> = config.profiles.keys().collect();
+    profile_names.sort();
+    for profile_name in profile_names {
+        // The rules of the active profile are among the links already
+        let links = sorted_links(&config.profiles[profile_name].links)
+            .into_iter()
+            .filter(|(name, _)| !config.links.contains_key(*name));
+        rules.extend(
+            links.map(|(name, link)| (format!("rule [{name}] of profile [{profile_name}]"), link)),
+        );
+    }
+    let mut warnings = Vec::new();
+    for (rule, link) in rules {
+        // The monitor ports of a sink are outputs
+        let source = match implied_direction(&link.source) {
+            Some(Direction::Input) if link.include_monitor => continue,
+            Some(direction) => direction,
+            None => continue,
+        };
+        if link
+            .sink
+            .targets()
+            .iter()
+            .any(|sink| implied_direction(sink) == Some(source.clone()))
+        {
+            let (nodes, ports) = match source {
+                Direction::Input => ("sinks or input streams", "input"),
+                Direction::Output => ("sources or output streams", "output"),
+            };
+            warnings.push(format!(
+                "{rule} can never link, both its source and sink are {nodes}, \
+                 which only have {ports} ports"
+            ));
+        }
+    }
+    warnings
+}
+
+fn sorted_links(links: &HashMap<String, LinkConfig>) -> Vec<(&String, &LinkConfig)> {
+    let mut links: Vec<_> = links.iter().collect();
+    links.sort_by_key(|(name, _)| *name);
+    links
+}
+
+/// The only direction of the ports that a target can match, going by its
+/// `media_class`: sinks and input streams only have inputs, sources and output
+/// streams only outputs. Only these exact classes are known to have a single
+/// direction, ie. virtual sources are sinks underneath and have inputs too.
+fn implied_direction(node_or_target: &NodeOrTarget) -> Option<Direction> {
+    let class = match node_or_target {
+        NodeOrTarget::Target(Target {
+            media_class: Some(class),
+            ..
+        }) => class,
+        _ => return None,
+    };
+    match class.as_str() {
+        "Audio/Sink" | "Stream/Input/Audio" => Some(Direction::Input),
+        "Audio/Source" | "Stream/Output/Audio" => Some(Direction::Output),
+        _ => None,
+    }
+}
+
+/// Errors of every rule in the config that can not be built, sorted by rule name,
+/// followed by the errors of each profile, sorted by profile name
+pub fn rule_errors(config: &Config) -> Vec<anyhow::Error> {
+    let mut errors = link_errors(&config.links, &config.general, |_| None);
+    let mut profile_names: Vec<&String> = config.profiles.keys().collect();
+    profile_names.sort();
+    for profile_name in profile_names {
+        let links = &config.profiles[profile_name].links;
+        errors.extend(link_errors(links, &config.general, |name| {
+            config.links.contains_key(name).then(|| {
+                PipeswitchError::ProfileRuleExists(name.to_owned(), profile_name.clone()).into()
+            })
+        }));
+    }
+    if let Some(profile_name) = &config.general.profile {
+        if !config.profiles.contains_key(profile_name) {
+            errors.push(PipeswitchError::NoSuchProfile(profile_name.clone()).into());
         }
     }
+    errors
 }
 
-#[derive(Debug)]
+/// Errors of the rules that can not be built, sorted by rule name. `conflict`
+/// may refuse a rule before it is built.
+fn link_errors(
+    links: &HashMap<String, LinkConfig>,
+    general: &General,
+    conflict: impl Fn(&str) -> Option<anyhow::Error>,
+) -> Vec<anyhow::Error> {
+    let mut names: Vec<&String> = links.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            conflict(name).or_else(|| {
+                let cfg = links[name].clone();
+                LinkRules::try_from((name.clone(), cfg, general)).err()
+            })
+        })
+        .collect()
+}
+
+impl LinkRules {
+    /// Whether building this rule again from the given config would result in
+    /// the same rule, without having to compile any patterns
+    pub fn built_from(&self, cfg: &LinkConfig, general: &General) -> bool {
+        self.config == *cfg
+            && self.case_sensitive == general.case_sensitive
+            && self.match_mode == general.match_mode
+            && self.default_special_empty_ports == general.default_special_empty_ports
+    }
+
+    /// Whether the rule links anything differently than the other one, ie.
+    /// when only the way it is written has changed
+    pub fn links_differently(&self, other: &LinkRules) -> bool {
+        self.inputs != other.inputs
+            || self.output != other.output
+            || self.exclusive != other.exclusive
+            || self.priority != other.priority
+            || self.link_delay != other.link_delay
+            || self.duplicate_mono != other.duplicate_mono
+            || self.channel_map != other.channel_map
+            || self.link_properties != other.link_properties
+            || self.link_name_template != other.link_name_template
+            || self.move_stream != other.move_stream
+            || self.pairing != other.pairing
+            || self.distribute != other.distribute
+            || self.output.include_monitor != other.output.include_monitor
+            || self.output.special_empty_ports != other.output.special_empty_ports
+    }
+
+    /// Whether the source or any of the sinks matches on a field of the node
+    /// that changed, so that its ports may need to be matched again
+    pub fn depends_on_change(&self, old: &Node, new: &Node) -> bool {
+        let affected = |rule: &Rule| rule.depends_on_change(old, new);
+        affected(&self.output) || self.inputs.iter().any(affected)
+    }
+
+    /// Whether any of the sinks matches the input port
+    pub fn matches_input(&self, port_id: u32) -> bool {
+        self.inputs
+            .iter()
+            .any(|sink| sink.matching_ports.contains(&port_id))
+    }
+
+    /// Input ports matching any of the sinks
+    pub fn input_ports(&self) -> HashSet<u32> {
+        self.inputs
+            .iter()
+            .flat_map(|sink| sink.matching_ports.iter().copied())
+            .collect()
+    }
+
+    /// Channels of the channel map that none of the matching ports have, on
+    /// either side, once both sides have matched something
+    pub fn missing_mapped_channels(&self, state: &PipewireState) -> Vec<Channel> {
+        let channels = |ports: &HashSet<u32>| -> Vec<Channel> {
+            ports
+                .iter()
+                .filter_map(|id| state.ports.get(id))
+                .filter(|port| port.kind == PortKind::Audio)
+                .map(|port| port.channel.clone())
+                .collect()
+        };
+        let outputs = channels(&self.output.matching_ports);
+        let inputs = channels(&self.input_ports());
+        if outputs.is_empty() || inputs.is_empty() {
+            return Vec::new();
+        }
+        let mut missing = Vec::new();
+        for (from, to) in &self.channel_map {
+            if !outputs.contains(from) && !missing.contains(from) {
+                missing.push(from.clone());
+            }
+            if !inputs.contains(to) && !missing.contains(to) {
+                missing.push(to.clone());
+            }
+        }
+        missing
+    }
+
+    /// Whether the given output and input port of this rule should be linked,
+    /// by any of the sinks matching the input port. With `by_node` pairing,
+    /// only the latest node of the source and of each sink are linked, and
+    /// with `round_robin` distribution only the sink assigned to the source
+    /// node. Ports are only linked to ports of the same kind, ie. MIDI to MIDI.
+    pub fn should_link(&self, output: &Port, input: &Port, state: &PipewireState) -> bool {
+        if output.kind != input.kind {
+            return false;
+        }
+        let by_node = self.pairing == Pairing::ByNode;
+        if by_node && self.output.latest_node() != Some(output.node_id) {
+            return false;
+        }
+        let captures = self
+            .output
+            .captures
+            .get(&output.id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        let assigned = match self.distribute {
+            Distribute::All => None,
+            Distribute::RoundRobin => match self.assignments.sink_of(output.node_id) {
+                Some(index) => Some(index),
+                None => return false,
+            },
+        };
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter(|(index, sink)| {
+                assigned.map_or(true, |assigned| assigned == *index)
+                    && sink.matching_ports.contains(&input.id)
+            })
+            .any(|(_, sink)| {
+                (!by_node || sink.latest_node() == Some(input.node_id))
+                    && self.channels_match(sink, output, input)
+                    && sink.templates_match(input, state, output.id, captures)
+            })
+    }
+
+    /// Whether the ports are only linked when their channels pair up, which
+    /// leaves channels unlinked when the nodes have different layouts
+    pub fn pairs_by_channel(&self, output: &Port, input: &Port) -> bool {
+        output.kind == PortKind::Audio
+            && self.channel_map.is_empty()
+            && !(self.duplicate_mono && output.channel == Channel::Mono)
+            && self
+                .inputs
+                .iter()
+                .filter(|sink| sink.matching_ports.contains(&input.id))
+                .all(|sink| !sink.should_ignore_channel(&self.output))
+    }
+
+    /// The name of a link from the output port, with the captures of the
+    /// source filled into the template. Captures it does not have are left
+    /// empty.
+    pub fn link_name(&self, output: &Port) -> Option<String> {
+        let template = self.link_name_template.as_ref()?;
+        let captures = self
+            .output
+            .captures
+            .get(&output.id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        let mut name = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            name.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let index = rest
+                .find('}')
+                .and_then(|end| Some((rest[1..end].parse::<usize>().ok()?, end)));
+            match index {
+                Some((index, end)) => {
+                    if let Some(capture) = index.checked_sub(1).and_then(|i| captures.get(i)) {
+                        name.push_str(capture);
+                    }
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    name.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        name.push_str(rest);
+        Some(name)
+    }
+
+    /// Output and input ports the rule would link between its matching ports,
+    /// leaving out ports on the same node, which are never linked. Only worked
+    /// out again once the source or a sink has matched or lost ports.
+    pub fn expected_links(&self, state: &PipewireState) -> HashSet<(u32, u32)> {
+        let changes: Vec<u64> = std::iter::once(&self.output)
+            .chain(&self.inputs)
+            .map(|rule| rule.changes)
+            .collect();
+        if let Some((seen, expected)) = &*self.expected.borrow() {
+            if *seen == changes {
+                return expected.clone();
+            }
+        }
+        let expected = self.find_expected_links(state);
+        *self.expected.borrow_mut() = Some((changes, expected.clone()));
+        expected
+    }
+
+    fn find_expected_links(&self, state: &PipewireState) -> HashSet<(u32, u32)> {
+        let mut expected = HashSet::new();
+        let input_ports = self.input_ports();
+        for output_id in &self.output.matching_ports {
+            for input_id in &input_ports {
+                if let (Some(output), Some(input)) =
+                    (state.ports.get(output_id), state.ports.get(input_id))
+                {
+                    if output.node_id != input.node_id && self.should_link(output, input, state) {
+                        expected.insert((output.id, input.id));
+                    }
+                }
+            }
+        }
+        expected
+    }
+
+    /// Forgets the ports and links of every sink and the source
+    pub fn forget_graph(&mut self) {
+        self.links.clear();
+        self.dry_run_links.clear();
+        self.moved_streams.clear();
+        self.assignments.clear();
+        self.output.forget_ports();
+        for sink in &mut self.inputs {
+            sink.forget_ports();
+            sink.expanded_templates.borrow_mut().clear();
+        }
+    }
+
+    /// Forgets the templates of the sinks filled in for a removed source port
+    pub fn forget_source_port(&self, port: &Port) {
+        for sink in &self.inputs {
+            sink.expanded_templates.borrow_mut().remove(&port.id);
+        }
+    }
+
+    /// Gives the node of a newly matched source port one of the sinks, when
+    /// sources are distributed. A node keeps its sink for as long as any of
+    /// its ports match.
+    pub fn assign_sink(&mut self, port: &Port) {
+        if self.distribute != Distribute::RoundRobin {
+            return;
+        }
+        let (sink, new) = self
+            .assignments
+            .assign(port.node_id, port.id, self.inputs.len());
+        if new {
+            let (node, name, sink) = (port.node_id, &self.name, sink + 1);
+            debug!("source node {node} of [{name}] assigned to sink {sink}");
+        }
+    }
+
+    /// Forgets a removed source port, freeing the sink of its node for the
+    /// next new source if it was the last port of the node
+    pub fn release_sink(&mut self, port: &Port) {
+        if let Some(sink) = self.assignments.release(port.node_id, port.id) {
+            let (node, name, sink) = (port.node_id, &self.name, sink + 1);
+            debug!("source node {node} of [{name}] released sink {sink}");
+        }
+    }
+
+    /// Only audio ports have channels, others are linked by their names alone
+    fn channels_match(&self, sink: &Rule, output: &Port, input: &Port) -> bool {
+        if output.kind != PortKind::Audio {
+            true
+        } else if self.channel_map.is_empty() {
+            sink.should_ignore_channel(&self.output)
+                || output.channel == input.channel
+                || (self.duplicate_mono
+                    && output.channel == Channel::Mono
+                    && matches!(input.channel, Channel::Left | Channel::Right))
+        } else {
+            self.channel_map
+                .iter()
+                .any(|(from, to)| output.channel == *from && input.channel == *to)
+        }
+    }
+}
+
+/// Which sink each source node of a rule is linked to, with `round_robin`
+/// distribution
+#[derive(Debug, Clone, Default)]
+pub struct SinkAssignments {
+    /// Index of the sink and the matching ports of each source node
+    nodes: HashMap<u32, (usize, HashSet<u32>)>,
+    /// Sink given to the next source node, unless one has been freed
+    next: usize,
+    /// Sinks of removed source nodes, given out again before moving on
+    freed: VecDeque<usize>,
+}
+
+impl SinkAssignments {
+    /// The sink of the port's node, assigning the node one if it has none.
+    /// Also tells whether the node was just assigned.
+    fn assign(&mut self, node_id: u32, port_id: u32, sinks: usize) -> (usize, bool) {
+        if let Some((sink, ports)) = self.nodes.get_mut(&node_id) {
+            ports.insert(port_id);
+            return (*sink, false);
+        }
+        let sink = match self.freed.pop_front() {
+            Some(sink) => sink,
+            None => {
+                let sink = self.next;
+                self.next = (self.next + 1) % sinks;
+                sink
+            }
+        };
+        self.nodes.insert(node_id, (sink, HashSet::from([port_id])));
+        (sink, true)
+    }
+
+    /// Forgets a port of a node, returning the sink it freed if the node has
+    /// no other ports left
+    fn release(&mut self, node_id: u32, port_id: u32) -> Option<usize> {
+        let (sink, ports) = self.nodes.get_mut(&node_id)?;
+        ports.remove(&port_id);
+        if !ports.is_empty() {
+            return None;
+        }
+        let sink = *sink;
+        self.nodes.remove(&node_id);
+        self.freed.push_back(sink);
+        Some(sink)
+    }
+
+    pub fn sink_of(&self, node_id: u32) -> Option<usize> {
+        self.nodes.get(&node_id).map(|(sink, _)| *sink)
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+        self.next = 0;
+        self.freed.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Rule {
     pub name: String,
-    pub client: Option<Regex>,
-    pub node: Option<Regex>,
-    pub port: Option<Regex>,
+    /// Any of the patterns may match, none at all matches anything
+    pub client: Vec<Regex>,
+    pub node: Vec<Regex>,
+    pub port: Vec<Regex>,
+    pub media_class: Option<Regex>,
+    pub role: Option<Regex>,
+    pub media_name: Option<Regex>,
+    pub nick: Option<Regex>,
+    pub description: Option<Regex>,
+    pub binary: Option<Regex>,
+    pub pid: Option<u32>,
+    pub path: Option<Regex>,
+    /// Compared against the parsed channel of the port, not its name
+    pub channel: Option<Channel>,
+    pub port_kind: Option<PortKind>,
+    pub not_client: Option<Regex>,
+    pub not_node: Option<Regex>,
+    pub not_port: Option<Regex>,
+    /// What `port`, `not_port` and the port template are matched against
+    pub port_field: PortField,
+    /// Node and port patterns of a sink referring to the captures of the
+    /// source as `$1`, `$2`..., only known once a source port is being linked.
+    /// Until then `node` and `port` hold them with anything in place of the
+    /// captures.
+    pub node_template: Option<String>,
+    pub port_template: Option<String>,
+    /// The templates filled in with the captures of each source port linked
+    /// so far, so that they are compiled once per source port
+    expanded_templates: RefCell<HashMap<u32, ExpandedTemplates>>,
+    /// Set by the `default_sink` and `default_source` node keywords, matching
+    /// whichever node is currently the default instead of a pattern
+    pub default_node: Option<DefaultNode>,
     pub matching_ports: HashSet<u32>,
+    /// Capture groups of the node and port patterns, in that order, for each
+    /// matching port
+    pub captures: HashMap<u32, Vec<String>>,
+    /// In which order the nodes first matched, to tell the latest node. Kept
+    /// when their ports go away, so that a node coming back or being matched
+    /// again does not become the latest one.
+    node_order: HashMap<u32, u64>,
+    match_count: u64,
+    /// Node of each matching port
+    port_nodes: HashMap<u32, u32>,
+    /// The node with matching ports that matched first most recently
+    latest: Option<u32>,
+    /// Counts every change to the matching ports
+    changes: u64,
     pub special_empty_ports: bool,
+    /// Monitor ports are left alone unless the rule asks for them
+    pub include_monitor: bool,
+    case_sensitive: bool,
+    match_mode: MatchMode,
     original_config: NodeOrTarget,
 }
 
+/// The node and port templates of a sink, filled in with the captures of a
+/// source port
+#[derive(Debug, Clone)]
+struct ExpandedTemplates {
+    captures: Vec<String>,
+    node: Option<Regex>,
+    port: Option<Regex>,
+}
+
 impl PartialEq for Rule {
     fn eq(&self, other: &Self) -> bool {
         self.original_config == other.original_config
+            && self.case_sensitive == other.case_sensitive
+            && self.match_mode == other.match_mode
     }
 }
 
 impl Rule {
-    fn from_node_or_target(name: String, special: bool, node_or_target: &NodeOrTarget) -> Rule {
+    fn from_node_or_target(
+        name: String,
+        cfg: &LinkConfig,
+        general: &General,
+        node_or_target: &NodeOrTarget,
+        allow_templates: bool,
+    ) -> Result<Rule> {
+        let invalid = |e: regex::Error| anyhow!("invalid pattern in rule [{name}]: {e}");
+        let template = |pattern: Option<&String>| {
+            pattern
+                .filter(|pattern| allow_templates && is_template(pattern))
+                .cloned()
+        };
         match node_or_target {
-            NodeOrTarget::NodeName(node_name) => Rule {
-                name,
-                client: None,
-                node: Some(
-                    RegexBuilder::new(node_name)
-                        .case_insensitive(true)
-                        .build()
-                        .unwrap(),
-                ),
-                port: None,
-                matching_ports: HashSet::new(),
-                special_empty_ports: special,
-                original_config: node_or_target.clone(),
-            },
-            NodeOrTarget::Target(t) => Rule {
-                name,
-                client: t.client.as_ref().map(|rex| {
-                    RegexBuilder::new(rex)
-                        .case_insensitive(true)
-                        .build()
-                        .unwrap()
-                }),
-                node: t.node.as_ref().map(|rex| {
-                    RegexBuilder::new(rex)
-                        .case_insensitive(true)
-                        .build()
-                        .unwrap()
-                }),
-                port: t.port.as_ref().map(|rex| {
-                    RegexBuilder::new(rex)
-                        .case_insensitive(true)
-                        .build()
-                        .unwrap()
-                }),
-                matching_ports: HashSet::new(),
-                special_empty_ports: special,
-                original_config: node_or_target.clone(),
-            },
+            NodeOrTarget::NodeName(node_name) => {
+                let node_template = template(Some(node_name));
+                let default_node = default_keyword(node_name);
+                let node = match (&node_template, default_node) {
+                    (Some(template), _) => {
+                        let regex =
+                            template_wildcard(template, general.case_sensitive, general.match_mode);
+                        vec![regex.map_err(invalid)?]
+                    }
+                    (None, Some(_)) => Vec::new(),
+                    (None, None) => {
+                        let regex =
+                            build_regex(node_name, general.case_sensitive, general.match_mode);
+                        vec![regex.map_err(invalid)?]
+                    }
+                };
+                Ok(Rule {
+                    name: name.clone(),
+                    client: Vec::new(),
+                    node,
+                    port: Vec::new(),
+                    media_class: None,
+                    role: None,
+                    media_name: None,
+                    nick: None,
+                    description: None,
+                    binary: None,
+                    pid: None,
+                    path: None,
+                    channel: None,
+                    port_kind: None,
+                    not_client: None,
+                    not_node: None,
+                    not_port: None,
+                    port_field: PortField::Name,
+                    node_template,
+                    port_template: None,
+                    default_node,
+                    matching_ports: HashSet::new(),
+                    captures: HashMap::new(),
+                    expanded_templates: RefCell::default(),
+                    node_order: HashMap::new(),
+                    match_count: 0,
+                    port_nodes: HashMap::new(),
+                    latest: None,
+                    changes: 0,
+                    special_empty_ports: cfg
+                        .special_empty_ports
+                        .unwrap_or(general.default_special_empty_ports),
+                    include_monitor: cfg.include_monitor,
+                    case_sensitive: general.case_sensitive,
+                    match_mode: general.match_mode,
+                    original_config: node_or_target.clone(),
+                })
+            }
+            NodeOrTarget::Target(t) => {
+                let case_sensitive = t.case_sensitive.unwrap_or(general.case_sensitive);
+                let match_mode = t.match_mode.unwrap_or(general.match_mode);
+                // An empty list would match anything, which is never what was meant
+                let lists = [("client", &t.client), ("node", &t.node), ("port", &t.port)];
+                for (field, patterns) in lists {
+                    if patterns.as_ref().map_or(false, |p| p.patterns().is_empty()) {
+                        let e = anyhow!("rule [{name}] has an empty list of {field} patterns");
+                        return Err(e);
+                    }
+                }
+                let build = |pattern: &String| {
+                    build_regex(pattern, case_sensitive, match_mode).map_err(&invalid)
+                };
+                let build_all = |patterns: Option<&Patterns>| {
+                    patterns
+                        .map_or(&[][..], Patterns::patterns)
+                        .iter()
+                        .map(build)
+                        .collect::<Result<Vec<_>>>()
+                };
+                // Only a single pattern may refer to captures or be a keyword
+                let node_template = template(t.node.as_ref().and_then(Patterns::single));
+                let port_template = template(t.port.as_ref().and_then(Patterns::single));
+                let default_node = t
+                    .node
+                    .as_ref()
+                    .and_then(Patterns::single)
+                    .and_then(|node| default_keyword(node));
+                let wildcard = |template: &String| {
+                    template_wildcard(template, case_sensitive, match_mode).map_err(&invalid)
+                };
+                let node = match (&node_template, default_node) {
+                    (Some(template), _) => vec![wildcard(template)?],
+                    (None, Some(_)) => Vec::new(),
+                    (None, None) => build_all(t.node.as_ref())?,
+                };
+                let port = match &port_template {
+                    Some(template) => vec![wildcard(template)?],
+                    None => build_all(t.port.as_ref())?,
+                };
+                Ok(Rule {
+                    name: name.clone(),
+                    client: build_all(t.client.as_ref())?,
+                    node,
+                    port,
+                    media_class: t.media_class.as_ref().map(build).transpose()?,
+                    role: t.role.as_ref().map(build).transpose()?,
+                    media_name: t.media_name.as_ref().map(build).transpose()?,
+                    nick: t.nick.as_ref().map(build).transpose()?,
+                    description: t.description.as_ref().map(build).transpose()?,
+                    binary: t.binary.as_ref().map(build).transpose()?,
+                    pid: t.pid,
+                    path: t.path.as_ref().map(build).transpose()?,
+                    channel: target_channel(&name, node_or_target)?,
+                    port_kind: t.port_kind,
+                    not_client: t.not_client.as_ref().map(build).transpose()?,
+                    not_node: t.not_node.as_ref().map(build).transpose()?,
+                    not_port: t.not_port.as_ref().map(build).transpose()?,
+                    port_field: t.port_field.unwrap_or_default(),
+                    node_template,
+                    port_template,
+                    default_node,
+                    matching_ports: HashSet::new(),
+                    captures: HashMap::new(),
+                    expanded_templates: RefCell::default(),
+                    node_order: HashMap::new(),
+                    match_count: 0,
+                    port_nodes: HashMap::new(),
+                    latest: None,
+                    changes: 0,
+                    special_empty_ports: cfg
+                        .special_empty_ports
+                        .unwrap_or(general.default_special_empty_ports),
+                    include_monitor: cfg.include_monitor,
+                    case_sensitive,
+                    match_mode,
+                    original_config: node_or_target.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// The known ports among the ids, in the order they should be linked in: by
+/// local port id and then channel, so that linking does not depend on the
+/// order of a HashSet
+pub fn sorted_ports<'a>(
+    ids: impl IntoIterator<Item = &'a u32>,
+    state: &PipewireState,
+) -> Vec<Port> {
+    let mut ports: Vec<Port> = ids
+        .into_iter()
+        .filter_map(|id| state.ports.get(id).cloned())
+        .collect();
+    ports.sort_by_key(|port| (port.local_port_id, port.channel.name(), port.id));
+    ports
+}
+
+fn build_regex(
+    pattern: &str,
+    case_sensitive: bool,
+    match_mode: MatchMode,
+) -> Result<Regex, regex::Error> {
+    let rex = match match_mode {
+        MatchMode::Regex => pattern.to_owned(),
+        MatchMode::Glob => glob_to_regex(pattern),
+        MatchMode::Exact => format!("^{}$", regex::escape(pattern)),
+    };
+    compile_regex(&rex, case_sensitive, match_mode)
+}
+
+fn compile_regex(
+    rex: &str,
+    case_sensitive: bool,
+    match_mode: MatchMode,
+) -> Result<Regex, regex::Error> {
+    // Exact names are compared as they are, case included
+    let case_insensitive = !case_sensitive && match_mode != MatchMode::Exact;
+    RegexBuilder::new(rex)
+        .case_insensitive(case_insensitive)
+        .build()
+}
+
+/// Translates `*` and `?` into their regex counterparts, escaping the rest
+fn glob_to_regex(glob: &str) -> String {
+    format!("^{}$", translate_glob(glob))
+}
+
+fn translate_glob(glob: &str) -> String {
+    let mut rex = String::new();
+    for c in glob.chars() {
+        match c {
+            '*' => rex.push_str(".*"),
+            '?' => rex.push('.'),
+            _ => rex.push_str(&regex::escape(&c.to_string())),
         }
     }
+    rex
 }
+
+/// The regex of a template, with `$n` replaced by the n:th capture as it is,
+/// or by anything at all without captures. None if a capture is missing.
+fn expand_template(
+    template: &str,
+    captures: Option<&[String]>,
+    match_mode: MatchMode,
+) -> Option<String> {
+    let translate = |literal: &str| match match_mode {
+        MatchMode::Regex => literal.to_owned(),
+        MatchMode::Glob => translate_glob(literal),
+        MatchMode::Exact => regex::escape(literal),
+    };
+    let mut rex = String::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$' && chars.peek().map_or(false, char::is_ascii_digit) {
+            let mut index = 0;
+            while let Some(digit) = chars.peek().and_then(|d| d.to_digit(10)) {
+                index = index * 10 + digit as usize;
+                chars.next();
+            }
+            rex.push_str(&translate(&literal));
+            literal.clear();
+            match captures {
+                Some(captures) => {
+                    let capture = captures.get(index.checked_sub(1)?)?;
+                    rex.push_str(&regex::escape(capture));
+                }
+                None => rex.push_str(".*"),
+            }
+        } else {
+            literal.push(c);
+        }
+    }
+    rex.push_str(&translate(&literal));
+    Some(match match_mode {
+        MatchMode::Regex => rex,
+        MatchMode::Glob | MatchMode::Exact => format!("^{rex}$"),
+    })
+}
+
+/// Matches whatever the template could match once filled in, so that a sink
+/// only keeps track of the ports it may link
+fn template_wildcard(
+    template: &str,
+    case_sensitive: bool,
+    match_mode: MatchMode,
+) -> Result<Regex, regex::Error> {
+    let rex = expand_template(template, None, match_mode).unwrap_or_default();
+    compile_regex(&rex, case_sensitive, match_mode)
+}
+
+/// A template missing a capture, or a field missing from the port, matches
+/// nothing
+fn template_matches(
+    template: &Option<String>,
+    expanded: Option<&Regex>,
+    text: Option<&str>,
+) -> bool {
+    match (template, expanded, text) {
+        (None, _, _) => true,
+        (Some(_), Some(regex), Some(text)) => matches_entirely(regex, text).unwrap_or(false),
+        (Some(_), _, _) => false,
+    }
+}
+
+/// The node keywords that follow the system default instead of matching a name
+fn default_keyword(pattern: &str) -> Option<DefaultNode> {
+    match pattern {
+        "default_sink" => Some(DefaultNode::Sink),
+        "default_source" => Some(DefaultNode::Source),
+        _ => None,
+    }
+}
+
+/// Whether the pattern refers to captures, ie. `speaker_$1`
+fn is_template(pattern: &str) -> bool {
+    pattern
+        .as_bytes()
+        .windows(2)
+        .any(|w| w[0] == b'$' && w[1].is_ascii_digit())
+}
+
 fn matches_entirely(regex: &Regex, text: &str) -> Option<bool> {
     let first_match = regex.captures(text)?.get(0)?;
     Some(first_match.start() == 0 && first_match.end() == text.len())
 }
 
+/// No regexes match anything, otherwise any of them has to match. A missing
+/// field matches nothing.
+fn matches_field(regexes: &[Regex], text: Option<&str>) -> bool {
+    match (regexes, text) {
+        ([], _) => true,
+        (regexes, Some(text)) => regexes
+            .iter()
+            .any(|regex| matches_entirely(regex, text).unwrap_or(false)),
+        (_, None) => false,
+    }
+}
+
+/// The regex as a list of one, or of none if it is missing
+fn one_or_none(regex: &Option<Regex>) -> &[Regex] {
+    match regex {
+        Some(regex) => std::slice::from_ref(regex),
+        None => &[],
+    }
+}
+
+/// A missing regex or a missing field never excludes anything
+fn excluded_by(regex: Option<&Regex>, text: Option<&str>) -> bool {
+    match (regex, text) {
+        (Some(regex), Some(text)) => matches_entirely(regex, text).unwrap_or(false),
+        _ => false,
+    }
+}
+
 impl Rule {
     pub fn add_if_matches(&mut self, port: &Port, state: &PipewireState) -> bool {
-        let port_matches = match &self.port {
-            Some(regex) => matches_entirely(regex, &port.name).unwrap_or(false),
-            _ => true,
-        };
+        if !self.matches(port, state) {
+            return false;
+        }
+        let node_name = state.nodes.get(&port.node_id).map(|n| n.node_name.as_str());
+        let port_name = self.port_text(port);
+        let mut captures = Vec::new();
+        for (regexes, text) in [(&self.node, node_name), (&self.port, port_name)] {
+            if let Some(text) = text {
+                // The first of the patterns that matches gives the captures
+                let groups = regexes
+                    .iter()
+                    .filter(|regex| matches_entirely(regex, text).unwrap_or(false))
+                    .find_map(|regex| regex.captures(text));
+                if let Some(groups) = groups {
+                    captures.extend(
+                        groups
+                            .iter()
+                            .skip(1)
+                            .map(|group| group.map(|g| g.as_str().to_owned()).unwrap_or_default()),
+                    );
+                }
+            }
+        }
+        if !captures.is_empty() {
+            self.captures.insert(port.id, captures);
+        }
+        self.matching_ports.insert(port.id);
+        self.changes += 1;
+        self.port_nodes.insert(port.id, port.node_id);
+        let count = &mut self.match_count;
+        let order = *self.node_order.entry(port.node_id).or_insert_with(|| {
+            *count += 1;
+            *count
+        });
+        // A removed node has no order anymore, and any other node is later
+        let latest = self.latest.and_then(|latest| self.node_order.get(&latest));
+        if latest.map_or(true, |latest| order > *latest) {
+            self.latest = Some(port.node_id);
+        }
+        let name = &self.name;
+        debug!("new port {port} for [{name}]");
+        true
+    }
 
-        if port_matches {
-            let node = state.nodes.get(&port.node_id);
-            let client = node.and_then(|n| state.clients.get(&n.client_id));
+    /// The field of the port that the port patterns are matched against
+    fn port_text<'a>(&self, port: &'a Port) -> Option<&'a str> {
+        match self.port_field {
+            PortField::Name => Some(port.name.as_str()),
+            PortField::Alias => Some(port.alias.as_str()),
+            PortField::Path => port.path.as_deref(),
+        }
+    }
 
-            let node_matches = match (&self.node, node) {
-                (Some(regex), Some(node)) => {
-                    matches_entirely(regex, &node.node_name).unwrap_or(false)
-                }
-                (Some(_), None) => false,
-                _ => true,
-            };
-            let client_matches = match (&self.client, client) {
-                (Some(regex), Some(client)) => {
-                    matches_entirely(regex, &client.application_name).unwrap_or(false)
-                }
-                (Some(_), None) => false,
-                _ => true,
-            };
+    /// Whether the port matches every field of the rule. The first field that
+    /// does not match is logged at trace level, along with what was compared.
+    fn matches(&self, port: &Port, state: &PipewireState) -> bool {
+        let name = &self.name;
+        let port_name = self.port_text(port);
+        let not_port = self.not_port.as_ref();
+        let path = port.path.as_deref();
+        if !self.field_matches(port, "port", &self.port, not_port, port_name)
+            || !self.field_matches(port, "path", one_or_none(&self.path), None, path)
+        {
+            return false;
+        }
+        if let Some(channel) = self.channel.as_ref().filter(|c| port.channel != **c) {
+            let port_channel = &port.channel;
+            trace!("port {port} does not match [{name}]: channel {port_channel} is not {channel}");
+            return false;
+        }
+        if let Some(kind) = self.port_kind.filter(|kind| port.kind != *kind) {
+            let port_kind = port.kind;
+            trace!("port {port} does not match [{name}]: it is {port_kind}, not {kind}");
+            return false;
+        }
+        if !self.include_monitor && port.monitor == Some(true) {
+            trace!("port {port} does not match [{name}]: it is a monitor port");
+            return false;
+        }
 
-            if node_matches && client_matches {
-                self.matching_ports.insert(port.id);
-                let alias = &port.alias;
-                let direction = &port.direction;
-                let name = &self.name;
-                debug!("new {direction:?} for [{name}] ({alias})");
-                true
-            } else {
-                false
+        let node = state.nodes.get(&port.node_id);
+        let client = node.and_then(|n| state.clients.get(&n.client_id));
+        let node_name = node.map(|n| n.node_name.as_str());
+        if let Some(kind) = self.default_node {
+            let default_name = state.default_node_name(kind);
+            if node_name.is_none() || node_name != default_name {
+                trace!(
+                    "port {port} does not match [{name}]: node {node_name:?} is not the default \
+                    {kind:?} {default_name:?}"
+                );
+                return false;
             }
-        } else {
+        }
+        let media_class = node.and_then(|n| n.media_class.as_deref());
+        let role = node.and_then(|n| n.media_role.as_deref());
+        let media_name = node.and_then(|n| n.media_name.as_deref());
+        let nick = node.and_then(|n| n.node_nick.as_deref());
+        let description = node.and_then(|n| n.node_description.as_deref());
+        let client_name = client.map(|c| c.application_name.as_str());
+        let binary = client.and_then(|c| c.binary.as_deref());
+        let (not_node, not_client) = (self.not_node.as_ref(), self.not_client.as_ref());
+        if !self.field_matches(port, "node", &self.node, not_node, node_name) {
+            return false;
+        }
+        let node_fields = [
+            ("media_class", &self.media_class, media_class),
+            ("role", &self.role, role),
+            ("media_name", &self.media_name, media_name),
+            ("nick", &self.nick, nick),
+            ("description", &self.description, description),
+        ];
+        for (field, regex, text) in node_fields {
+            if !self.field_matches(port, field, one_or_none(regex), None, text) {
+                return false;
+            }
+        }
+        if !self.field_matches(port, "client", &self.client, not_client, client_name)
+            || !self.field_matches(port, "binary", one_or_none(&self.binary), None, binary)
+        {
+            return false;
+        }
+        if let Some(pid) = self.pid {
+            let client_pid = client.map(|c| c.pid);
+            if client_pid != Some(pid) {
+                trace!("port {port} does not match [{name}]: pid {client_pid:?} is not {pid}");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether the text matches any of the regexes of the field and not its
+    /// exclusion, logging at trace level why not
+    fn field_matches(
+        &self,
+        port: &Port,
+        field: &str,
+        regexes: &[Regex],
+        not: Option<&Regex>,
+        text: Option<&str>,
+    ) -> bool {
+        let name = &self.name;
+        if !matches_field(regexes, text) {
+            trace!(
+                "port {port} does not match [{name}]: {field} {text:?} does not match {:?}",
+                regexes.iter().map(Regex::as_str).collect::<Vec<_>>()
+            );
             false
+        } else if excluded_by(not, text) {
+            trace!(
+                "port {port} does not match [{name}]: {field} {text:?} is excluded by {:?}",
+                not.map(Regex::as_str)
+            );
+            false
+        } else {
+            true
         }
     }
 
+    /// Whether the rule matches on a field that differs between the old and
+    /// new version of a node
+    fn depends_on_change(&self, old: &Node, new: &Node) -> bool {
+        let by_name = !self.node.is_empty()
+            || self.not_node.is_some()
+            || self.node_template.is_some()
+            || self.default_node.is_some();
+        let by_client = !self.client.is_empty()
+            || self.not_client.is_some()
+            || self.binary.is_some()
+            || self.pid.is_some();
+        let differs = |regex: &Option<Regex>, field: fn(&Node) -> &Option<String>| {
+            regex.is_some() && field(old) != field(new)
+        };
+        (by_name && old.node_name != new.node_name)
+            || (by_client && old.client_id != new.client_id)
+            || differs(&self.media_class, |node| &node.media_class)
+            || differs(&self.role, |node| &node.media_role)
+            || differs(&self.media_name, |node| &node.media_name)
+            || differs(&self.nick, |node| &node.node_nick)
+            || differs(&self.description, |node| &node.node_description)
+    }
+
+    /// Forgets every matched port, ie. once PipeWire has restarted and the ids
+    /// mean nothing anymore
+    pub fn forget_ports(&mut self) {
+        self.matching_ports.clear();
+        self.captures.clear();
+        self.node_order.clear();
+        self.port_nodes.clear();
+        self.latest = None;
+        self.changes += 1;
+    }
+
+    /// Node with matching ports that matched first most recently
+    pub fn latest_node(&self) -> Option<u32> {
+        self.latest
+    }
+
+    /// Forgets in which order a removed node matched. Its ports may still
+    /// be waiting to be forgotten, but no later node is linked after it.
+    pub fn forget_node(&mut self, node_id: u32) {
+        self.node_order.remove(&node_id);
+    }
+
     pub fn delete_port(&mut self, port: &Port) -> bool {
+        self.captures.remove(&port.id);
+        if let Some(node_id) = self.port_nodes.remove(&port.id) {
+            let node_left = !self.port_nodes.values().any(|node| *node == node_id);
+            if node_left && self.latest == Some(node_id) {
+                // Only the nodes that still have matching ports can be linked
+                let order = &self.node_order;
+                self.latest = self
+                    .port_nodes
+                    .values()
+                    .max_by_key(|node| order.get(*node))
+                    .copied();
+            }
+        }
         let was = self.matching_ports.remove(&port.id);
         if was {
-            let alias = &port.alias;
-            let direction = &port.direction;
+            self.changes += 1;
             let name = &self.name;
-            debug!("removed {direction:?} from [{name}] ({alias})");
+            debug!("removed port {port} from [{name}]");
         }
         was
     }
 
     pub fn should_ignore_channel(&self, other: &Rule) -> bool {
-        let ports_some = self.port.is_some() || other.port.is_some();
+        let ports_some = !self.port.is_empty()
+            || self.port_template.is_some()
+            || !other.port.is_empty()
+            || other.port_template.is_some();
         !self.special_empty_ports || ports_some
     }
+
+    /// Whether the port matches the node and port templates once they are
+    /// filled in with the captures of the source port
+    pub fn templates_match(
+        &self,
+        port: &Port,
+        state: &PipewireState,
+        source: u32,
+        captures: &[String],
+    ) -> bool {
+        if self.node_template.is_none() && self.port_template.is_none() {
+            return true;
+        }
+        let mut cache = self.expanded_templates.borrow_mut();
+        // The captures change if the source port is matched again
+        if cache.get(&source).map_or(true, |e| e.captures != captures) {
+            let expanded = ExpandedTemplates {
+                captures: captures.to_vec(),
+                node: self.fill_template(&self.node_template, captures),
+                port: self.fill_template(&self.port_template, captures),
+            };
+            cache.insert(source, expanded);
+        }
+        let expanded = &cache[&source];
+        let node_name = state.nodes.get(&port.node_id).map(|n| n.node_name.as_str());
+        let port_name = self.port_text(port);
+        template_matches(&self.node_template, expanded.node.as_ref(), node_name)
+            && template_matches(&self.port_template, expanded.port.as_ref(), port_name)
+    }
+
+    fn fill_template(&self, template: &Option<String>, captures: &[String]) -> Option<Regex> {
+        let rex = expand_template(template.as_ref()?, Some(captures), self.match_mode)?;
+        compile_regex(&rex, self.case_sensitive, self.match_mode).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pipeswitch_lib::{config::Config, StateBuilder};
+
+    use super::*;
+    use Channel::*;
+    use Direction::{Input, Output};
+
+    /// A rule built from the body of a `[link.test]` table, with the default
+    /// general settings
+    fn rule(link: &str) -> LinkRules {
+        try_rule(link).unwrap()
+    }
+
+    fn try_rule(link: &str) -> Result<LinkRules> {
+        let toml = format!(
+            "[general]\nlinger_links = false\nhotreload_config = false\n\
+            [log]\nlevel = \"info\"\n[link.test]\n{link}"
+        );
+        let (config, _) = Config::from_string(&toml).unwrap();
+        let cfg = config.links["test"].clone();
+        LinkRules::try_from(("test".to_owned(), cfg, &config.general))
+    }
+
+    /// Matches the ports of the state in the order they appeared, like the
+    /// daemon does, giving the output and input ports the rule links
+    fn links(rule: &mut LinkRules, state: &PipewireState) -> Vec<(u32, u32)> {
+        let mut ids: Vec<&u32> = state.ports.keys().collect();
+        ids.sort();
+        for port in ids.into_iter().map(|id| &state.ports[id]) {
+            match port.direction {
+                Input => {
+                    for sink in &mut rule.inputs {
+                        sink.add_if_matches(port, state);
+                    }
+                }
+                Output => {
+                    if rule.output.add_if_matches(port, state) {
+                        rule.assign_sink(port);
+                    }
+                }
+            }
+        }
+        let mut links: Vec<(u32, u32)> = rule.expected_links(state).into_iter().collect();
+        links.sort_unstable();
+        links
+    }
+
+    #[test]
+    fn surround_channels_link_like_to_like() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "surround")
+            .port(11, 10, "out_FL", Output, Left)
+            .port(12, 10, "out_FC", Output, FrontCenter)
+            .port(13, 10, "out_LFE", Output, LowFrequency)
+            .node(20, 1, "speakers")
+            .port(21, 20, "in_FL", Input, Left)
+            .port(22, 20, "in_FR", Input, Right)
+            .port(23, 20, "in_FC", Input, FrontCenter)
+            .port(24, 20, "in_LFE", Input, LowFrequency)
+            .port(25, 20, "in_SL", Input, SideLeft)
+            .build()
+            .unwrap();
+        let mut rule = rule("source = \"surround\"\nsink = \"speakers\"");
+        assert_eq!(links(&mut rule, &state), [(11, 21), (12, 23), (13, 24)]);
+    }
+
+    #[test]
+    fn captures_pair_numbered_nodes() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "mic_1")
+            .port(11, 10, "capture_MONO", Output, Mono)
+            .node(20, 1, "mic_2")
+            .port(21, 20, "capture_MONO", Output, Mono)
+            .node(30, 1, "speaker_1")
+            .port(31, 30, "playback_MONO", Input, Mono)
+            .node(40, 1, "speaker_2")
+            .port(41, 40, "playback_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let mut rule = rule("source = { node = 'mic_(\\d+)' }\nsink = { node = 'speaker_$1' }");
+        assert_eq!(links(&mut rule, &state), [(11, 31), (21, 41)]);
+    }
+
+    #[test]
+    fn templates_fill_in_captures() {
+        let captures = ["2".to_owned(), "a.b".to_owned()];
+        let expand = |template, mode| expand_template(template, Some(&captures), mode);
+        assert_eq!(expand("speaker_$1", MatchMode::Regex).unwrap(), "speaker_2");
+        // Captures are taken literally, whatever the match mode
+        assert_eq!(expand("$2*", MatchMode::Glob).unwrap(), "^a\\.b.*$");
+        assert_eq!(expand("speaker_$3", MatchMode::Regex), None);
+        let wildcard = expand_template("speaker_$1", None, MatchMode::Regex).unwrap();
+        assert_eq!(wildcard, "speaker_.*");
+    }
+
+    #[test]
+    fn path_tells_apart_ports_with_the_same_name() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "source")
+            .port(11, 10, "capture_FL", Output, Left)
+            .node(20, 1, "sink")
+            .port(21, 20, "playback_FL", Input, Left)
+            .with(&[("object.path", "alsa:pcm:0:front:0:playback:0")])
+            .node(30, 1, "sink")
+            .port(31, 30, "playback_FL", Input, Left)
+            .with(&[("object.path", "alsa:pcm:1:front:1:playback:0")])
+            .build()
+            .unwrap();
+        let link = r#"
+            source = "source"
+            sink = { node = "sink", path = "alsa:pcm:1:.*" }
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 31)]);
+    }
+
+    #[test]
+    fn channel_picks_only_those_ports() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "source")
+            .port(11, 10, "capture_1", Output, Left)
+            .port(12, 10, "capture_2", Output, Right)
+            .node(20, 1, "sink")
+            .port(21, 20, "playback_1", Input, Left)
+            .port(22, 20, "playback_2", Input, Right)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = { node = "source", channel = "FR" }
+            sink = { node = "sink", channel = "FR" }
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(12, 22)]);
+    }
+
+    #[test]
+    fn role_skips_nodes_without_one() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "call")
+            .with(&[("media.role", "Communication")])
+            .port(11, 10, "output_MONO", Output, Mono)
+            .node(20, 1, "music")
+            .with(&[("media.role", "Music")])
+            .port(21, 20, "output_MONO", Output, Mono)
+            .node(30, 1, "game")
+            .port(31, 30, "output_MONO", Output, Mono)
+            .node(40, 1, "headset")
+            .port(41, 40, "playback_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = { role = "Comm.*" }
+            sink = "headset"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 41)]);
+        // Even a pattern matching anything needs the node to have a role
+        let link = r#"
+            source = { role = ".*" }
+            sink = "headset"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 41), (21, 41)]);
+    }
+
+    #[test]
+    fn link_properties_are_passed_as_strings() {
+        let link = r#"
+            source = "a"
+            sink = "b"
+            link_properties = { "link.passive" = true, "node.latency" = "256/48000" }
+        "#;
+        let properties = rule(link).link_properties;
+        assert_eq!(properties["link.passive"], "true");
+        assert_eq!(properties["node.latency"], "256/48000");
+        let link = r#"
+            source = "a"
+            sink = "b"
+            link_properties = { "link.passive" = true, "object.linger" = false }
+        "#;
+        assert!(try_rule(link).is_err());
+    }
+
+    #[test]
+    fn monitor_ports_need_include_monitor() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "speakers")
+            .port(11, 10, "playback_FL", Input, Left)
+            .port(12, 10, "monitor_FL", Output, Left)
+            .with(&[("port.monitor", "true")])
+            .node(20, 1, "mic")
+            .port(21, 20, "capture_FL", Output, Left)
+            .node(30, 1, "recorder")
+            .port(31, 30, "input_FL", Input, Left)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = ".*"
+            sink = "recorder"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(21, 31)]);
+        let link = r#"
+            source = ".*"
+            sink = "recorder"
+            include_monitor = true
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(12, 31), (21, 31)]);
+    }
+
+    #[test]
+    fn port_field_picks_what_port_matches() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "source")
+            .port(11, 10, "wanted", Output, Mono)
+            .with(&[("port.alias", "source:a"), ("object.path", "source:0")])
+            .port(12, 10, "b", Output, Mono)
+            .with(&[("port.alias", "wanted"), ("object.path", "source:1")])
+            .port(13, 10, "c", Output, Mono)
+            .with(&[("port.alias", "source:c"), ("object.path", "wanted")])
+            .node(20, 1, "sink")
+            .port(21, 20, "playback_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let linked = |port_field: &str| {
+            let link = format!(
+                "source = {{ node = \"source\", port = \"wanted\"{port_field} }}\nsink = \"sink\""
+            );
+            links(&mut rule(&link), &state)
+        };
+        assert_eq!(linked(""), [(11, 21)]);
+        assert_eq!(linked(", port_field = \"name\""), [(11, 21)]);
+        assert_eq!(linked(", port_field = \"alias\""), [(12, 21)]);
+        assert_eq!(linked(", port_field = \"path\""), [(13, 21)]);
+    }
+
+    #[test]
+    fn pairing_by_node_links_only_the_latest_nodes() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "speaker_1")
+            .port(11, 10, "playback_MONO", Input, Mono)
+            .node(20, 1, "mic_1")
+            .port(21, 20, "capture_MONO", Output, Mono)
+            .node(30, 1, "speaker_2")
+            .port(31, 30, "playback_MONO", Input, Mono)
+            .node(40, 1, "mic_2")
+            .port(41, 40, "capture_MONO", Output, Mono)
+            .build()
+            .unwrap();
+        let link =
+            |pairing| format!("source = 'mic_.*'\nsink = 'speaker_.*'\npairing = '{pairing}'");
+        let all = [(21, 11), (21, 31), (41, 11), (41, 31)];
+        assert_eq!(links(&mut rule(&link("all")), &state), all);
+        assert_eq!(links(&mut rule(&link("by_node")), &state), [(41, 31)]);
+    }
+
+    #[test]
+    fn any_pattern_of_a_list_matches() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "mic")
+            .port(11, 10, "capture_MONO", Output, Mono)
+            .node(20, 1, "speakers")
+            .port(21, 20, "playback_FL", Input, Left)
+            .port(22, 20, "playback_FR", Input, Right)
+            .port(23, 20, "playback_FC", Input, FrontCenter)
+            .node(30, 1, "headphones")
+            .port(31, 30, "playback_FL", Input, Left)
+            .node(40, 1, "recorder")
+            .port(41, 40, "playback_FL", Input, Left)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = "mic"
+            sink = { node = ["speakers", "headphones"], port = ["playback_FL", "playback_FR"] }
+        "#;
+        // The sink picks its ports, so the channels are not paired up
+        let expected = [(11, 21), (11, 22), (11, 31)];
+        assert_eq!(links(&mut rule(link), &state), expected);
+    }
+
+    #[test]
+    fn midi_ports_link_by_name() {
+        let midi = [("format.dsp", "8 bit raw midi")];
+        // The channels of MIDI ports come from their port ids and mean nothing
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "keyboard")
+            .port(11, 10, "midi_out", Output, Left)
+            .with(&midi)
+            .port(12, 10, "capture_FL", Output, Left)
+            .node(20, 1, "synth")
+            .port(21, 20, "midi_in", Input, Right)
+            .with(&midi)
+            .port(22, 20, "playback_FL", Input, Left)
+            .build()
+            .unwrap();
+        let link = "source = 'keyboard'\nsink = 'synth'";
+        assert_eq!(links(&mut rule(link), &state), [(11, 21), (12, 22)]);
+        let link = r#"
+            source = { node = "keyboard", port = "midi_out" }
+            sink = { node = "synth", port = ".*" }
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 21)]);
+        let link = r#"
+            source = { node = "keyboard", port_kind = "midi" }
+            sink = { node = "synth", port_kind = "midi" }
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 21)]);
+    }
+
+    #[test]
+    fn round_robin_takes_turns_between_sinks() {
+        let graph = |apps: &[u32]| {
+            let mut builder = StateBuilder::new()
+                .client(1, "app")
+                .node(10, 1, "speakers")
+                .port(11, 10, "playback_MONO", Input, Mono)
+                .node(20, 1, "headphones")
+                .port(21, 20, "playback_MONO", Input, Mono);
+            for &app in apps {
+                let (node, name) = (app * 10, format!("app_{app}"));
+                builder = builder.node(node, 1, &name);
+                builder = builder.port(node + 1, node, "output_MONO", Output, Mono);
+            }
+            builder.build().unwrap()
+        };
+        let link = r#"
+            source = "app_.*"
+            sink = ["speakers", "headphones"]
+            distribute = "round_robin"
+        "#;
+        let mut rule = rule(link);
+        let state = graph(&[3, 4, 5]);
+        assert_eq!(links(&mut rule, &state), [(31, 11), (41, 21), (51, 11)]);
+        // The sink of a removed source goes to the next new one
+        let removed = &state.ports[&31];
+        rule.output.delete_port(removed);
+        rule.release_sink(removed);
+        let state = graph(&[4, 5, 6]);
+        assert_eq!(links(&mut rule, &state), [(41, 21), (51, 11), (61, 11)]);
+    }
+
+    #[test]
+    fn case_sensitive_tells_apart_names_differing_in_case() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "Spotify")
+            .port(11, 10, "output_MONO", Output, Mono)
+            .node(20, 1, "spotify")
+            .port(21, 20, "output_MONO", Output, Mono)
+            .node(30, 1, "speakers")
+            .port(31, 30, "playback_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = "Spotify"
+            sink = "speakers"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 31), (21, 31)]);
+        let link = r#"
+            source = { node = "Spotify", case_sensitive = true }
+            sink = "speakers"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 31)]);
+    }
+
+    #[test]
+    fn media_class_tells_devices_from_streams() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "mic")
+            .with(&[("media.class", "Audio/Source")])
+            .port(11, 10, "capture_MONO", Output, Mono)
+            .node(20, 1, "player")
+            .with(&[("media.class", "Stream/Output/Audio")])
+            .port(21, 20, "output_MONO", Output, Mono)
+            .node(30, 1, "recorder")
+            .port(31, 30, "input_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = { media_class = "Audio/Source" }
+            sink = "recorder"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 31)]);
+        let link = r#"
+            source = { media_class = "Stream/Output/Audio" }
+            sink = "recorder"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(21, 31)]);
+    }
+
+    #[test]
+    fn description_matches_a_cryptic_node_name() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "bluez_output.00_1B_66_AB_CD_EF.1")
+            .with(&[("node.description", "Headphones"), ("node.nick", "BT")])
+            .port(11, 10, "playback_MONO", Input, Mono)
+            .node(20, 1, "alsa_output.pci-0000_00_1f.3.analog-stereo")
+            .with(&[("node.description", "Built-in Audio")])
+            .port(21, 20, "playback_MONO", Input, Mono)
+            .node(30, 1, "player")
+            .port(31, 30, "output_MONO", Output, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = "player"
+            sink = { description = "Headphones" }
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(31, 11)]);
+        // Every field given has to match
+        let link = r#"
+            source = "player"
+            sink = { description = "Headphones", nick = "USB" }
+        "#;
+        assert_eq!(links(&mut rule(link), &state), []);
+    }
+
+    #[test]
+    fn not_node_excludes_only_that_node() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "firefox")
+            .port(11, 10, "output_MONO", Output, Mono)
+            .node(20, 1, "mpv")
+            .port(21, 20, "output_MONO", Output, Mono)
+            .node(30, 1, "game")
+            .port(31, 30, "output_MONO", Output, Mono)
+            .node(40, 1, "recorder")
+            .port(41, 40, "input_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = { node = ".*", not_node = "firefox" }
+            sink = "recorder"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(21, 41), (31, 41)]);
+        // An exclusion has to match the whole name
+        let link = r#"
+            source = { node = ".*", not_node = "fire" }
+            sink = "recorder"
+        "#;
+        assert_eq!(
+            links(&mut rule(link), &state),
+            [(11, 41), (21, 41), (31, 41)]
+        );
+    }
+
+    #[test]
+    fn absent_exclusions_exclude_nothing() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "firefox")
+            .port(11, 10, "output_MONO", Output, Mono)
+            .node(20, 1, "recorder")
+            .port(21, 20, "input_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = { node = ".*" }
+            sink = "recorder"
+        "#;
+        let mut rule = rule(link);
+        assert!(rule.output.not_client.is_none());
+        assert!(rule.output.not_node.is_none());
+        assert!(rule.output.not_port.is_none());
+        assert_eq!(links(&mut rule, &state), [(11, 21)]);
+    }
+
+    #[test]
+    fn binary_tells_apart_clients_with_the_same_name() {
+        let state = StateBuilder::new()
+            .client(1, "Chromium")
+            .with(&[("application.process.binary", "chromium")])
+            .node(10, 1, "chromium_output")
+            .port(11, 10, "output_MONO", Output, Mono)
+            .client(2, "Chromium")
+            .with(&[("application.process.binary", "electron")])
+            .node(20, 2, "chromium_output")
+            .port(21, 20, "output_MONO", Output, Mono)
+            .client(3, "pipewire")
+            .node(30, 3, "speakers")
+            .port(31, 30, "playback_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = { client = "Chromium" }
+            sink = "speakers"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 31), (21, 31)]);
+        let link = r#"
+            source = { client = "Chromium", binary = "electron" }
+            sink = "speakers"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(21, 31)]);
+    }
+
+    #[test]
+    fn globs_match_like_their_escaped_regex() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "alsa_input.usb-mic")
+            .port(11, 10, "capture_MONO", Output, Mono)
+            .node(20, 1, "alsa_inputs")
+            .port(21, 20, "capture_MONO", Output, Mono)
+            .node(30, 1, "alsa_output.usb-dac")
+            .port(31, 30, "monitor_MONO", Output, Mono)
+            .node(40, 1, "recorder")
+            .port(41, 40, "input_MONO", Input, Mono)
+            .build()
+            .unwrap();
+        let linked = |source: &str| {
+            let link = format!("source = {source}\nsink = \"recorder\"");
+            links(&mut rule(&link), &state)
+        };
+        let glob = linked("{ node = 'alsa_input.*', match_mode = 'glob' }");
+        assert_eq!(glob, [(11, 41)]);
+        assert_eq!(linked("{ node = 'alsa_input\\..*' }"), glob);
+        // As a regex the dot matches any character
+        assert_eq!(linked("{ node = 'alsa_input.*' }"), [(11, 41), (21, 41)]);
+    }
+
+    #[test]
+    fn duplicate_mono_feeds_both_stereo_channels() {
+        let state = StateBuilder::new()
+            .client(1, "app")
+            .node(10, 1, "mic")
+            .port(11, 10, "capture_MONO", Output, Mono)
+            .node(20, 1, "speakers")
+            .port(21, 20, "playback_FL", Input, Left)
+            .port(22, 20, "playback_FR", Input, Right)
+            .port(23, 20, "playback_FC", Input, FrontCenter)
+            .build()
+            .unwrap();
+        let link = r#"
+            source = "mic"
+            sink = "speakers"
+        "#;
+        assert_eq!(links(&mut rule(link), &state), []);
+        let link = r#"
+            source = "mic"
+            sink = "speakers"
+            duplicate_mono = true
+        "#;
+        assert_eq!(links(&mut rule(link), &state), [(11, 21), (11, 22)]);
+    }
 }
@@ -0,0 +1,55 @@
+use std::{path::Path, sync::mpsc::Sender};
+
+use anyhow::Result;
+use log::*;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+use crate::config::{load_valid_config, Event};
+
+/// Asks the main loop to shut down when SIGTERM or SIGINT is received, and
+/// reloads the config on SIGHUP
+pub fn start(config_path: &Path, sender: Sender<Event>) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    let config_path = config_path.to_owned();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            debug!("received signal {signal}");
+            let event = match signal {
+                SIGHUP => match load_valid_config(&config_path) {
+                    Ok(cfg) => Event::ConfigModified(cfg),
+                    Err(err) => {
+                        error!("Error loading config on SIGHUP, keeping the previous one: {err}");
+                        continue;
+                    }
+                },
+                SIGTERM | SIGINT => Event::Shutdown,
+                _ => continue,
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, time::Duration};
+
+    use signal_hook::low_level::raise;
+
+    use super::*;
+
+    #[test]
+    fn sigterm_asks_for_shutdown() {
+        let (sender, receiver) = channel();
+        start(Path::new("/nonexistent/pipeswitch.conf"), sender).unwrap();
+        raise(SIGTERM).unwrap();
+        let event = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, Event::Shutdown));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::mpsc::Sender,
+    thread::JoinHandle,
+};
+
+use anyhow::{anyhow, Result};
+use log::*;
+use pipeswitch_lib::config::Config;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::Event;
+use crate::control::{self, Command, RuleStatus};
+
+/// One command per line, ie. `{"cmd":"link","output":40,"input":52}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    ListRules,
+    ListLinks,
+    Status,
+    Reload,
+    Link {
+        output: u32,
+        input: u32,
+    },
+    Unlink {
+        id: u32,
+    },
+    /// Without a name, only the rules outside of profiles are linked
+    Profile {
+        name: Option<String>,
+    },
+}
+
+/// Binds the control socket and answers newline-delimited JSON commands on it
+/// in a background thread.
+pub fn start(path: &Path, sender: Sender<Event>) -> Result<JoinHandle<()>> {
+    if path.try_exists()? {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle_client(stream, &sender) {
+                            debug!("control socket client disconnected: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Error accepting control socket connection: {e}"),
+            }
+        }
+    }))
+}
+
+fn handle_client(stream: UnixStream, sender: &Sender<Event>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle_request(request, sender),
+            Err(e) => json!({ "error": format!("malformed command: {e}") }),
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+fn handle_request(request: Request, sender: &Sender<Event>) -> Value {
+    let reply = match request {
+        Request::ListRules => control::request(sender, Command::ListRules).map(|r| Ok(json!(r))),
+        Request::ListLinks => control::request(sender, Command::ListLinks).map(|l| Ok(json!(l))),
+        Request::Status => control::request(sender, Command::RuleStatus).map(|r| Ok(json!(r))),
+        Request::Reload => {
+            control::request(sender, Command::ReloadConfig).map(|res| res.map(|_| Value::Null))
+        }
+        Request::Link { output, input } => control::request(sender, |reply| Command::CreateLink {
+            output,
+            input,
+            reply,
+        })
+        .map(|res| res.map(|id| json!(id))),
+        Request::Unlink { id } => control::request(sender, |reply| Command::DestroyLink(id, reply))
+            .map(|res| res.map(|destroyed| json!(destroyed))),
+        Request::Profile { name } => {
+            control::request(sender, |reply| Command::ActivateProfile(name, reply))
+                .map(|res| res.map(|_| Value::Null))
+        }
+    };
+    match reply {
+        Some(Ok(value)) => json!({ "ok": value }),
+        Some(Err(e)) => json!({ "error": e }),
+        None => json!({ "error": "daemon did not answer" }),
+    }
+}
+
+/// Asks the running daemon for the status of its rules over the control socket
+/// of the config and prints them as a table
+pub fn print_status() -> Result<()> {
+    let path = Config::default_path().ok_or_else(|| anyhow!("no config directory"))?;
+    let (config, _) = Config::load_with_dropins(&path)?
+        .ok_or_else(|| anyhow!("no config at {}", path.display()))?;
+    let path = config
+        .general
+        .control_socket
+        .ok_or_else(|| anyhow!("control_socket is not set in the config"))?;
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| anyhow!("could not connect to {}: {e}", path.display()))?;
+    writeln!(stream, "{}", json!({ "cmd": "status" }))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let mut reply: Value = serde_json::from_str(&line)?;
+    if let Some(e) = reply.get("error").and_then(Value::as_str) {
+        return Err(anyhow!("{e}"));
+    }
+    let rules: Vec<RuleStatus> = serde_json::from_value(reply["ok"].take())?;
+
+    let width = rules
+        .iter()
+        .map(|rule| rule.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!("{:width$}  ENABLED  OUTPUTS  INPUTS  LINKS  FAILED", "RULE");
+    for rule in rules {
+        let enabled = if rule.enabled { "yes" } else { "no" };
+        let RuleStatus {
+            name,
+            outputs,
+            inputs,
+            links,
+            failed,
+            ..
+        } = rule;
+        println!("{name:width$}  {enabled:7}  {outputs:>7}  {inputs:>6}  {links:>5}  {failed:>6}");
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use std::{
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use crate::config::Event;
+
+/// Sends events to the main loop once their delay has passed, all of them
+/// from a single thread
+pub struct Timers {
+    sender: Sender<(Instant, Event)>,
+}
+
+impl Timers {
+    /// The thread stops once the timers are dropped and the events still
+    /// waiting are thrown away
+    pub fn start(events: Sender<Event>) -> Self {
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || run(receiver, events));
+        Timers { sender }
+    }
+
+    pub fn schedule(&self, delay: Duration, event: Event) {
+        let _ = self.sender.send((Instant::now() + delay, event));
+    }
+}
+
+fn run(receiver: Receiver<(Instant, Event)>, events: Sender<Event>) {
+    let mut waiting: Vec<(Instant, Event)> = Vec::new();
+    loop {
+        let now = Instant::now();
+        // Events due at the same time are sent in the order they were scheduled
+        let (due, rest): (Vec<_>, Vec<_>) = waiting.into_iter().partition(|(at, _)| *at <= now);
+        waiting = rest;
+        for (_, event) in due {
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        let next = waiting.iter().map(|(at, _)| *at).min();
+        let received = match next {
+            Some(next) => receiver.recv_timeout(next.saturating_duration_since(now)),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(timer) => waiting.push(timer),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}